@@ -9,6 +9,7 @@ import kotlinx.coroutines.flow.callbackFlow
 import kotlinx.coroutines.withContext
 import mages.FfiRoomNotificationMode
 import mages.TimelineDiffKind
+import mages.TimelineItemKind
 import org.mlm.mages.AttachmentInfo
 import org.mlm.mages.AttachmentKind
 import org.mlm.mages.EncFile
@@ -65,57 +66,102 @@ class RustMatrixPort(hs: String) : MatrixPort {
 
     override fun timelineDiffs(roomId: String): Flow<TimelineDiff<MessageEvent>> = callbackFlow {
         val obs = object : mages.TimelineObserver {
+            // Mirrors the Rust item list, which also holds date dividers and other
+            // virtual items, so its indices can be mapped to positions among events.
+            private val isEvent = ArrayList<Boolean>()
+
+            private fun eventIndex(index: Int): Int =
+                isEvent.subList(0, index.coerceAtMost(isEvent.size)).count { it }
+
+            private fun events(values: List<TimelineItemKind>): List<MessageEvent> =
+                values.filterIsInstance<TimelineItemKind.Event>().map { it.event.toModel() }
+
             override fun onDiff(diff: TimelineDiffKind) {
-                val mapped: TimelineDiff<MessageEvent> = when (diff) {
+                val mapped: TimelineDiff<MessageEvent>? = when (diff) {
                     is TimelineDiffKind.Reset -> {
-                        TimelineDiff.Reset(diff.values.map { it.toModel() })
+                        isEvent.clear()
+                        isEvent.addAll(diff.values.map { it is TimelineItemKind.Event })
+                        TimelineDiff.Reset(events(diff.values))
                     }
 
                     is TimelineDiffKind.Clear -> {
+                        isEvent.clear()
                         TimelineDiff.Clear()
                     }
 
                     is TimelineDiffKind.Append -> {
                         // semantics: values appended at the end in order
-                        TimelineDiff.Append(diff.values.map { it.toModel() })
+                        isEvent.addAll(diff.values.map { it is TimelineItemKind.Event })
+                        events(diff.values).takeIf { it.isNotEmpty() }?.let { TimelineDiff.Append(it) }
                     }
 
                     is TimelineDiffKind.PushBack -> {
                         // append a single element at end
-                        TimelineDiff.Append(listOf(diff.value.toModel()))
+                        val value = diff.value
+                        isEvent.add(value is TimelineItemKind.Event)
+                        if (value is TimelineItemKind.Event) {
+                            TimelineDiff.Append(listOf(value.event.toModel()))
+                        } else null
                     }
 
                     is TimelineDiffKind.PushFront -> {
                         // equivalent to insert at index 0
-                        TimelineDiff.InsertAt(0, diff.value.toModel())
+                        val value = diff.value
+                        isEvent.add(0, value is TimelineItemKind.Event)
+                        if (value is TimelineItemKind.Event) {
+                            TimelineDiff.InsertAt(0, value.event.toModel())
+                        } else null
                     }
 
                     is TimelineDiffKind.Insert -> {
-                        TimelineDiff.InsertAt(diff.index.toInt(), diff.value.toModel())
+                        val index = diff.index.toInt()
+                        val value = diff.value
+                        val at = eventIndex(index)
+                        isEvent.add(index, value is TimelineItemKind.Event)
+                        if (value is TimelineItemKind.Event) {
+                            TimelineDiff.InsertAt(at, value.event.toModel())
+                        } else null
                     }
 
                     is TimelineDiffKind.Set -> {
-                        TimelineDiff.UpdateAt(diff.index.toInt(), diff.value.toModel())
+                        val index = diff.index.toInt()
+                        val value = diff.value
+                        val at = eventIndex(index)
+                        val was = isEvent[index]
+                        isEvent[index] = value is TimelineItemKind.Event
+                        when {
+                            value is TimelineItemKind.Event && was ->
+                                TimelineDiff.UpdateAt(at, value.event.toModel())
+                            value is TimelineItemKind.Event ->
+                                TimelineDiff.InsertAt(at, value.event.toModel())
+                            was -> TimelineDiff.RemoveAt(at)
+                            else -> null
+                        }
                     }
 
                     is TimelineDiffKind.Remove -> {
-                        TimelineDiff.RemoveAt(diff.index.toInt())
+                        val index = diff.index.toInt()
+                        val at = eventIndex(index)
+                        if (isEvent.removeAt(index)) TimelineDiff.RemoveAt(at) else null
                     }
 
                     is TimelineDiffKind.PopFront -> {
-                        TimelineDiff.PopFront
+                        if (isEvent.removeAt(0)) TimelineDiff.PopFront else null
                     }
 
                     is TimelineDiffKind.PopBack -> {
-                        TimelineDiff.PopBack
+                        if (isEvent.removeAt(isEvent.lastIndex)) TimelineDiff.PopBack else null
                     }
 
                     is TimelineDiffKind.Truncate -> {
-                        TimelineDiff.Truncate(diff.length.toInt())
+                        val length = diff.length.toInt()
+                        val keep = eventIndex(length)
+                        while (isEvent.size > length) isEvent.removeAt(isEvent.lastIndex)
+                        TimelineDiff.Truncate(keep)
                     }
                 }
 
-                trySendBlocking(mapped)
+                mapped?.let { trySendBlocking(it) }
             }
             override fun onError(message: String) { /* log */ }
         }
@@ -163,7 +209,7 @@ class RustMatrixPort(hs: String) : MatrixPort {
 
     override suspend fun send(roomId: String, body: String): Boolean =
         withContext(Dispatchers.IO) {
-            client.sendMessage(roomId, body)
+            runCatching { client.sendMessage(roomId, body, null) }.isSuccess
         }
 
     override suspend fun sendExistingAttachment(
@@ -178,12 +224,20 @@ class RustMatrixPort(hs: String) : MatrixPort {
             }
         } else null
 
-        client.sendExistingAttachment(roomId, attachment.toFfi(), body, cb)
+        val options = body?.let {
+            mages.AttachmentOptions(
+                caption = it,
+                captionOptions = null,
+                replyToEventId = null,
+                threadRootEventId = null,
+            )
+        }
+        runCatching { client.sendExistingAttachment(roomId, attachment.toFfi(), options, cb) }.isSuccess
     }
 
     override suspend fun enqueueText(roomId: String, body: String, txnId: String?): String =
         withContext(Dispatchers.IO) {
-            client.enqueueText(roomId, body, txnId)
+            client.enqueueText(roomId, body, txnId, null)
         }
 
     override fun observeSends(): Flow<SendUpdate> = callbackFlow {
@@ -220,7 +274,7 @@ class RustMatrixPort(hs: String) : MatrixPort {
 
     override suspend fun setTyping(roomId: String, typing: Boolean): Boolean =
         withContext(Dispatchers.IO) {
-            client.setTyping(roomId, typing)
+            runCatching { client.setTyping(roomId, typing) }.isSuccess
         }
 
     override fun whoami(): String? {
@@ -239,32 +293,32 @@ class RustMatrixPort(hs: String) : MatrixPort {
 
     override suspend fun markRead(roomId: String): Boolean =
         withContext(Dispatchers.IO) {
-            client.markRead(roomId)
+            runCatching { client.markRead(roomId) }.isSuccess
         }
 
     override suspend fun markReadAt(roomId: String, eventId: String): Boolean =
         withContext(Dispatchers.IO) {
-            client.markReadAt(roomId, eventId)
+            runCatching { client.markReadAt(roomId, eventId) }.isSuccess
         }
 
     override suspend fun react(roomId: String, eventId: String, emoji: String): Boolean =
         withContext(Dispatchers.IO) {
-            client.react(roomId, eventId, emoji)
+            runCatching { client.react(roomId, eventId, emoji) }.isSuccess
         }
 
     override suspend fun reply(roomId: String, inReplyToEventId: String, body: String): Boolean =
         withContext(Dispatchers.IO) {
-            client.reply(roomId, inReplyToEventId, body)
+            runCatching { client.reply(roomId, inReplyToEventId, body, null) }.isSuccess
         }
 
     override suspend fun edit(roomId: String, targetEventId: String, newBody: String): Boolean =
         withContext(Dispatchers.IO) {
-            client.edit(roomId, targetEventId, newBody)
+            runCatching { client.edit(roomId, targetEventId, newBody, null) }.isSuccess
         }
 
     override suspend fun redact(roomId: String, eventId: String, reason: String?): Boolean =
         withContext(Dispatchers.IO) {
-            client.redact(roomId, eventId, reason)
+            runCatching { client.redact(roomId, eventId, reason) }.isSuccess
         }
 
     override fun observeTyping(roomId: String, onUpdate: (List<String>) -> Unit): ULong {
@@ -356,6 +410,8 @@ class RustMatrixPort(hs: String) : MatrixPort {
         mages.SasPhase.CANCELLED -> SasPhase.Cancelled
         mages.SasPhase.FAILED -> SasPhase.Failed
         mages.SasPhase.DONE -> SasPhase.Done
+        mages.SasPhase.QR_SCANNED -> SasPhase.QrScanned
+        mages.SasPhase.QR_RECIPROCATED -> SasPhase.QrReciprocated
     }
 
     override suspend fun startSelfSas(
@@ -424,6 +480,8 @@ class RustMatrixPort(hs: String) : MatrixPort {
                         mages.SasPhase.CANCELLED -> SasPhase.Cancelled
                         mages.SasPhase.FAILED -> SasPhase.Failed
                         mages.SasPhase.DONE -> SasPhase.Done
+                        mages.SasPhase.QR_SCANNED -> SasPhase.QrScanned
+                        mages.SasPhase.QR_RECIPROCATED -> SasPhase.QrReciprocated
                     }
                 )
             }
@@ -441,27 +499,27 @@ class RustMatrixPort(hs: String) : MatrixPort {
                 observer.onError(flowId, message)
             }
         }
-        client.acceptVerification(flowId, otherUserId, cb)
+        runCatching { client.acceptVerification(flowId, otherUserId, cb) }.isSuccess
     }
 
     override suspend fun confirmVerification(flowId: String): Boolean =
-        withContext(Dispatchers.IO) { client.confirmVerification(flowId) }
+        withContext(Dispatchers.IO) { runCatching { client.confirmVerification(flowId) }.isSuccess }
 
     override suspend fun cancelVerification(flowId: String): Boolean =
-        withContext(Dispatchers.IO) { client.cancelVerification(flowId) }
+        withContext(Dispatchers.IO) { runCatching { client.cancelVerification(flowId) }.isSuccess }
 
     override suspend fun cancelVerificationRequest(
         flowId: String,
         otherUserId: String?
     ): Boolean = withContext(Dispatchers.IO) {
-        client.cancelVerificationRequest(flowId, otherUserId)
+        runCatching { client.cancelVerificationRequest(flowId, otherUserId) }.isSuccess
     }
 
     override suspend fun logout(): Boolean =
-        withContext(Dispatchers.IO) { client.logout() }
+        withContext(Dispatchers.IO) { runCatching { client.logout() }.isSuccess }
 
     override suspend fun retryByTxn(roomId: String, txnId: String): Boolean =
-        withContext(Dispatchers.IO) { client.retryByTxn(roomId, txnId) }
+        withContext(Dispatchers.IO) { runCatching { client.retryByTxn(roomId, txnId) }.isSuccess }
 
     override suspend fun checkVerificationRequest(userId: String, flowId: String): Boolean =
         withContext(Dispatchers.IO) { client.checkVerificationRequest(userId, flowId) }
@@ -486,7 +544,10 @@ class RustMatrixPort(hs: String) : MatrixPort {
                 onProgress(sent.toLong(), total?.toLong())
             }
         } else null
-        client.sendAttachmentFromPath(roomId, path, mime, filename, cb)
+        // The upload continues in the send queue; the handle is only needed to cancel it.
+        runCatching {
+            client.sendAttachmentFromPath(roomId, path, mime, filename, cb, null).close()
+        }.isSuccess
     }
 
     override suspend fun sendAttachmentBytes(
@@ -501,7 +562,9 @@ class RustMatrixPort(hs: String) : MatrixPort {
                 onProgress(sent.toLong(), total?.toLong())
             }
         } else null
-        client.sendAttachmentBytes(roomId, filename, mime, data, cb)
+        runCatching {
+            client.sendAttachmentBytes(roomId, filename, mime, data, cb, null).close()
+        }.isSuccess
     }
 
     override suspend fun downloadAttachmentToCache(
@@ -531,7 +594,7 @@ class RustMatrixPort(hs: String) : MatrixPort {
     }
 
     override suspend fun recoverWithKey(recoveryKey: String): Boolean =
-        withContext(Dispatchers.IO) { client.recoverWithKey(recoveryKey) }
+        withContext(Dispatchers.IO) { runCatching { client.recoverWithKey(recoveryKey) }.isSuccess }
 
     override suspend fun registerUnifiedPush(
         appId: String,
@@ -541,14 +604,14 @@ class RustMatrixPort(hs: String) : MatrixPort {
         lang: String,
         profileTag: String?,
     ): Boolean = withContext(Dispatchers.IO) {
-        client.registerUnifiedpush(appId, pushKey, gatewayUrl, deviceName, lang, profileTag)
+        runCatching { client.registerUnifiedpush(appId, pushKey, gatewayUrl, deviceName, lang, profileTag) }.isSuccess
     }
 
     override suspend fun unregisterUnifiedPush(
         appId: String,
         pushKey: String,
     ): Boolean = withContext(Dispatchers.IO) {
-        client.unregisterUnifiedpush(appId, pushKey)
+        runCatching { client.unregisterUnifiedpush(appId, pushKey) }.isSuccess
     }
 
     override suspend fun roomUnreadStats(roomId: String): UnreadStats? =
@@ -597,7 +660,7 @@ class RustMatrixPort(hs: String) : MatrixPort {
 
     override suspend fun markFullyReadAt(roomId: String, eventId: String): Boolean =
         withContext(Dispatchers.IO) {
-            client.markFullyReadAt(roomId, eventId)
+            runCatching { client.markFullyReadAt(roomId, eventId) }.isSuccess
         }
 
     override fun observeRoomList(observer: MatrixPort.RoomListObserver): ULong {
@@ -772,7 +835,7 @@ class RustMatrixPort(hs: String) : MatrixPort {
 
     override suspend fun joinByIdOrAlias(idOrAlias: String): Boolean =
         withContext(Dispatchers.IO) {
-            client.joinByIdOrAlias(idOrAlias)
+            runCatching { client.joinByIdOrAlias(idOrAlias) }.isSuccess
         }
 
     override suspend fun ensureDm(userId: String): String? =
@@ -792,7 +855,7 @@ class RustMatrixPort(hs: String) : MatrixPort {
     }
 
     override suspend fun acceptInvite(roomId: String): Boolean = withContext(Dispatchers.IO) {
-        client.acceptInvite(roomId)
+        runCatching { client.acceptInvite(roomId) }.isSuccess
     }
 
     override suspend fun leaveRoom(roomId: String): Boolean = withContext(Dispatchers.IO) {
@@ -806,11 +869,11 @@ class RustMatrixPort(hs: String) : MatrixPort {
     }
 
     override suspend fun setRoomName(roomId: String, name: String): Boolean = withContext(Dispatchers.IO) {
-        client.setRoomName(roomId, name)
+        runCatching { client.setRoomName(roomId, name) }.isSuccess
     }
 
     override suspend fun setRoomTopic(roomId: String, topic: String): Boolean = withContext(Dispatchers.IO) {
-        client.setRoomTopic(roomId, topic)
+        runCatching { client.setRoomTopic(roomId, topic) }.isSuccess
     }
 
     override suspend fun roomProfile(roomId: String): RoomProfile? = withContext(Dispatchers.IO) {
@@ -871,7 +934,7 @@ class RustMatrixPort(hs: String) : MatrixPort {
         body: String,
         replyToEventId: String?
     ): Boolean = withContext(Dispatchers.IO) {
-        client.sendThreadText(roomId, rootEventId, body, replyToEventId)
+        runCatching { client.sendThreadText(roomId, rootEventId, body, replyToEventId, null) }.isSuccess
     }
 
     override suspend fun isSpace(roomId: String): Boolean =
@@ -26,7 +26,7 @@ sealed class TimelineDiff<out T> {
     object PopBack : TimelineDiff<Nothing>()
 }
 
-enum class SasPhase { Requested, Ready, Emojis, Confirmed, Cancelled, Failed, Done }
+enum class SasPhase { Requested, Ready, Emojis, Confirmed, Cancelled, Failed, Done, QrScanned, QrReciprocated }
 
 enum class SendState { Enqueued, Sending, Sent, Retrying, Failed }
 
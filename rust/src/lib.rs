@@ -8,10 +8,13 @@ use matrix_sdk::{
             ignored_user_list::IgnoredUserListEventContent,
             room::{
                 ImageInfo,
+                avatar::RoomAvatarEventContent,
                 message::{
-                    FileInfo, FileMessageEventContent, ImageMessageEventContent, VideoInfo,
-                    VideoMessageEventContent,
+                    AudioInfo, AudioMessageEventContent, FileInfo, FileMessageEventContent,
+                    ImageMessageEventContent, UnstableAudioDetailsContentBlock,
+                    UnstableVoiceContentBlock, VideoInfo, VideoMessageEventContent,
                 },
+                power_levels::UserPowerLevel,
             },
         },
         room::Restricted,
@@ -26,7 +29,7 @@ use mime::Mime;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     path::{Path, PathBuf},
     sync::{
         Arc, Mutex,
@@ -42,9 +45,12 @@ use uniffi::{Enum, HandleAlloc, Object, Record, export, setup_scaffolding};
 use futures_util::StreamExt;
 use matrix_sdk::{
     Client as SdkClient, OwnedServerName, Room, RoomMemberships, SessionTokens,
-    authentication::{matrix::MatrixSession, oauth::OAuthError},
+    authentication::{
+        matrix::MatrixSession,
+        oauth::{ClientId, OAuthError, OAuthSession, UrlOrQuery, UserSession},
+    },
     config::SyncSettings,
-    media::{MediaFormat, MediaRequestParameters, MediaRetentionPolicy, MediaThumbnailSettings},
+    media::MediaRetentionPolicy,
     ruma::{
         OwnedMxcUri, OwnedRoomAliasId, OwnedRoomOrAliasId, SpaceChildOrder,
         api::client::{
@@ -85,7 +91,10 @@ use matrix_sdk::{
     },
 };
 use matrix_sdk::{
-    encryption::verification::{SasState as SdkSasState, SasVerification, VerificationRequest},
+    encryption::verification::{
+        QrVerification, QrVerificationData, QrVerificationState, SasState as SdkSasState,
+        SasVerification, Verification, VerificationRequest, VerificationRequestState,
+    },
     ruma::events::receipt::ReceiptThread,
 };
 use matrix_sdk_ui::{
@@ -98,7 +107,7 @@ use matrix_sdk_ui::{
     sync_service::{State, SyncService},
     timeline::{
         EventSendState, EventTimelineItem, MsgLikeContent, MsgLikeKind, RoomExt as _, Timeline,
-        TimelineItem, TimelineItemContent,
+        TimelineItem, TimelineItemContent, VirtualTimelineItem,
     },
 };
 use ruma::api::client::room::create_room::v3 as create_room_v3;
@@ -179,13 +188,222 @@ pub struct MessageEvent {
     pub reply_to_body: Option<String>,
     pub attachment: Option<AttachmentInfo>,
     pub thread_root_event_id: Option<String>,
+    /// `org.matrix.custom.html` body, when the sender provided one.
+    pub formatted_body: Option<String>,
+    /// Users listed in the event's `m.mentions`.
+    pub mentioned_user_ids: Vec<String>,
+    /// Whether `m.mentions` contains `@room`.
+    pub mentions_room: bool,
+    pub kind: MessageKind,
+    pub reactions: Vec<ReactionSummary>,
+    pub edited: bool,
+    /// Revisions known to the timeline, oldest first: the original and the latest
+    /// edit. Use [`Client::edit_history`] to fetch every intermediate revision.
+    pub edit_history: Vec<EditRevision>,
+    /// Users whose latest read receipt is on this event.
+    pub read_by: Vec<ReadReceipt>,
+    /// Authenticity of an encrypted event; `None` when it wasn't encrypted.
+    pub shield: Option<EventShield>,
+    /// Set when `send_state` is `Failed`.
+    pub send_error: Option<SendFailure>,
+    /// Display name / avatar change, for profile change items.
+    pub profile_change: Option<ProfileChange>,
+    /// http(s) links in the body, in order; see `room_url_preview`.
+    pub links: Vec<String>,
+    /// Set for `m.location` messages.
+    pub location: Option<LocationInfo>,
+}
+
+/// A static location (`m.location`, MSC3488).
+#[derive(Clone, Record)]
+pub struct LocationInfo {
+    pub geo_uri: String,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub description: Option<String>,
+    /// Suggested map zoom, 0..=20.
+    pub zoom_level: Option<u32>,
+    /// The location of a place (pin) rather than of the sender.
+    pub is_pin: bool,
+}
+
+/// OpenGraph data of a link, as returned by the homeserver's `/preview_url`.
+#[derive(Clone, Serialize, Deserialize, Record)]
+pub struct UrlPreview {
+    pub url: String,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub site_name: Option<String>,
+    /// `mxc://` of the preview image, already on our homeserver.
+    pub image_mxc: Option<String>,
+    pub image_width: Option<u32>,
+    pub image_height: Option<u32>,
+}
+
+/// A member's display name and/or avatar change (`m.room.member` with an
+/// unchanged membership).
+#[derive(Clone, Record)]
+pub struct ProfileChange {
+    pub user_id: String,
+    pub display_name_changed: bool,
+    pub old_display_name: Option<String>,
+    pub new_display_name: Option<String>,
+    pub avatar_changed: bool,
+    pub old_avatar_url: Option<String>,
+    pub new_avatar_url: Option<String>,
+}
+
+#[derive(Clone, Copy, Enum)]
+pub enum MessageKind {
+    Text,
+    Emote,
+    Notice,
+    Image,
+    Video,
+    Audio,
+    File,
+    Location,
+    Sticker,
+    Poll,
+    Redacted,
+    UnableToDecrypt,
+    /// Membership, profile and other state changes rendered as text.
+    State,
+    Other,
+}
+
+#[derive(Clone, Record)]
+pub struct EditRevision {
+    pub event_id: Option<String>,
+    pub body: String,
+    pub timestamp_ms: u64,
+}
+
+#[derive(Clone, Record)]
+pub struct ReadReceipt {
+    pub user_id: String,
+    pub timestamp_ms: Option<u64>,
+}
+
+#[derive(Clone, Copy, Enum)]
+pub enum EventShield {
+    Verified,
+    /// Sender's identity isn't verified by us.
+    UnverifiedIdentity,
+    /// Sender's device isn't signed by its owner.
+    UnverifiedDevice,
+    /// Sending device is unknown (e.g. deleted).
+    UnknownDevice,
+    /// Keys came from an insecure source (key backup, forwarded), so the
+    /// sender can't be confirmed.
+    AuthenticityUnknown,
+    /// Sender was verified before but their identity changed.
+    VerificationViolation,
+}
+
+#[derive(Clone, Enum)]
+pub enum SendFailureKind {
+    Network,
+    Forbidden,
+    RateLimited {
+        retry_after_ms: Option<u64>,
+    },
+    /// Rejected content: too large, malformed…
+    InvalidContent,
+    /// Session expired; log in again.
+    Unauthorized,
+    Crypto,
+    Other,
+}
+
+#[derive(Clone, Record)]
+pub struct SendFailure {
+    pub kind: SendFailureKind,
+    pub message: String,
+    /// The send queue will retry by itself; otherwise retry or discard manually.
+    pub recoverable: bool,
+}
+
+#[derive(Clone, Enum)]
+pub enum TextFormat {
+    Plain,
+    /// Body is Markdown; converted to `org.matrix.custom.html` when it has markup.
+    Markdown,
+    /// Caller supplies the HTML; `body` is used as the plain fallback.
+    Html {
+        html: String,
+    },
+}
+
+#[derive(Clone, Copy, Enum)]
+pub enum TextMsgKind {
+    Text,
+    Emote,
+    Notice,
+}
+
+#[derive(Clone, Record)]
+pub struct TextOptions {
+    pub format: TextFormat,
+    pub kind: TextMsgKind,
+    /// Users mentioned in the body (MXIDs). Emitted in `m.mentions` and as pills.
+    pub mentioned_user_ids: Vec<String>,
+    /// `@room` mention.
+    pub mention_room: bool,
+    /// Custom emoji (MSC2545): each `:shortcode:` in the body becomes an inline image.
+    pub custom_emojis: Vec<PackImage>,
+}
+
+/// An image of an MSC2545 image pack.
+#[derive(Clone, Record)]
+pub struct PackImage {
+    pub shortcode: String,
+    pub mxc_uri: String,
+    pub body: Option<String>,
+    pub mime: Option<String>,
+    pub size_bytes: Option<u64>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub is_emoticon: bool,
+    pub is_sticker: bool,
+}
+
+/// An MSC2545 image pack: the account's own (`im.ponies.user_emotes`) or a
+/// room's (`im.ponies.room_emotes` state).
+#[derive(Clone, Record)]
+pub struct ImagePack {
+    /// `user` for the account pack, else `<room_id>/<state_key>`.
+    pub id: String,
+    pub room_id: Option<String>,
+    pub state_key: Option<String>,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
+    pub attribution: Option<String>,
+    pub images: Vec<PackImage>,
+}
+
+/// Caption and placement of an attachment.
+#[derive(Clone, Record)]
+pub struct AttachmentOptions {
+    /// MSC2530 caption, sent as the body next to the filename.
+    pub caption: Option<String>,
+    /// Formatting and mentions of the caption. Media can't be an emote, so a
+    /// "/me " caption is refused.
+    pub caption_options: Option<TextOptions>,
+    pub reply_to_event_id: Option<String>,
+    /// Post inside this thread. With `reply_to_event_id` set, the thread is the
+    /// replied-to event's.
+    pub thread_root_event_id: Option<String>,
 }
 
 #[derive(Clone, Enum)]
 pub enum AttachmentKind {
     Image,
     Video,
+    Audio,
     File,
+    /// `m.sticker`; `caption` holds the sticker's description.
+    Sticker,
 }
 
 #[derive(Clone, Record)]
@@ -211,6 +429,14 @@ pub struct AttachmentInfo {
     pub encrypted: Option<EncFile>,
     /// encrypted "thumbnail_file"
     pub thumbnail_encrypted: Option<EncFile>,
+    /// Original file name (MSC2530 `filename`, else the body).
+    pub filename: Option<String>,
+    /// Caption, when the body is distinct from the filename (MSC2530).
+    pub caption: Option<String>,
+    /// Audio sent as a voice message (MSC3245).
+    pub is_voice: bool,
+    /// Audio waveform (MSC1767), each sample normalised to 0.0..=1.0.
+    pub waveform: Vec<f32>,
 }
 
 #[derive(Clone, Record)]
@@ -220,6 +446,43 @@ pub struct DeviceSummary {
     pub ed25519: String,
     pub is_own: bool,
     pub verified: bool,
+    pub last_seen_ip: Option<String>,
+    pub last_seen_ts_ms: Option<u64>,
+    /// User agent of the last request (MSC3852), if the server reports it.
+    pub user_agent: Option<String>,
+}
+
+/// Trust in a user's cross-signing identity.
+#[derive(Clone, Copy, Enum)]
+pub enum IdentityTrust {
+    Verified,
+    /// Not verified, but the identity is the one we first saw.
+    Pinned,
+    /// Changed since we pinned it; the user must acknowledge the new one.
+    IdentityChanged,
+    /// Changed after we had verified it.
+    VerificationViolation,
+}
+
+#[derive(Clone, Record)]
+pub struct UserIdentityInfo {
+    pub user_id: String,
+    /// Base64 ed25519 master key.
+    pub master_key: Option<String>,
+    pub trust: IdentityTrust,
+    pub was_previously_verified: bool,
+}
+
+#[export(callback_interface)]
+pub trait IdentityObserver: Send + Sync {
+    /// Members of the room whose identity trust changed.
+    fn on_changes(&self, changes: Vec<IdentityChange>);
+}
+
+#[derive(Clone, Record)]
+pub struct IdentityChange {
+    pub user_id: String,
+    pub trust: IdentityTrust,
 }
 
 #[derive(Clone, Enum)]
@@ -276,6 +539,74 @@ pub struct DownloadResult {
     pub bytes: u64,
 }
 
+/// Server-side thumbnail size for `resolve_media`.
+#[derive(Clone, Copy, Record)]
+pub struct ThumbnailSize {
+    pub width: u32,
+    pub height: u32,
+    /// Crop to fill the box instead of scaling to fit inside it.
+    pub crop: bool,
+}
+
+#[derive(Clone, Enum)]
+pub enum DownloadState {
+    /// Waiting for a free slot (see `set_max_concurrent_downloads`).
+    Queued,
+    Downloading,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Clone, Record)]
+pub struct DownloadInfo {
+    pub id: u64,
+    pub mxc_uri: String,
+    pub path: String,
+    pub state: DownloadState,
+    pub downloaded: u64,
+    pub total: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[export(callback_interface)]
+pub trait DownloadObserver: Send + Sync {
+    fn on_update(&self, info: DownloadInfo);
+}
+
+#[derive(Clone, Record)]
+pub struct SsoProvider {
+    pub id: String,
+    pub name: String,
+    pub icon_mxc: Option<String>,
+}
+
+#[derive(Clone, Record)]
+pub struct LoginMethods {
+    pub password: bool,
+    pub sso: bool,
+    pub sso_providers: Vec<SsoProvider>,
+    /// Next-gen auth (OAuth 2.0 / OIDC, e.g. Matrix Authentication Service).
+    pub oauth: bool,
+    pub token: bool,
+}
+
+#[derive(Clone, Record)]
+pub struct ServerInfo {
+    /// Resolved homeserver base URL (after `.well-known` discovery).
+    pub homeserver_url: String,
+    pub supported_versions: Vec<String>,
+    pub login_methods: LoginMethods,
+}
+
+#[derive(Clone, Record)]
+pub struct OAuthAuthorization {
+    /// Authorization URL to open in a browser.
+    pub url: String,
+    /// CSRF state; echoed back in the redirect query.
+    pub state: String,
+}
+
 #[derive(Clone, Record)]
 pub struct RenderedNotification {
     pub room_id: String,
@@ -304,6 +635,7 @@ pub struct RoomProfile {
     pub member_count: u64,
     pub is_encrypted: bool,
     pub is_dm: bool,
+    pub avatar_url: Option<String>,
 }
 
 #[derive(Clone, Record)]
@@ -312,6 +644,18 @@ pub struct MemberSummary {
     pub display_name: Option<String>,
     pub is_me: bool,
     pub membership: String,
+    /// Room-specific avatar, else the global one.
+    pub avatar_url: Option<String>,
+    /// Room creators in room version 12+ have unlimited power: `i64::MAX`.
+    pub power_level: i64,
+}
+
+/// Global profile of a user.
+#[derive(Clone, Record)]
+pub struct UserProfile {
+    pub user_id: String,
+    pub display_name: Option<String>,
+    pub avatar_url: Option<String>,
 }
 
 enum RoomListCmd {
@@ -333,6 +677,29 @@ pub struct ThreadPage {
     pub prev_batch: Option<String>,
 }
 
+#[derive(Clone, Record)]
+pub struct SearchHit {
+    pub event: MessageEvent,
+    /// Oldest first.
+    pub context_before: Vec<MessageEvent>,
+    /// Oldest first.
+    pub context_after: Vec<MessageEvent>,
+    /// Server-side rank; `None` for hits from the local index.
+    pub rank: Option<f64>,
+    /// True when the hit came from the on-device index (encrypted rooms).
+    pub local: bool,
+}
+
+#[derive(Clone, Record)]
+pub struct SearchPage {
+    /// Newest first.
+    pub hits: Vec<SearchHit>,
+    /// Total server-side matches, if the server reported it.
+    pub server_count: Option<u64>,
+    /// Pass back to `search_messages` for the next page; `None` when exhausted.
+    pub next_batch: Option<String>,
+}
+
 #[derive(Clone, Record)]
 pub struct ThreadSummary {
     pub root_event_id: String,
@@ -356,6 +723,10 @@ pub enum SasPhase {
     Cancelled,
     Failed,
     Done,
+    /// The other device scanned our QR code; confirm once it shows success.
+    QrScanned,
+    /// We scanned their QR code and are waiting for them to confirm.
+    QrReciprocated,
 }
 
 #[derive(Clone, Record)]
@@ -373,6 +744,25 @@ pub trait VerificationObserver: Send + Sync {
     fn on_error(&self, flow_id: String, message: String);
 }
 
+/// Steps of an MSC4108 QR login on the new device.
+#[derive(Clone, Enum)]
+pub enum QrLoginProgress {
+    Starting,
+    /// Show this code; the existing device asks the user to type it in.
+    EstablishingSecureChannel {
+        check_code: u8,
+    },
+    WaitingForToken {
+        user_code: String,
+    },
+    Done,
+}
+
+#[export(callback_interface)]
+pub trait QrLoginObserver: Send + Sync {
+    fn on_progress(&self, progress: QrLoginProgress);
+}
+
 #[export(callback_interface)]
 pub trait VerificationInboxObserver: Send + Sync {
     fn on_request(&self, flow_id: String, from_user: String, from_device: String);
@@ -461,6 +851,7 @@ pub struct ReactionSummary {
     pub key: String,
     pub count: u32,
     pub me: bool,
+    pub senders: Vec<String>,
 }
 
 #[derive(Clone, Record)]
@@ -493,18 +884,46 @@ pub struct SpaceHierarchyPage {
     pub next_batch: Option<String>,
 }
 
+/// One entry of the timeline list. Virtual items are included so that diff
+/// indices line up with the SDK's timeline one-to-one.
+// UniFFI can't lift a `Box<MessageEvent>`, so the event variant stays inline.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Enum)]
+pub enum TimelineItemKind {
+    Event {
+        event: MessageEvent,
+    },
+    /// Day separator; `timestamp_ms` is the start of that day's first event.
+    DateDivider {
+        item_id: String,
+        timestamp_ms: u64,
+    },
+    /// "New messages" marker: everything after it is unread.
+    ReadMarker {
+        item_id: String,
+    },
+    /// The room's history has been fully loaded.
+    TimelineStart {
+        item_id: String,
+    },
+    /// Placeholder for anything we don't map, to keep indices aligned.
+    Other {
+        item_id: String,
+    },
+}
+
 #[derive(Clone, Enum)]
 pub enum TimelineDiffKind {
-    Append { values: Vec<MessageEvent> },
-    PushBack { value: MessageEvent },
-    PushFront { value: MessageEvent },
+    Append { values: Vec<TimelineItemKind> },
+    PushBack { value: TimelineItemKind },
+    PushFront { value: TimelineItemKind },
     PopBack,
     PopFront,
-    Insert { index: u32, value: MessageEvent },
+    Insert { index: u32, value: TimelineItemKind },
     Remove { index: u32 },
-    Set { index: u32, value: MessageEvent },
+    Set { index: u32, value: TimelineItemKind },
     Truncate { length: u32 },
-    Reset { values: Vec<MessageEvent> },
+    Reset { values: Vec<TimelineItemKind> },
     Clear,
 }
 
@@ -532,19 +951,72 @@ pub struct PollDefinition {
     pub max_selections: u32,
 }
 
+#[derive(Clone, Record)]
+pub struct KeyImportResult {
+    pub imported: u64,
+    /// Keys in the file; the rest were already known.
+    pub total: u64,
+    pub room_ids: Vec<String>,
+}
+
+/// Whether our secrets are stored server-side (4S) under a recovery key.
+#[derive(Clone, Copy, Enum)]
+pub enum RecoveryState {
+    /// Not known yet (before the first sync).
+    Unknown,
+    Enabled,
+    Disabled,
+    /// Recovery is set up, but this device lacks some secrets; enter the
+    /// recovery key to complete it.
+    Incomplete,
+}
+
+/// Server-side room key backup of this device.
+#[derive(Clone, Copy, Enum)]
+pub enum BackupState {
+    Unknown,
+    Creating,
+    Enabling,
+    Resuming,
+    Enabled,
+    Downloading,
+    Disabling,
+}
+
+#[export(callback_interface)]
+pub trait BackupObserver: Send + Sync {
+    fn on_recovery_state(&self, state: RecoveryState);
+    fn on_backup_state(&self, state: BackupState);
+    /// Room keys uploaded to the backup so far, of `total` to upload.
+    fn on_upload_progress(&self, backed_up: u64, total: u64);
+}
+
 #[derive(Clone, Record)]
 pub struct LiveLocationShareInfo {
     pub user_id: String,
     pub geo_uri: String,
     pub ts_ms: u64,
     pub is_live: bool,
+    pub description: Option<String>,
+    /// When the share ends unless renewed.
+    pub expires_at_ms: Option<u64>,
+    /// Tracks a place (pin) rather than the sender.
+    pub is_pin: bool,
 }
 
 #[export(callback_interface)]
 pub trait LiveLocationObserver: Send + Sync {
+    /// Every active share in the room, sorted by user; sent on each change and
+    /// when a share expires.
     fn on_update(&self, shares: Vec<LiveLocationShareInfo>);
 }
 
+/// How often `observe_live_location` drops expired shares.
+const LIVE_LOCATION_EXPIRY_CHECK: Duration = Duration::from_secs(10);
+
+/// How long a flow waits for the user to finish in the browser.
+const BROWSER_AUTH_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
 #[derive(Clone, Enum)]
 pub enum Presence {
     Online,
@@ -610,11 +1082,11 @@ impl From<PredecessorRoom> for PredecessorRoomInfo {
     }
 }
 
-fn cache_dir(dir: &PathBuf) -> PathBuf {
+fn cache_dir(dir: &Path) -> PathBuf {
     dir.join("media_cache")
 }
 
-fn ensure_dir(d: &PathBuf) {
+fn ensure_dir(d: &Path) {
     let _ = std::fs::create_dir_all(d);
 }
 
@@ -648,106 +1120,708 @@ struct SessionInfo {
     access_token: String,
     refresh_token: Option<String>,
     homeserver: String,
+    /// Set for OAuth 2.0 / OIDC sessions; restored via `OAuthSession`.
+    #[serde(default)]
+    oauth_client_id: Option<String>,
 }
 
-// TODO: remove later (after the login fix, should not be needed)
-fn session_file(dir: &PathBuf) -> PathBuf {
-    dir.join("session.json")
+// Multi-account index
+#[derive(Clone, Serialize, Deserialize, Record)]
+pub struct AccountInfo {
+    pub account_id: String,
+    pub homeserver_url: String,
+    pub user_id: Option<String>,
+    pub store_dir: String,
 }
 
-#[derive(Debug, Error, uniffi::Error)]
-pub enum FfiError {
-    #[error("{0}")]
-    Msg(String),
+#[derive(Default, Serialize, Deserialize)]
+struct AccountIndex {
+    accounts: Vec<AccountInfo>,
+    active: Option<String>,
 }
 
-impl From<matrix_sdk::Error> for FfiError {
-    fn from(e: matrix_sdk::Error) -> Self {
-        FfiError::Msg(format!("matrix_sdk error: {e:?}"))
-    }
+fn accounts_file(dir: &Path) -> PathBuf {
+    dir.join("accounts.json")
 }
 
-impl From<matrix_sdk_ui::notification_client::Error> for FfiError {
-    fn from(e: matrix_sdk_ui::notification_client::Error) -> Self {
-        FfiError::Msg(format!("NotificationClient error: {e:?}"))
-    }
+/// Plaintext session file, used when the stores have no passphrase.
+fn session_file(dir: &Path) -> PathBuf {
+    dir.join("session.json")
 }
 
-impl From<std::io::Error> for FfiError {
-    fn from(e: std::io::Error) -> Self {
-        FfiError::Msg(format!("io error: {e:?}"))
-    }
-}
+/// Key of the session blob inside the (optionally encrypted) state store.
+const SESSION_STORE_KEY: &[u8] = b"mages.session";
 
-struct VerifFlow {
-    sas: SasVerification,
-    _other_user: OwnedUserId,
-    _other_device: OwnedDeviceId,
+/// The whole local search index as one blob, as older versions stored it.
+/// Migrated into per-room entries on load.
+const LEGACY_SEARCH_INDEX_KEY: &[u8] = b"mages.search_index";
+
+/// Rooms that have a search index entry in the state store.
+const SEARCH_INDEX_ROOMS_KEY: &[u8] = b"mages.search_index.rooms";
+
+/// Key of one room's search index entry.
+fn search_index_room_key(room_id: &str) -> Vec<u8> {
+    format!("mages.search_index.room.{room_id}").into_bytes()
 }
 
-type VerifMap = Arc<Mutex<HashMap<String, VerifFlow>>>;
+/// Key of the URL preview cache (and its settings) inside the state store.
+const URL_PREVIEW_STORE_KEY: &[u8] = b"mages.url_previews";
 
-#[derive(Object)]
-pub struct Client {
-    inner: SdkClient,
-    store_dir: PathBuf,
-    guards: Mutex<Vec<tokio::task::JoinHandle<()>>>,
-    verifs: VerifMap,
-    send_observers: Arc<Mutex<HashMap<u64, Arc<dyn SendObserver>>>>,
-    send_obs_counter: AtomicU64,
-    send_tx: tokio::sync::mpsc::UnboundedSender<SendUpdate>,
-    inbox: Arc<Mutex<HashMap<String, (OwnedUserId, OwnedDeviceId)>>>,
-    sync_service: Arc<Mutex<Option<Arc<SyncService>>>>,
-    subs_counter: AtomicU64,
-    timeline_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
-    typing_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
-    connection_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
-    inbox_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
-    receipts_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
-    room_list_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
-    room_list_cmds: Mutex<HashMap<u64, tokio::sync::mpsc::UnboundedSender<RoomListCmd>>>,
-    send_handles_by_txn: Mutex<HashMap<String, matrix_sdk::send_queue::SendHandle>>,
-    call_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
-    live_location_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+/// Present when the stores in this dir were created with a passphrase.
+fn store_encrypted_marker(dir: &Path) -> PathBuf {
+    dir.join("store_encrypted")
 }
 
-#[derive(Clone, Enum)]
-pub enum SendState {
-    Enqueued,
-    Sending,
-    Sent,
-    Retrying,
-    Failed,
+#[export(callback_interface)]
+pub trait StoreKeyProvider: Send + Sync {
+    /// Passphrase for the stores in `store_dir`, or None to keep them unencrypted.
+    fn store_passphrase(&self, store_dir: String) -> Option<String>;
 }
 
-#[derive(Clone, Record)]
-pub struct SendUpdate {
-    pub room_id: String,
-    pub txn_id: String,
-    pub attempts: u32,
-    pub state: SendState,
-    pub event_id: Option<String>,
-    pub error: Option<String>,
+#[derive(Clone, Debug, Error, uniffi::Error)]
+pub enum FfiError {
+    /// Could not reach the homeserver (DNS, TLS, timeout, offline).
+    #[error("network error: {message}")]
+    Network { message: String },
+    /// `M_FORBIDDEN`: not allowed (power levels, bans, guest access…).
+    #[error("forbidden: {message}")]
+    Forbidden { message: String },
+    /// Unknown room/event/user, or `M_NOT_FOUND` from the server.
+    #[error("not found: {message}")]
+    NotFound { message: String },
+    /// `M_LIMIT_EXCEEDED`; retry after `retry_after_ms` if the server said so.
+    #[error("rate limited: {message}")]
+    RateLimited {
+        retry_after_ms: Option<u64>,
+        message: String,
+    },
+    /// Malformed ID, URL, JSON or other argument.
+    #[error("invalid input: {message}")]
+    InvalidInput { message: String },
+    /// Access token is no longer valid; the user has to log in again
+    /// (or just refresh, if `soft_logout`).
+    #[error("unknown token: {message}")]
+    UnknownToken { soft_logout: bool, message: String },
+    #[error("crypto error: {message}")]
+    Crypto { message: String },
+    #[error("store error: {message}")]
+    Store { message: String },
+    /// Anything not covered above.
+    #[error("{0}")]
+    Msg(String),
 }
 
-#[derive(Clone, Record)]
-pub struct PresenceInfo {
-    pub presence: Presence,
-    pub status_msg: Option<String>,
+impl FfiError {
+    fn invalid_input(message: impl Into<String>) -> Self {
+        FfiError::InvalidInput {
+            message: message.into(),
+        }
+    }
+
+    fn not_found(message: impl Into<String>) -> Self {
+        FfiError::NotFound {
+            message: message.into(),
+        }
+    }
+
+    /// Classify a Matrix API error by its `errcode`.
+    fn from_api_kind(kind: &ruma::api::client::error::ErrorKind, message: String) -> Self {
+        use ruma::api::client::error::{ErrorKind, RetryAfter};
+
+        match kind {
+            ErrorKind::Forbidden { .. } => FfiError::Forbidden { message },
+            ErrorKind::NotFound => FfiError::NotFound { message },
+            ErrorKind::LimitExceeded { retry_after } => {
+                let retry_after_ms = retry_after.as_ref().map(|r| match r {
+                    RetryAfter::Delay(d) => d.as_millis() as u64,
+                    RetryAfter::DateTime(t) => t
+                        .duration_since(SystemTime::now())
+                        .map(|d| d.as_millis() as u64)
+                        .unwrap_or(0),
+                });
+                FfiError::RateLimited {
+                    retry_after_ms,
+                    message,
+                }
+            }
+            ErrorKind::UnknownToken { soft_logout } => FfiError::UnknownToken {
+                soft_logout: *soft_logout,
+                message,
+            },
+            ErrorKind::MissingToken => FfiError::UnknownToken {
+                soft_logout: false,
+                message,
+            },
+            ErrorKind::BadJson
+            | ErrorKind::NotJson
+            | ErrorKind::InvalidParam
+            | ErrorKind::InvalidUsername
+            | ErrorKind::InvalidRoomState
+            | ErrorKind::MissingParam
+            | ErrorKind::TooLarge
+            | ErrorKind::BadAlias => FfiError::InvalidInput { message },
+            _ => FfiError::Msg(message),
+        }
+    }
 }
 
-#[export(callback_interface)]
-pub trait SendObserver: Send + Sync {
-    fn on_update(&self, update: SendUpdate);
+impl From<matrix_sdk::HttpError> for FfiError {
+    fn from(e: matrix_sdk::HttpError) -> Self {
+        FfiError::from_http(&e)
+    }
 }
 
-macro_rules! sub_manager {
-    ($self:expr, $subs:ident, $spawn:expr) => {{
-        let id = $self.next_sub_id();
-        let h = RT.spawn($spawn);
-        $self.$subs.lock().unwrap().insert(id, h);
-        id
-    }};
+impl From<matrix_sdk::Error> for FfiError {
+    fn from(e: matrix_sdk::Error) -> Self {
+        FfiError::from_sdk(&e)
+    }
+}
+
+impl FfiError {
+    fn from_http(e: &matrix_sdk::HttpError) -> Self {
+        let message = e.to_string();
+        if let Some(kind) = e.client_api_error_kind() {
+            return FfiError::from_api_kind(kind, message);
+        }
+        match e {
+            matrix_sdk::HttpError::Reqwest(_) => FfiError::Network { message },
+            _ => FfiError::Msg(message),
+        }
+    }
+
+    /// For SDK errors we can't name (e.g. the beacon errors): classify by the
+    /// HTTP or SDK error they wrap, if any.
+    fn from_wrapped(e: &(dyn std::error::Error + 'static)) -> Self {
+        let source = e.source();
+        if let Some(http) = source.and_then(|s| s.downcast_ref::<matrix_sdk::HttpError>()) {
+            return FfiError::from_http(http);
+        }
+        if let Some(sdk) = source.and_then(|s| s.downcast_ref::<matrix_sdk::Error>()) {
+            return FfiError::from_sdk(sdk);
+        }
+        FfiError::Msg(e.to_string())
+    }
+
+    fn from_sdk(e: &matrix_sdk::Error) -> Self {
+        let message = e.to_string();
+        if let Some(kind) = e.client_api_error_kind() {
+            return FfiError::from_api_kind(kind, message);
+        }
+        match e {
+            matrix_sdk::Error::Http(h) if matches!(**h, matrix_sdk::HttpError::Reqwest(_)) => {
+                FfiError::Network { message }
+            }
+            matrix_sdk::Error::OlmError(_)
+            | matrix_sdk::Error::MegolmError(_)
+            | matrix_sdk::Error::CryptoStoreError(_) => FfiError::Crypto { message },
+            matrix_sdk::Error::StateStore(_) | matrix_sdk::Error::EventCacheStore(_) => {
+                FfiError::Store { message }
+            }
+            matrix_sdk::Error::Io(_) => FfiError::Store { message },
+            _ => FfiError::Msg(message),
+        }
+    }
+}
+
+impl From<matrix_sdk::encryption::recovery::RecoveryError> for FfiError {
+    fn from(e: matrix_sdk::encryption::recovery::RecoveryError) -> Self {
+        use matrix_sdk::encryption::recovery::RecoveryError;
+
+        match &e {
+            RecoveryError::Sdk(inner) => FfiError::from_sdk(inner),
+            _ => FfiError::Crypto {
+                message: e.to_string(),
+            },
+        }
+    }
+}
+
+impl From<matrix_sdk_crypto::CryptoStoreError> for FfiError {
+    fn from(e: matrix_sdk_crypto::CryptoStoreError) -> Self {
+        FfiError::Crypto {
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<matrix_sdk_ui::timeline::Error> for FfiError {
+    fn from(e: matrix_sdk_ui::timeline::Error) -> Self {
+        use matrix_sdk::event_cache::EventCacheError;
+        use matrix_sdk::paginators::PaginatorError;
+        use matrix_sdk::room::{edit::EditError as RoomEditError, reply::ReplyError};
+        use matrix_sdk_ui::timeline::{EditError, Error, PaginationError, RedactError};
+
+        let message = e.to_string();
+        match e {
+            Error::EventNotInTimeline(_) | Error::RedactError(RedactError::ItemNotFound(_)) => {
+                FfiError::NotFound { message }
+            }
+            Error::UnsupportedEvent
+            | Error::InvalidAttachmentData
+            | Error::InvalidAttachmentFileName
+            | Error::EditError(EditError::ContentMismatch { .. }) => {
+                FfiError::InvalidInput { message }
+            }
+            Error::EditError(EditError::RoomError(RoomEditError::NotAuthor)) => {
+                FfiError::Forbidden { message }
+            }
+            Error::EditError(EditError::RoomError(RoomEditError::Fetch(e)))
+            | Error::ReplyError(ReplyError::Fetch(e))
+            | Error::EventCacheError(EventCacheError::BackpaginationError(e))
+            | Error::PaginationError(PaginationError::Paginator(PaginatorError::SdkError(e))) => {
+                FfiError::from_sdk(&e)
+            }
+            Error::EventCacheError(EventCacheError::RoomNotFound { .. }) => {
+                FfiError::NotFound { message }
+            }
+            Error::EventCacheError(
+                EventCacheError::Storage(_) | EventCacheError::LockingStorage(_),
+            ) => FfiError::Store { message },
+            Error::RedactError(RedactError::HttpError(e)) => e.into(),
+            Error::SendQueueError(e) => e.into(),
+            _ => FfiError::Msg(message),
+        }
+    }
+}
+
+impl From<matrix_sdk::send_queue::RoomSendQueueError> for FfiError {
+    fn from(e: matrix_sdk::send_queue::RoomSendQueueError) -> Self {
+        use matrix_sdk::send_queue::RoomSendQueueError;
+
+        let message = e.to_string();
+        match e {
+            RoomSendQueueError::RoomNotJoined => FfiError::Forbidden { message },
+            RoomSendQueueError::RoomDisappeared => FfiError::NotFound { message },
+            RoomSendQueueError::StorageError(_) => FfiError::Store { message },
+            _ => FfiError::Msg(message),
+        }
+    }
+}
+
+impl From<matrix_sdk::room::reply::ReplyError> for FfiError {
+    fn from(e: matrix_sdk::room::reply::ReplyError) -> Self {
+        use matrix_sdk::room::reply::ReplyError;
+
+        match e {
+            ReplyError::Fetch(e) => FfiError::from_sdk(&e),
+            other => FfiError::invalid_input(other.to_string()),
+        }
+    }
+}
+
+impl From<matrix_sdk::NotificationSettingsError> for FfiError {
+    fn from(e: matrix_sdk::NotificationSettingsError) -> Self {
+        use matrix_sdk::NotificationSettingsError;
+
+        let message = e.to_string();
+        match e {
+            NotificationSettingsError::InvalidParameter(_) => FfiError::InvalidInput { message },
+            NotificationSettingsError::RuleNotFound(_) => FfiError::NotFound { message },
+            _ => FfiError::Msg(message),
+        }
+    }
+}
+
+impl From<matrix_sdk::authentication::oauth::OAuthError> for FfiError {
+    fn from(e: matrix_sdk::authentication::oauth::OAuthError) -> Self {
+        use matrix_sdk::authentication::oauth::{OAuthError, error::OAuthDiscoveryError};
+
+        match e {
+            OAuthError::Discovery(OAuthDiscoveryError::Http(e)) => e.into(),
+            e => FfiError::Msg(e.to_string()),
+        }
+    }
+}
+
+impl From<matrix_sdk::authentication::oauth::qrcode::QRCodeLoginError> for FfiError {
+    fn from(e: matrix_sdk::authentication::oauth::qrcode::QRCodeLoginError) -> Self {
+        use matrix_sdk::authentication::oauth::qrcode::QRCodeLoginError;
+
+        match e {
+            QRCodeLoginError::UserIdDiscovery(e) => e.into(),
+            QRCodeLoginError::SecureChannel(e) => FfiError::Crypto {
+                message: e.to_string(),
+            },
+            e => FfiError::Msg(e.to_string()),
+        }
+    }
+}
+
+impl From<matrix_sdk::ClientBuildError> for FfiError {
+    fn from(e: matrix_sdk::ClientBuildError) -> Self {
+        use matrix_sdk::ClientBuildError;
+
+        let message = e.to_string();
+        match e {
+            ClientBuildError::MissingHomeserver
+            | ClientBuildError::InvalidServerName
+            | ClientBuildError::Url(_) => FfiError::InvalidInput { message },
+            ClientBuildError::AutoDiscovery(inner) => FfiError::Network {
+                message: format!("{message}: {inner}"),
+            },
+            ClientBuildError::Http(inner) => match FfiError::from_http(&inner) {
+                FfiError::Msg(message) => FfiError::Network { message },
+                other => other,
+            },
+            ClientBuildError::SqliteStore(_) => FfiError::Store { message },
+            _ => FfiError::Msg(message),
+        }
+    }
+}
+
+impl From<matrix_sdk_ui::notification_client::Error> for FfiError {
+    fn from(e: matrix_sdk_ui::notification_client::Error) -> Self {
+        FfiError::Msg(format!("NotificationClient error: {e:?}"))
+    }
+}
+
+impl From<ruma::IdParseError> for FfiError {
+    fn from(e: ruma::IdParseError) -> Self {
+        FfiError::invalid_input(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for FfiError {
+    fn from(e: serde_json::Error) -> Self {
+        FfiError::invalid_input(format!("json: {e}"))
+    }
+}
+
+impl From<std::io::Error> for FfiError {
+    fn from(e: std::io::Error) -> Self {
+        FfiError::Store {
+            message: format!("io error: {e:?}"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum VerifKind {
+    Sas(SasVerification),
+    Qr(QrVerification),
+}
+
+struct VerifFlow {
+    kind: VerifKind,
+    _other_user: OwnedUserId,
+    _other_device: OwnedDeviceId,
+}
+
+type VerifMap = Arc<Mutex<HashMap<String, VerifFlow>>>;
+
+/// Flows a SAS starter is waiting on; a QR flow takes its id out of here to
+/// stop the starter, and the starter removes it when it ends.
+type SasStarters = Arc<Mutex<HashSet<String>>>;
+
+/// Timelines are cached per `Client`, so several accounts can live side by side.
+type TimelineCache = Arc<Mutex<HashMap<OwnedRoomId, Arc<Timeline>>>>;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct IndexedMessage {
+    event_id: String,
+    room_id: String,
+    sender: String,
+    body: String,
+    timestamp_ms: u64,
+}
+
+/// On-device full-text index for encrypted rooms, which the server can't search.
+/// Saved per room, and only into an encrypted state store: it holds decrypted
+/// message text.
+#[derive(Default)]
+struct SearchIndex {
+    docs: HashMap<String, IndexedMessage>,
+    postings: HashMap<String, HashSet<String>>,
+    /// Event ids of each room in timeline order.
+    by_room: HashMap<String, BTreeSet<(u64, String)>>,
+    /// Rooms changed since the last flush.
+    dirty: HashSet<String>,
+}
+
+type SearchIndexHandle = Arc<Mutex<SearchIndex>>;
+
+impl SearchIndex {
+    fn insert(&mut self, doc: IndexedMessage) {
+        if self
+            .docs
+            .get(&doc.event_id)
+            .is_some_and(|d| d.body == doc.body)
+        {
+            return;
+        }
+        self.remove(&doc.event_id);
+        for tok in search_tokens(&doc.body) {
+            self.postings
+                .entry(tok)
+                .or_default()
+                .insert(doc.event_id.clone());
+        }
+        self.by_room
+            .entry(doc.room_id.clone())
+            .or_default()
+            .insert((doc.timestamp_ms, doc.event_id.clone()));
+        self.dirty.insert(doc.room_id.clone());
+        self.docs.insert(doc.event_id.clone(), doc);
+    }
+
+    fn remove(&mut self, event_id: &str) {
+        let Some(old) = self.docs.remove(event_id) else {
+            return;
+        };
+        for tok in search_tokens(&old.body) {
+            if let Some(ids) = self.postings.get_mut(&tok) {
+                ids.remove(event_id);
+                if ids.is_empty() {
+                    self.postings.remove(&tok);
+                }
+            }
+        }
+        if let Some(room) = self.by_room.get_mut(&old.room_id) {
+            room.remove(&(old.timestamp_ms, old.event_id));
+            if room.is_empty() {
+                self.by_room.remove(&old.room_id);
+            }
+        }
+        self.dirty.insert(old.room_id);
+    }
+
+    /// Drop everything indexed for `rooms` (or the whole index for `None`).
+    fn clear(&mut self, rooms: Option<&HashSet<String>>) {
+        match rooms {
+            None => {
+                self.dirty
+                    .extend(self.by_room.drain().map(|(room, _)| room));
+                self.docs.clear();
+                self.postings.clear();
+            }
+            Some(rooms) => {
+                for room in rooms {
+                    let Some(ids) = self.by_room.get(room) else {
+                        continue;
+                    };
+                    let ids: Vec<String> = ids.iter().map(|(_, id)| id.clone()).collect();
+                    for id in ids {
+                        self.remove(&id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Every query term must match; the last one also matches as a prefix, so
+    /// search-as-you-type works. Newest first.
+    fn query(&self, query: &str, rooms: Option<&HashSet<String>>) -> Vec<IndexedMessage> {
+        let tokens = search_tokens(query);
+        let Some((last, rest)) = tokens.split_last() else {
+            return vec![];
+        };
+
+        let mut ids: HashSet<&String> = self
+            .postings
+            .iter()
+            .filter(|(tok, _)| tok.starts_with(last.as_str()))
+            .flat_map(|(_, ids)| ids.iter())
+            .collect();
+        for tok in rest {
+            let Some(with_tok) = self.postings.get(tok) else {
+                return vec![];
+            };
+            ids.retain(|id| with_tok.contains(*id));
+        }
+
+        let mut out: Vec<IndexedMessage> = ids
+            .into_iter()
+            .filter_map(|id| self.docs.get(id))
+            .filter(|d| rooms.is_none_or(|r| r.contains(&d.room_id)))
+            .cloned()
+            .collect();
+        out.sort_by(|a, b| b.timestamp_ms.cmp(&a.timestamp_ms));
+        out
+    }
+
+    /// Up to `n` indexed messages on either side of `doc` in its room.
+    fn context(
+        &self,
+        doc: &IndexedMessage,
+        n: usize,
+    ) -> (Vec<IndexedMessage>, Vec<IndexedMessage>) {
+        use std::ops::Bound::{Excluded, Unbounded};
+
+        let Some(room) = self.by_room.get(&doc.room_id).filter(|_| n > 0) else {
+            return (vec![], vec![]);
+        };
+        let key = (doc.timestamp_ms, doc.event_id.clone());
+        let lookup = |(_, id): &(u64, String)| self.docs.get(id).cloned();
+        let mut before: Vec<IndexedMessage> = room
+            .range(..key.clone())
+            .rev()
+            .take(n)
+            .filter_map(lookup)
+            .collect();
+        before.reverse();
+        let after = room
+            .range((Excluded(key), Unbounded))
+            .take(n)
+            .filter_map(lookup)
+            .collect();
+        (before, after)
+    }
+
+    /// The docs of every room changed since the last call (empty for rooms
+    /// now without any), and all rooms that still have docs.
+    fn take_dirty(&mut self) -> (Vec<(String, Vec<IndexedMessage>)>, Vec<String>) {
+        let changed = std::mem::take(&mut self.dirty)
+            .into_iter()
+            .map(|room| {
+                let docs = self
+                    .by_room
+                    .get(&room)
+                    .into_iter()
+                    .flatten()
+                    .filter_map(|(_, id)| self.docs.get(id).cloned())
+                    .collect();
+                (room, docs)
+            })
+            .collect();
+        (changed, self.by_room.keys().cloned().collect())
+    }
+}
+
+/// Previews are kept this long; failed lookups for `URL_PREVIEW_MISS_TTL_MS`.
+const URL_PREVIEW_TTL_MS: u64 = 24 * 60 * 60 * 1000;
+const URL_PREVIEW_MISS_TTL_MS: u64 = 60 * 60 * 1000;
+const URL_PREVIEW_CACHE_MAX: usize = 500;
+
+#[derive(Clone, Serialize, Deserialize)]
+struct CachedUrlPreview {
+    fetched_ms: u64,
+    preview: Option<UrlPreview>,
+}
+
+/// Persisted in the state store, so it is encrypted along with it.
+#[derive(Default, Serialize, Deserialize)]
+struct UrlPreviewCache {
+    /// Previews in encrypted rooms leak links to the homeserver; off by default.
+    #[serde(default)]
+    encrypted_rooms: bool,
+    #[serde(default)]
+    entries: HashMap<String, CachedUrlPreview>,
+    #[serde(skip)]
+    loaded: bool,
+}
+
+impl UrlPreviewCache {
+    fn get(&self, url: &str, now: u64) -> Option<&CachedUrlPreview> {
+        self.entries.get(url).filter(|c| {
+            let ttl = match c.preview {
+                Some(_) => URL_PREVIEW_TTL_MS,
+                None => URL_PREVIEW_MISS_TTL_MS,
+            };
+            now.saturating_sub(c.fetched_ms) < ttl
+        })
+    }
+
+    fn insert(&mut self, url: String, entry: CachedUrlPreview) {
+        self.entries.insert(url, entry);
+        if self.entries.len() > URL_PREVIEW_CACHE_MAX {
+            let mut by_age: Vec<(u64, String)> = self
+                .entries
+                .iter()
+                .map(|(k, v)| (v.fetched_ms, k.clone()))
+                .collect();
+            by_age.sort();
+            let excess = self.entries.len() - URL_PREVIEW_CACHE_MAX;
+            for (_, k) in by_age.into_iter().take(excess) {
+                self.entries.remove(&k);
+            }
+        }
+    }
+}
+
+/// Opaque `next_batch` for `search_messages`: server token + offset into local hits.
+#[derive(Default, Serialize, Deserialize)]
+struct SearchCursor {
+    server: Option<String>,
+    server_done: bool,
+    local_offset: usize,
+    local_done: bool,
+}
+
+#[derive(Object)]
+pub struct Client {
+    inner: SdkClient,
+    store_dir: PathBuf,
+    store_encrypted: bool,
+    guards: Mutex<Vec<tokio::task::JoinHandle<()>>>,
+    verifs: VerifMap,
+    sas_starters: SasStarters,
+    timelines: TimelineCache,
+    search_index: SearchIndexHandle,
+    send_observers: Arc<Mutex<HashMap<u64, Arc<dyn SendObserver>>>>,
+    send_obs_counter: AtomicU64,
+    send_tx: tokio::sync::mpsc::UnboundedSender<SendUpdate>,
+    inbox: Arc<Mutex<HashMap<String, (OwnedUserId, OwnedDeviceId)>>>,
+    sync_service: Arc<Mutex<Option<Arc<SyncService>>>>,
+    subs_counter: AtomicU64,
+    timeline_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    typing_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    connection_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    inbox_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    receipts_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    room_list_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    room_list_cmds: Mutex<HashMap<u64, tokio::sync::mpsc::UnboundedSender<RoomListCmd>>>,
+    send_handles_by_txn: Mutex<HashMap<String, matrix_sdk::send_queue::SendHandle>>,
+    call_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    live_location_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    backup_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    identity_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    /// Timers stopping our own live location shares when they expire, by room.
+    live_location_timers: Mutex<HashMap<OwnedRoomId, tokio::task::JoinHandle<()>>>,
+    downloads: Arc<DownloadManager>,
+    download_subs: Mutex<HashMap<u64, tokio::task::JoinHandle<()>>>,
+    media_proxy: Mutex<Option<MediaProxy>>,
+    url_previews: Arc<Mutex<UrlPreviewCache>>,
+    /// Fires `cancel_browser_auth` for the flow currently waiting on the browser.
+    browser_auth_cancel: Mutex<Option<tokio::sync::oneshot::Sender<()>>>,
+}
+
+#[derive(Clone, Enum)]
+pub enum SendState {
+    Enqueued,
+    Sending,
+    Sent,
+    Retrying,
+    Failed,
+}
+
+#[derive(Clone, Record)]
+pub struct SendUpdate {
+    pub room_id: String,
+    pub txn_id: String,
+    pub attempts: u32,
+    pub state: SendState,
+    pub event_id: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Record)]
+pub struct PresenceInfo {
+    pub presence: Presence,
+    pub status_msg: Option<String>,
+}
+
+#[export(callback_interface)]
+pub trait SendObserver: Send + Sync {
+    fn on_update(&self, update: SendUpdate);
+}
+
+macro_rules! sub_manager {
+    ($self:expr, $subs:ident, $spawn:expr) => {{
+        let id = $self.next_sub_id();
+        let h = RT.spawn($spawn);
+        $self.$subs.lock().unwrap().insert(id, h);
+        id
+    }};
 }
 
 macro_rules! unsub {
@@ -764,14 +1838,11 @@ macro_rules! unsub {
 macro_rules! with_room_async {
     ($self:expr, $room_id:expr, $body:expr) => {{
         RT.block_on(async {
-            let rid = match OwnedRoomId::try_from($room_id) {
-                Ok(r) => r,
-                Err(_) => return false,
-            };
-            let room = match $self.inner.get_room(&rid) {
-                Some(r) => r,
-                None => return false,
-            };
+            let rid = OwnedRoomId::try_from($room_id)?;
+            let room = $self
+                .inner
+                .get_room(&rid)
+                .ok_or_else(|| FfiError::not_found("room not found"))?;
             $body(room, rid).await
         })
     }};
@@ -780,14 +1851,10 @@ macro_rules! with_room_async {
 macro_rules! with_timeline_async {
     ($self:expr, $room_id:expr, $body:expr) => {{
         RT.block_on(async {
-            let rid = match OwnedRoomId::try_from($room_id) {
-                Ok(r) => r,
-                Err(_) => return false,
-            };
-            let tl = match get_timeline_for(&$self.inner, &rid).await {
-                Some(t) => t,
-                None => return false,
-            };
+            let rid = OwnedRoomId::try_from($room_id)?;
+            let tl = get_timeline_for(&$self.inner, &$self.timelines, &rid)
+                .await
+                .ok_or_else(|| FfiError::not_found("room/timeline not found"))?;
             $body(tl, rid).await
         })
     }};
@@ -812,196 +1879,59 @@ fn init_tracing() {
 
 #[export]
 impl Client {
+    /// Client with unencrypted stores. The session is saved in plaintext to
+    /// `session.json`; use a passphrase to keep it in the encrypted store instead.
     #[uniffi::constructor]
-    pub fn new(homeserver_url: String, store_dir: String) -> Self {
-        init_tracing();
+    pub fn new(homeserver_url: String, store_dir: String) -> Result<Self, FfiError> {
+        Self::try_build(HomeserverRef::Url(homeserver_url), store_dir, None)
+    }
 
-        let store_dir_path = std::path::PathBuf::from(&store_dir);
-        let _ = std::fs::create_dir_all(&store_dir_path);
+    /// Fallible constructor. `server` may be a homeserver URL, a bare server name
+    /// (resolved via `.well-known`) or a full MXID.
+    #[uniffi::constructor]
+    pub fn try_new(
+        server: String,
+        store_dir: String,
+        passphrase: Option<String>,
+    ) -> Result<Self, FfiError> {
+        Self::try_build(HomeserverRef::Discover(server), store_dir, passphrase)
+    }
 
-        let inner = RT.block_on(async {
-            SdkClient::builder()
-                .homeserver_url(&homeserver_url)
-                .sqlite_store(&store_dir_path, None)
-                .with_encryption_settings(EncryptionSettings {
-                    auto_enable_cross_signing: true,
-                    auto_enable_backups: true,
-                    backup_download_strategy: BackupDownloadStrategy::OneShot,
-                    ..Default::default()
-                })
-                .build()
-                .await
-                .expect("client")
-        });
+    /// Like `new`, but encrypts the state/crypto/event-cache stores with `passphrase`.
+    #[uniffi::constructor]
+    pub fn new_with_passphrase(
+        homeserver_url: String,
+        store_dir: String,
+        passphrase: String,
+    ) -> Result<Self, FfiError> {
+        Self::try_build(
+            HomeserverRef::Url(homeserver_url),
+            store_dir,
+            Some(passphrase),
+        )
+    }
+
+    /// Like `new`, but asks `provider` (e.g. backed by the Android Keystore) for the
+    /// store passphrase.
+    #[uniffi::constructor]
+    pub fn new_with_store_key(
+        homeserver_url: String,
+        store_dir: String,
+        provider: Box<dyn StoreKeyProvider>,
+    ) -> Result<Self, FfiError> {
+        let passphrase = provider.store_passphrase(store_dir.clone());
+        Self::try_build(HomeserverRef::Url(homeserver_url), store_dir, passphrase)
+    }
 
-        let (send_tx, mut send_rx) = tokio::sync::mpsc::unbounded_channel::<SendUpdate>();
-        let this = Self {
-            inner,
-            store_dir: store_dir_path,
-            guards: Mutex::new(vec![]),
-            verifs: Arc::new(Mutex::new(HashMap::new())),
-            send_observers: Arc::new(Mutex::new(HashMap::new())),
-            send_obs_counter: AtomicU64::new(0),
-            send_tx,
-            inbox: Arc::new(Mutex::new(HashMap::new())),
-            sync_service: Arc::new(Mutex::new(None)),
-            subs_counter: AtomicU64::new(0),
-            timeline_subs: Mutex::new(HashMap::new()),
-            typing_subs: Mutex::new(HashMap::new()),
-            connection_subs: Mutex::new(HashMap::new()),
-            inbox_subs: Mutex::new(HashMap::new()),
-            receipts_subs: Mutex::new(HashMap::new()),
-            room_list_subs: Mutex::new(HashMap::new()),
-            room_list_cmds: Mutex::new(HashMap::new()),
-            send_handles_by_txn: Mutex::new(HashMap::new()),
-            call_subs: Mutex::new(HashMap::new()),
-            live_location_subs: Mutex::new(HashMap::new()),
-        };
-
-        {
-            let client = this.inner.clone();
-            let h = RT.spawn(async move {
-                if let Some(mut stream) = client.encryption().room_keys_received_stream().await {
-                    while let Some(batch) = stream.next().await {
-                        let Ok(infos) = batch else { continue };
-                        use std::collections::HashMap;
-                        let mut by_room: HashMap<OwnedRoomId, Vec<String>> = HashMap::new();
-                        for info in infos {
-                            by_room
-                                .entry(info.room_id.clone())
-                                .or_default()
-                                .push(info.session_id.clone());
-                        }
-                        for (rid, sessions) in by_room {
-                            if let Some(tl) = get_timeline_for(&client, &rid).await {
-                                tl.retry_decryption(sessions).await;
-                            }
-                        }
-                    }
-                }
-            });
-            this.guards.lock().unwrap().push(h);
-        }
-
-        {
-            let observers = this.send_observers.clone();
-            let h = RT.spawn(async move {
-                while let Some(upd) = send_rx.recv().await {
-                    let list: Vec<Arc<dyn SendObserver>> = {
-                        let guard = observers.lock().expect("send_observers");
-                        guard.values().cloned().collect()
-                    };
-                    for obs in list {
-                        let upd_clone = upd.clone();
-                        let _ = std::panic::catch_unwind(AssertUnwindSafe(move || {
-                            obs.on_update(upd_clone)
-                        }));
-                    }
-                }
-            });
-            this.guards.lock().unwrap().push(h);
-        }
-
-        RT.block_on(async {
-            match this.inner.whoami().await {
-                Ok(_) => {
-                    if this.sync_service.lock().unwrap().is_none() {
-                        if let Ok(service) = SyncService::builder(this.inner.clone()).build().await
-                        {
-                            this.sync_service.lock().unwrap().replace(service.into());
-                        }
-                    }
-                }
-                Err(_) => {
-                    let path = session_file(&this.store_dir);
-                    if let Ok(txt) = tokio::fs::read_to_string(&path).await {
-                        if let Ok(info) = serde_json::from_str::<SessionInfo>(&txt) {
-                            if let Ok(user_id) = info.user_id.parse::<OwnedUserId>() {
-                                let session = MatrixSession {
-                                    meta: matrix_sdk::SessionMeta {
-                                        user_id,
-                                        device_id: info.device_id.clone().into(),
-                                    },
-                                    tokens: SessionTokens {
-                                        access_token: info.access_token.clone(),
-                                        refresh_token: info.refresh_token.clone(),
-                                    },
-                                };
-                                if this.inner.restore_session(session).await.is_ok() {
-                                    if this.sync_service.lock().unwrap().is_none() {
-                                        if let Ok(service) =
-                                            SyncService::builder(this.inner.clone()).build().await
-                                        {
-                                            this.sync_service
-                                                .lock()
-                                                .unwrap()
-                                                .replace(service.into());
-                                        }
-                                    }
-                                } else {
-                                    let _ = tokio::fs::remove_file(&path).await;
-                                    reset_store_dir(&this.store_dir);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        });
-
-        {
-            let inner = this.inner.clone();
-            let store = this.store_dir.clone();
-            let h = RT.spawn(async move {
-                let mut session_rx = inner.subscribe_to_session_changes();
-                while let Ok(update) = session_rx.recv().await {
-                    if let matrix_sdk::SessionChange::TokensRefreshed = update {
-                        if let Some(sess) = inner.matrix_auth().session() {
-                            let path = session_file(&store);
-                            let info = SessionInfo {
-                                user_id: sess.meta.user_id.to_string(),
-                                device_id: sess.meta.device_id.to_string(),
-                                access_token: sess.tokens.access_token.clone(),
-                                refresh_token: sess.tokens.refresh_token.clone(),
-                                homeserver: inner.homeserver().to_string(),
-                            };
-                            let _ =
-                                tokio::fs::write(path, serde_json::to_string(&info).unwrap()).await;
-                        }
-                    }
-                }
-            });
-            this.guards.lock().unwrap().push(h);
-        }
-
-        {
-            let client = this.inner.clone();
-            let h = RT.spawn(async move {
-                if let Some(mut stream) = client.encryption().room_keys_received_stream().await {
-                    while let Some(batch) = stream.next().await {
-                        let Ok(infos) = batch else { continue };
-                        use std::collections::HashMap;
-                        let mut by_room: HashMap<OwnedRoomId, Vec<String>> = HashMap::new();
-                        for info in infos {
-                            by_room
-                                .entry(info.room_id.clone())
-                                .or_default()
-                                .push(info.session_id.clone());
-                        }
-                        for (rid, sessions) in by_room {
-                            if let Some(room) = client.get_room(&rid) {
-                                if let Ok(tl) = room.timeline().await {
-                                    tl.retry_decryption(sessions).await;
-                                }
-                            }
-                        }
-                    }
-                }
-            });
-            this.guards.lock().unwrap().push(h);
-        }
+    /// Whether the stores are encrypted with a passphrase, and with them the
+    /// saved session.
+    pub fn store_encrypted(&self) -> bool {
+        self.store_encrypted
+    }
 
-        this
+    /// Login methods offered by this client's homeserver.
+    pub fn login_methods(&self) -> Result<LoginMethods, FfiError> {
+        RT.block_on(async { fetch_login_methods(&self.inner).await })
     }
 
     pub fn whoami(&self) -> Option<String> {
@@ -1023,24 +1953,9 @@ impl Client {
                 req = req.initial_device_display_name(name);
             }
 
-            let res = req.send().await.map_err(|e| FfiError::Msg(e.to_string()))?;
-
-            let info = SessionInfo {
-                user_id: res.user_id.to_string(),
-                device_id: res.device_id.to_string(),
-                access_token: res.access_token.clone(),
-                refresh_token: res.refresh_token.clone(),
-                homeserver: self.inner.homeserver().to_string(),
-            };
-
-            tokio::fs::create_dir_all(&self.store_dir).await?;
-            tokio::fs::write(
-                session_file(&self.store_dir),
-                serde_json::to_string(&info).unwrap(),
-            )
-            .await?;
+            req.send().await?;
 
-            Ok(())
+            persist_session(&self.inner, &self.store_dir, self.store_encrypted).await
         })
     }
 
@@ -1062,22 +1977,22 @@ impl Client {
         })
     }
 
-    pub fn set_typing(&self, room_id: String, typing: bool) -> bool {
+    pub fn set_typing(&self, room_id: String, typing: bool) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            room.typing_notice(typing).await.is_ok()
+            room.typing_notice(typing).await.map_err(FfiError::from)
         })
     }
 
     pub fn enter_foreground(&self) {
         if let Some(svc) = self.sync_service.lock().unwrap().as_ref().cloned() {
-            let _ = RT.block_on(async { svc.start().await });
+            RT.block_on(async { svc.start().await });
         }
     }
 
     /// Send the app to background: stop Sliding Sync supervision. (stub)
     pub fn enter_background(&self) {
         if let Some(svc) = self.sync_service.lock().unwrap().as_ref().cloned() {
-            let _ = RT.block_on(async { svc.stop().await });
+            RT.block_on(async { svc.stop().await });
         }
     }
 
@@ -1087,7 +2002,8 @@ impl Client {
                 return vec![];
             };
 
-            let Some(timeline) = get_timeline_for(&self.inner, &room_id).await else {
+            let Some(timeline) = get_timeline_for(&self.inner, &self.timelines, &room_id).await
+            else {
                 return vec![];
             };
 
@@ -1101,6 +2017,7 @@ impl Client {
                             ev,
                             room_id.as_str(),
                             Some(&it.unique_id().0.to_string()),
+                            self.inner.user_id(),
                         )
                     })
                 })
@@ -1113,50 +2030,63 @@ impl Client {
 
     pub fn observe_timeline(&self, room_id: String, observer: Box<dyn TimelineObserver>) -> u64 {
         let client = self.inner.clone();
+        let timelines = self.timelines.clone();
+        let index = self.search_index.clone();
         let Ok(room_id) = OwnedRoomId::try_from(room_id) else {
             return 0;
         };
         let obs: Arc<dyn TimelineObserver> = Arc::from(observer);
 
         sub_manager!(self, timeline_subs, async move {
-            let Some(tl) = get_timeline_for(&client, &room_id).await else {
+            let Some(tl) = get_timeline_for(&client, &timelines, &room_id).await else {
                 return;
             };
+            // Encrypted rooms can't be searched server-side; index what we decrypt.
+            let encrypted = match client.get_room(&room_id) {
+                Some(room) => room
+                    .latest_encryption_state()
+                    .await
+                    .map(|s| s.is_encrypted())
+                    .unwrap_or(false),
+                None => false,
+            };
 
             let (items, mut stream) = tl.subscribe().await;
+            if encrypted {
+                for it in items.iter() {
+                    if let Some(ev) = it.as_event() {
+                        index_timeline_item(&index, &room_id, ev);
+                    }
+                }
+            }
 
             // Initial snapshot
+            let me = client.user_id().map(|u| u.to_owned());
             let initial: Vec<_> = items
                 .iter()
-                .filter_map(|it| {
-                    it.as_event().and_then(|ei| {
-                        map_timeline_event(
-                            ei,
-                            room_id.as_str(),
-                            Some(&it.unique_id().0.to_string()),
-                        )
-                    })
-                })
+                .map(|it| map_timeline_item(it, room_id.as_str(), me.as_deref()))
                 .collect();
             obs.on_diff(TimelineDiffKind::Reset { values: initial });
 
             // Fetch missing reply details
             for it in items.iter() {
-                if let Some(ev) = it.as_event() {
-                    if let Some(eid) = missing_reply_event_id(ev) {
-                        let tlc = tl.clone();
-                        tokio::spawn(async move {
-                            let _ = tlc.fetch_details_for_event(eid.as_ref()).await;
-                        });
-                    }
+                if let Some(ev) = it.as_event()
+                    && let Some(eid) = missing_reply_event_id(ev)
+                {
+                    let tlc = tl.clone();
+                    tokio::spawn(async move {
+                        let _ = tlc.fetch_details_for_event(eid.as_ref()).await;
+                    });
                 }
             }
 
             while let Some(diffs) = stream.next().await {
                 for diff in diffs {
-                    if let Some(mapped) = map_vec_diff(diff, &room_id, &tl) {
-                        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| obs.on_diff(mapped)));
+                    if encrypted {
+                        index_vector_diff(&index, &room_id, &diff);
                     }
+                    let mapped = map_vec_diff(diff, &room_id, &tl, me.as_deref());
+                    let _ = std::panic::catch_unwind(AssertUnwindSafe(|| obs.on_diff(mapped)));
                 }
             }
         })
@@ -1212,23 +2142,23 @@ impl Client {
                 maybe = ir_sub.next() => {
                     info!("verification_inbox: in-room next = {:?}", maybe.as_ref().map(|(ev, _)| ev.event_id()));
                     if let Some((ev, _room)) = maybe {
-                        if let SyncRoomMessageEvent::Original(o) = ev {
-                            if let MessageType::VerificationRequest(_c) = &o.content.msgtype {
-                                let flow_id   = o.event_id.to_string();
-                                let from_user = o.sender.to_string();
-
-                                inbox.lock().unwrap().insert(
-                                    flow_id.clone(),
-                                    (o.sender.clone(), owned_device_id!("inroom")),
-                                );
+                        if let SyncRoomMessageEvent::Original(o) = ev
+                            && let MessageType::VerificationRequest(_c) = &o.content.msgtype
+                        {
+                            let flow_id   = o.event_id.to_string();
+                            let from_user = o.sender.to_string();
 
-                                info!("verification_inbox: got in-room request flow_id={} from {}",
-                                      flow_id, from_user);
+                            inbox.lock().unwrap().insert(
+                                flow_id.clone(),
+                                (o.sender.clone(), owned_device_id!("inroom")),
+                            );
 
-                                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
-                                    obs.on_request(flow_id, from_user, String::new());
-                                }));
-                            }
+                            info!("verification_inbox: got in-room request flow_id={} from {}",
+                                  flow_id, from_user);
+
+                            let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                                obs.on_request(flow_id, from_user, String::new());
+                            }));
                         }
                     } else {
                         info!("verification_inbox: in-room stream ended");
@@ -1281,7 +2211,7 @@ impl Client {
                             (ConnectionState::Connected, ConnectionState::Connected) |
                             (ConnectionState::Disconnected, ConnectionState::Disconnected))
                         {
-                            obs.on_connection_change(current.clone());
+                            obs.on_connection_change(current);
                             last_state = current;
                         }
                     }
@@ -1292,7 +2222,7 @@ impl Client {
                             (ConnectionState::Connected, ConnectionState::Connected) |
                             (ConnectionState::Disconnected, ConnectionState::Disconnected))
                         {
-                            obs.on_connection_change(current.clone());
+                            obs.on_connection_change(current);
                             last_state = current;
                         }
                     }
@@ -1324,40 +2254,37 @@ impl Client {
         self.send_observers.lock().unwrap().remove(&id).is_some()
     }
 
-    pub fn send_message(&self, room_id: String, body: String) -> bool {
-        RT.block_on(async {
-            use matrix_sdk::ruma::events::room::message::RoomMessageEventContent as Msg;
-
-            let Ok(room_id) = OwnedRoomId::try_from(room_id) else {
-                return false;
-            };
-            let Some(timeline) = get_timeline_for(&self.inner, &room_id).await else {
-                return false;
-            };
-
-            match timeline.send(Msg::text_plain(body.clone()).into()).await {
-                Ok(handle) => {
-                    if let Some(latest) = timeline.latest_event().await {
-                        if latest.event_id().is_none() {
-                            if let Some(txn) = latest.transaction_id() {
-                                self.send_handles_by_txn
-                                    .lock()
-                                    .unwrap()
-                                    .insert(txn.to_string(), handle.clone());
-                            }
-                        }
-                    }
-                    true
-                }
-                Err(_) => false,
+    pub fn send_message(
+        &self,
+        room_id: String,
+        body: String,
+        options: Option<TextOptions>,
+    ) -> Result<(), FfiError> {
+        with_timeline_async!(self, room_id, |timeline: Arc<Timeline>, _rid| async move {
+            let content = build_text_content(body, options).with_relation(None);
+            let handle = timeline.send(content.into()).await?;
+            if let Some(latest) = timeline.latest_event().await
+                && latest.event_id().is_none()
+                && let Some(txn) = latest.transaction_id()
+            {
+                self.send_handles_by_txn
+                    .lock()
+                    .unwrap()
+                    .insert(txn.to_string(), handle);
             }
+            Ok(())
         })
     }
 
     pub fn shutdown(&self) {
         if let Some(svc) = self.sync_service.lock().unwrap().as_ref().cloned() {
-            let _ = RT.block_on(async { svc.stop().await });
+            RT.block_on(async { svc.stop().await });
         }
+        RT.block_on(flush_search_index(
+            &self.inner,
+            &self.search_index,
+            self.store_encrypted,
+        ));
         for (_, h) in self.timeline_subs.lock().unwrap().drain() {
             h.abort();
         }
@@ -1385,49 +2312,60 @@ impl Client {
         for (_, h) in self.live_location_subs.lock().unwrap().drain() {
             h.abort();
         }
+        for (_, h) in self.live_location_timers.lock().unwrap().drain() {
+            h.abort();
+        }
+        for (_, h) in self.backup_subs.lock().unwrap().drain() {
+            h.abort();
+        }
+        for (_, h) in self.identity_subs.lock().unwrap().drain() {
+            h.abort();
+        }
+        for (_, h) in self.download_subs.lock().unwrap().drain() {
+            h.abort();
+        }
+        // Partial files stay on disk; the next request for the same media resumes them.
+        self.downloads.abort_all();
+        self.stop_media_proxy();
 
-        TIMELINES.lock().unwrap().clear();
+        self.timelines.lock().unwrap().clear();
     }
 
-    pub fn logout(&self) -> bool {
+    /// Log out on the server and wipe the local stores. The local data is
+    /// removed even if the server call fails; its error is returned.
+    pub fn logout(&self) -> Result<(), FfiError> {
         self.shutdown();
-        let _ = RT.block_on(async { self.inner.logout().await });
+        let res = RT.block_on(async { self.inner.logout().await });
         let _ = std::fs::remove_file(session_file(&self.store_dir));
         reset_store_dir(&self.store_dir);
-        true
+        res.map_err(FfiError::from)
     }
 
-    pub fn mark_read(&self, room_id: String) -> bool {
+    pub fn mark_read(&self, room_id: String) -> Result<(), FfiError> {
         with_timeline_async!(self, room_id, |tl: Arc<Timeline>, _rid| async move {
-            tl.mark_as_read(ReceiptType::ReadPrivate).await.is_ok()
+            tl.mark_as_read(ReceiptType::ReadPrivate)
+                .await
+                .map(|_| ())
+                .map_err(FfiError::from)
         })
     }
 
-    pub fn mark_read_at(&self, room_id: String, event_id: String) -> bool {
-        RT.block_on(async {
-            let Ok(room_id) = OwnedRoomId::try_from(room_id) else {
-                return false;
-            };
-            let Ok(eid) = EventId::parse(event_id) else {
-                return false;
-            };
-            let Some(room) = self.inner.get_room(&room_id) else {
-                return false;
-            };
-
+    pub fn mark_read_at(&self, room_id: String, event_id: String) -> Result<(), FfiError> {
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
+            let eid = EventId::parse(event_id)?;
             room.send_single_receipt(
                 ReceiptType::ReadPrivate,
                 ReceiptThread::Unthreaded,
                 eid.to_owned(),
             )
             .await
-            .is_ok()
+            .map_err(FfiError::from)
         })
     }
 
-    pub fn set_mark_unread(&self, room_id: String, unread: bool) -> bool {
+    pub fn set_mark_unread(&self, room_id: String, unread: bool) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            room.set_unread_flag(unread).await.is_ok()
+            room.set_unread_flag(unread).await.map_err(FfiError::from)
         })
     }
 
@@ -1436,9 +2374,7 @@ impl Client {
             let Ok(rid) = ruma::OwnedRoomId::try_from(room_id) else {
                 return None;
             };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return None;
-            };
+            let room = self.inner.get_room(&rid)?;
             Some(room.is_marked_unread())
         })
     }
@@ -1473,14 +2409,13 @@ impl Client {
             self.inner
                 .media()
                 .set_media_retention_policy(policy)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+                .await?;
             // Apply right away.
             self.inner
                 .media()
                 .clean_up_media_cache()
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
@@ -1491,10 +2426,12 @@ impl Client {
                 .media()
                 .clean_up_media_cache()
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
+    /// Thumbnail of an attachment: its own thumbnail when it has one, else a
+    /// server-side thumbnail (plain media) or the full file (encrypted media).
     pub fn thumbnail_to_cache(
         &self,
         att: AttachmentInfo,
@@ -1502,170 +2439,304 @@ impl Client {
         height: u32,
         use_crop: bool,
     ) -> Result<String, FfiError> {
-        use matrix_sdk::media::{MediaFormat, MediaRequestParameters, MediaThumbnailSettings};
-        use ruma::events::room::MediaSource;
-
-        let (source, format, name_key) = if let Some(enc) = att.thumbnail_encrypted.as_ref() {
-            let ef: EncryptedFile = serde_json::from_str(&enc.json)
-                .map_err(|e| FfiError::Msg(format!("thumb enc parse: {e}")))?;
-            (
-                MediaSource::Encrypted(Box::new(ef)),
-                MediaFormat::File,
-                enc.url.clone(),
-            )
-        } else if let Some(mxc) = att.thumbnail_mxc_uri.as_ref() {
-            (
-                MediaSource::Plain(mxc.clone().into()),
-                MediaFormat::File,
-                mxc.clone(),
-            )
-        } else if let Some(enc) = att.encrypted.as_ref() {
-            // fetch full encrypted file as fallback
-            let ef: EncryptedFile = serde_json::from_str(&enc.json)
-                .map_err(|e| FfiError::Msg(format!("file enc parse: {e}")))?;
-            (
-                MediaSource::Encrypted(Box::new(ef)),
-                MediaFormat::File,
-                enc.url.clone(),
-            )
+        let size = ThumbnailSize {
+            width,
+            height,
+            crop: use_crop,
+        };
+        let (mxc_uri, encrypted, thumbnail) = if let Some(enc) = att.thumbnail_encrypted {
+            (enc.url.clone(), Some(enc), None)
+        } else if let Some(mxc) = att.thumbnail_mxc_uri {
+            (mxc, None, None)
+        } else if let Some(enc) = att.encrypted {
+            (enc.url.clone(), Some(enc), None)
         } else {
-            // Plain primary mxc
-            let settings = if use_crop {
-                MediaThumbnailSettings::with_method(
-                    matrix_sdk::ruma::api::client::media::get_content_thumbnail::v3::Method::Crop,
-                    width.into(),
-                    height.into(),
-                )
-            } else {
-                MediaThumbnailSettings::new(width.into(), height.into())
-            };
-            let mxc = att.mxc_uri.clone();
-            (
-                MediaSource::Plain(mxc.clone().into()),
-                MediaFormat::Thumbnail(settings),
-                mxc,
-            )
+            (att.mxc_uri, None, Some(size))
         };
+        self.resolve_media(mxc_uri, encrypted, thumbnail)
+    }
 
-        let req = MediaRequestParameters { source, format };
-
+    /// Fetch any `mxc://` media into the media cache and return the file path.
+    /// `encrypted` decrypts it; `thumbnail` asks for a server-side thumbnail of
+    /// plain media (ignored for encrypted media, falls back to the full file).
+    /// Uses the authenticated media endpoints (MSC3916) and falls back to the
+    /// legacy ones on servers without them.
+    pub fn resolve_media(
+        &self,
+        mxc_uri: String,
+        encrypted: Option<EncFile>,
+        thumbnail: Option<ThumbnailSize>,
+    ) -> Result<String, FfiError> {
+        let encrypted = encrypted
+            .map(|enc| serde_json::from_str::<EncryptedFile>(&enc.json))
+            .transpose()?;
         let dir = cache_dir(&self.store_dir);
-        ensure_dir(&dir);
-        fn sanitize(name: &str) -> String {
-            let mut s = String::with_capacity(name.len());
-            for ch in name.chars() {
-                if ch.is_ascii_alphanumeric() || "-_.".contains(ch) {
-                    s.push(ch);
-                } else {
-                    s.push('_');
-                }
+        let path = RT.block_on(resolve_media_file(
+            &self.inner,
+            &self.downloads,
+            &dir,
+            mxc_uri,
+            encrypted,
+            thumbnail,
+        ))?;
+        Ok(path.to_string_lossy().to_string())
+    }
+
+    /// Serve cached media on `http://127.0.0.1:<port>/…` for image loaders.
+    /// `port` 0 picks a free one. Returns the base URL; see `media_proxy_url`.
+    pub fn start_media_proxy(&self, port: u16) -> Result<String, FfiError> {
+        let mut guard = self.media_proxy.lock().unwrap();
+        if let Some(proxy) = guard.as_ref() {
+            return Ok(proxy.base_url.clone());
+        }
+
+        let listener = RT.block_on(tokio::net::TcpListener::bind(("127.0.0.1", port)))?;
+        let port = listener.local_addr()?.port();
+        // Unguessable path prefix, so other apps on the device cannot use the proxy.
+        let secret = format!("{:032x}", rand::random::<u128>());
+        let ctx = Arc::new(MediaProxyCtx {
+            client: self.inner.clone(),
+            downloads: self.downloads.clone(),
+            dir: cache_dir(&self.store_dir),
+            prefix: format!("/{secret}/mxc/"),
+            keys: Mutex::new(MediaKeys::default()),
+        });
+
+        let accept_ctx = ctx.clone();
+        let task = RT.spawn(async move {
+            let mut backoff = Duration::from_millis(50);
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    // E.g. out of file descriptors: wait for some to be freed.
+                    Err(e) => {
+                        warn!("media proxy accept failed: {e}");
+                        tokio::time::sleep(backoff).await;
+                        backoff = (backoff * 2).min(Duration::from_secs(5));
+                        continue;
+                    }
+                };
+                backoff = Duration::from_millis(50);
+                let ctx = accept_ctx.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_media_request(stream, &ctx).await {
+                        debug!("media proxy request failed: {e}");
+                    }
+                });
             }
-            s.trim_matches('_').to_string()
+        });
+
+        let base_url = format!("http://127.0.0.1:{port}/{secret}");
+        *guard = Some(MediaProxy {
+            base_url: base_url.clone(),
+            ctx,
+            task,
+        });
+        Ok(base_url)
+    }
+
+    pub fn stop_media_proxy(&self) -> bool {
+        match self.media_proxy.lock().unwrap().take() {
+            Some(proxy) => {
+                proxy.task.abort();
+                true
+            }
+            None => false,
         }
-        let key =
-            blake3::hash(format!("{}-{}x{}-{}", name_key, width, height, use_crop).as_bytes())
-                .to_hex();
-        let ext = att
-            .mime
-            .as_deref()
-            .and_then(|m| m.split('/').nth(1))
-            .filter(|e| !e.is_empty())
-            .unwrap_or("jpg");
-        let fname = format!(
-            "thumb_{}_{}x{}{}.{ext}",
-            &key[..16],
-            width,
-            height,
-            if use_crop { "_crop" } else { "_scale" }
-        );
-        let out = dir.join(sanitize(&fname));
-        if let Some(parent) = out.parent() {
-            std::fs::create_dir_all(parent)?;
-        }
-
-        let bytes = RT
-            .block_on(async { self.inner.media().get_media_content(&req, true).await })
-            .or_else(|_e| {
-                // Fallback only when we asked for a server-side thumb of a plain mxc
-                if matches!(req.format, MediaFormat::Thumbnail(_)) {
-                    let req_full = MediaRequestParameters {
-                        source: req.source.clone(),
-                        format: MediaFormat::File,
-                    };
-                    RT.block_on(async {
-                        self.inner.media().get_media_content(&req_full, true).await
-                    })
-                } else {
-                    Err(_e)
-                }
-            })
-            .map_err(|e| FfiError::Msg(format!("thumbnail fetch: {e}")))?;
+    }
 
-        std::fs::write(&out, &bytes)?;
-        Ok(out.to_string_lossy().to_string())
+    /// Proxy URL for the media, or None when the proxy is not running.
+    /// Keys of `encrypted` media are remembered (the most recently used few
+    /// thousand) so the proxy can decrypt it.
+    pub fn media_proxy_url(
+        &self,
+        mxc_uri: String,
+        encrypted: Option<EncFile>,
+        thumbnail: Option<ThumbnailSize>,
+    ) -> Option<String> {
+        let guard = self.media_proxy.lock().unwrap();
+        let proxy = guard.as_ref()?;
+        let mxc = OwnedMxcUri::from(mxc_uri);
+        let (server, media_id) = mxc.parts().ok()?;
+
+        if let Some(enc) = encrypted {
+            let file: EncryptedFile = serde_json::from_str(&enc.json).ok()?;
+            proxy.ctx.keys.lock().unwrap().insert(mxc.to_string(), file);
+        }
+        let query = match thumbnail {
+            Some(t) => format!("?w={}&h={}&crop={}", t.width, t.height, t.crop as u8),
+            None => String::new(),
+        };
+        Some(format!("{}/mxc/{server}/{media_id}{query}", proxy.base_url))
     }
 
-    pub fn react(&self, room_id: String, event_id: String, emoji: String) -> bool {
+    pub fn react(&self, room_id: String, event_id: String, emoji: String) -> Result<(), FfiError> {
         with_timeline_async!(self, room_id, |tl: Arc<Timeline>, _rid| async move {
-            let Ok(eid) = EventId::parse(&event_id) else {
-                return false;
-            };
-            let Some(item) = tl.item_by_event_id(&eid).await else {
-                return false;
-            };
+            let eid = EventId::parse(&event_id)?;
+            let item = tl
+                .item_by_event_id(&eid)
+                .await
+                .ok_or_else(|| FfiError::not_found("event not in timeline"))?;
             let item_id = item.identifier();
-            tl.toggle_reaction(&item_id, &emoji).await.is_ok()
+            tl.toggle_reaction(&item_id, &emoji)
+                .await
+                .map(|_| ())
+                .map_err(FfiError::from)
         })
     }
 
-    pub fn reply(&self, room_id: String, in_reply_to: String, body: String) -> bool {
-        with_timeline_async!(self, room_id, |tl: Arc<Timeline>, _rid| async move {
-            use matrix_sdk::ruma::events::room::message::RoomMessageEventContentWithoutRelation as MsgNoRel;
+    /// React with a custom emoji; the reaction key is the image's mxc URI (MSC2545).
+    pub fn react_with_image(
+        &self,
+        room_id: String,
+        event_id: String,
+        image: PackImage,
+    ) -> Result<(), FfiError> {
+        self.react(room_id, event_id, image.mxc_uri)
+    }
 
-            let Ok(reply_to) = EventId::parse(&in_reply_to) else {
-                return false;
-            };
-            let content = MsgNoRel::text_plain(body);
-            tl.send_reply(content, reply_to.to_owned()).await.is_ok()
+    /// Image packs usable in `room_id`: the account's own pack, packs of rooms
+    /// enabled globally (`im.ponies.emote_rooms`) and the room's own packs.
+    pub fn image_packs(&self, room_id: Option<String>) -> Result<Vec<ImagePack>, FfiError> {
+        use ruma::events::GlobalAccountDataEventType;
+
+        RT.block_on(async {
+            let account = self.inner.account();
+            let mut packs = Vec::new();
+
+            let user_pack = account
+                .fetch_account_data(GlobalAccountDataEventType::from("im.ponies.user_emotes"))
+                .await?;
+            if let Some(raw) = user_pack {
+                let pack: RawImagePack = serde_json::from_str(raw.json().get())?;
+                packs.push(map_image_pack(pack, "user".to_owned(), None, None));
+            }
+
+            let mut seen: HashSet<String> = HashSet::new();
+            if let Some(rid) = room_id {
+                let rid = OwnedRoomId::try_from(rid)?;
+                let room = self
+                    .inner
+                    .get_room(&rid)
+                    .ok_or_else(|| FfiError::not_found("room not found"))?;
+                for pack in room_image_packs(&room).await? {
+                    seen.insert(pack.id.clone());
+                    packs.push(pack);
+                }
+            }
+
+            let enabled = account
+                .fetch_account_data(GlobalAccountDataEventType::from("im.ponies.emote_rooms"))
+                .await?;
+            if let Some(raw) = enabled {
+                let enabled: RawEmoteRooms = serde_json::from_str(raw.json().get())?;
+                for (rid, state_keys) in enabled.rooms {
+                    let Some(room) = OwnedRoomId::try_from(rid)
+                        .ok()
+                        .and_then(|rid| self.inner.get_room(&rid))
+                    else {
+                        continue;
+                    };
+                    for pack in room_image_packs(&room).await? {
+                        let state_key = pack.state_key.clone().unwrap_or_default();
+                        if state_keys.contains_key(&state_key) && seen.insert(pack.id.clone()) {
+                            packs.push(pack);
+                        }
+                    }
+                }
+            }
+            Ok(packs)
+        })
+    }
+
+    /// Send a pack image as an `m.sticker`. `body` defaults to the image's
+    /// description, then its shortcode.
+    pub fn send_sticker(
+        &self,
+        room_id: String,
+        image: PackImage,
+        body: Option<String>,
+    ) -> Result<(), FfiError> {
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
+            let mut info = ImageInfo::new();
+            info.mimetype = image.mime.clone();
+            info.size = image.size_bytes.and_then(UInt::new);
+            info.width = image.width.map(UInt::from);
+            info.height = image.height.map(UInt::from);
+
+            let body = body
+                .or_else(|| image.body.clone())
+                .unwrap_or_else(|| image.shortcode.clone());
+            let content = sticker_content(body, image.mxc_uri, info);
+            room.send_queue().send(content.into()).await?;
+            Ok(())
+        })
+    }
+
+    pub fn reply(
+        &self,
+        room_id: String,
+        in_reply_to: String,
+        body: String,
+        options: Option<TextOptions>,
+    ) -> Result<(), FfiError> {
+        with_timeline_async!(self, room_id, |tl: Arc<Timeline>, _rid| async move {
+            let reply_to = EventId::parse(&in_reply_to)?;
+            let content = build_text_content(body, options);
+            tl.send_reply(content, reply_to.to_owned())
+                .await
+                .map_err(FfiError::from)
         })
     }
 
-    pub fn edit(&self, room_id: String, target_event_id: String, new_body: String) -> bool {
+    pub fn edit(
+        &self,
+        room_id: String,
+        target_event_id: String,
+        new_body: String,
+        options: Option<TextOptions>,
+    ) -> Result<(), FfiError> {
         with_timeline_async!(self, room_id, |tl: Arc<Timeline>, _rid| async move {
             use matrix_sdk::room::edit::EditedContent;
-            use matrix_sdk::ruma::events::room::message::RoomMessageEventContentWithoutRelation as MsgNoRel;
 
-            let Ok(eid) = EventId::parse(&target_event_id) else {
-                return false;
-            };
-            let Some(item) = tl.item_by_event_id(&eid).await else {
-                return false;
-            };
+            let eid = EventId::parse(&target_event_id)?;
+            let item = tl
+                .item_by_event_id(&eid)
+                .await
+                .ok_or_else(|| FfiError::not_found("event not in timeline"))?;
             let item_id = item.identifier();
-            let edited = EditedContent::RoomMessage(MsgNoRel::text_plain(new_body));
+            let edited = EditedContent::RoomMessage(build_text_content(new_body, options));
 
-            tl.edit(&item_id, edited).await.is_ok()
+            tl.edit(&item_id, edited).await.map_err(FfiError::from)
         })
     }
 
-    pub fn paginate_backwards(&self, room_id: String, count: u16) -> bool {
+    /// Returns true when the start of the timeline has been reached.
+    pub fn paginate_backwards(&self, room_id: String, count: u16) -> Result<bool, FfiError> {
         with_timeline_async!(self, room_id, |tl: Arc<Timeline>, _rid| async move {
-            tl.paginate_backwards(count).await.unwrap_or(false)
+            tl.paginate_backwards(count).await.map_err(FfiError::from)
         })
     }
 
-    pub fn paginate_forwards(&self, room_id: String, count: u16) -> bool {
+    /// Returns true when the end of the timeline has been reached.
+    pub fn paginate_forwards(&self, room_id: String, count: u16) -> Result<bool, FfiError> {
         with_timeline_async!(self, room_id, |tl: Arc<Timeline>, _rid| async move {
-            tl.paginate_forwards(count).await.unwrap_or(false)
+            tl.paginate_forwards(count).await.map_err(FfiError::from)
         })
     }
 
-    pub fn redact(&self, room_id: String, event_id: String, reason: Option<String>) -> bool {
+    pub fn redact(
+        &self,
+        room_id: String,
+        event_id: String,
+        reason: Option<String>,
+    ) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            let Ok(eid) = EventId::parse(&event_id) else {
-                return false;
-            };
-            room.redact(&eid, reason.as_deref(), None).await.is_ok()
+            let eid = EventId::parse(&event_id)?;
+            room.redact(&eid, reason.as_deref(), None)
+                .await
+                .map(|_| ())
+                .map_err(FfiError::from)
         })
     }
 
@@ -1754,12 +2825,8 @@ impl Client {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
                 return None;
             };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return None;
-            };
-            let Some(me) = self.inner.user_id() else {
-                return None;
-            };
+            let room = self.inner.get_room(&rid)?;
+            let me = self.inner.user_id()?;
             if let Ok(members) = room.members(RoomMemberships::ACTIVE).await {
                 for m in members {
                     if m.user_id() != me {
@@ -1782,7 +2849,7 @@ impl Client {
             let Ok(uid) = user_id.parse::<OwnedUserId>() else {
                 return false;
             };
-            let Some(tl) = get_timeline_for(&self.inner, &rid).await else {
+            let Some(tl) = get_timeline_for(&self.inner, &self.timelines, &rid).await else {
                 return false;
             };
             let latest_opt = tl.latest_user_read_receipt_timeline_event_id(&uid).await;
@@ -1794,14 +2861,14 @@ impl Client {
             let mut idx_latest = None;
             let mut idx_mine = None;
             for (i, it) in items.iter().enumerate() {
-                if let Some(ev) = it.as_event() {
-                    if let Some(e) = ev.event_id() {
-                        if e == &latest {
-                            idx_latest = Some(i);
-                        }
-                        if e == &eid {
-                            idx_mine = Some(i);
-                        }
+                if let Some(ev) = it.as_event()
+                    && let Some(e) = ev.event_id()
+                {
+                    if e == latest {
+                        idx_latest = Some(i);
+                    }
+                    if e == eid {
+                        idx_mine = Some(i);
                     }
                 }
                 if idx_latest.is_some() && idx_mine.is_some() {
@@ -1848,30 +2915,17 @@ impl Client {
         mime: String,
         bytes: Vec<u8>,
         progress: Option<Box<dyn ProgressObserver>>,
-    ) -> bool {
-        RT.block_on(async {
-            let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return false;
-            };
-            let Some(tl) = get_timeline_for(&self.inner, &rid).await else {
-                return false;
-            };
-
+        options: Option<AttachmentOptions>,
+    ) -> Result<Arc<UploadHandle>, FfiError> {
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
             let parsed: Mime = mime.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM);
-            let fut = tl.send_attachment(
-                AttachmentSource::Data {
-                    filename: filename.clone(),
-                    bytes: bytes.clone(),
-                },
-                parsed,
-                AttachmentConfig::default(),
-            );
-            let res = fut.await;
-            if let Some(p) = progress {
-                let sz = bytes.len() as u64;
-                p.on_progress(sz, Some(sz));
-            }
-            res.is_ok()
+            let file = MediaFile {
+                filename,
+                mime: parsed,
+                data: bytes,
+                info: None,
+            };
+            self.enqueue_media(room, file, progress, options).await
         })
     }
 
@@ -1880,35 +2934,83 @@ impl Client {
         room_id: String,
         path: String,
         mime: String,
-        _filename: Option<String>,
+        filename: Option<String>,
         progress: Option<Box<dyn ProgressObserver>>,
-    ) -> bool {
-        RT.block_on(async {
-            let Ok(rid) = ruma::OwnedRoomId::try_from(room_id) else {
-                return false;
-            };
-            let Some(tl) = get_timeline_for(&self.inner, &rid).await else {
-                return false;
-            };
-
+        options: Option<AttachmentOptions>,
+    ) -> Result<Arc<UploadHandle>, FfiError> {
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
             // Parse MIME (fallback to application/octet-stream)
             let parsed: Mime = mime.parse().unwrap_or(mime::APPLICATION_OCTET_STREAM);
 
-            // Stream directly from disk (AttachmentSource::File reads size + filename)
-            let fut = tl.send_attachment(
-                std::path::PathBuf::from(&path),
-                parsed,
-                AttachmentConfig::default(),
-            );
-            let res = fut.await;
+            let file = std::path::PathBuf::from(&path);
+            let filename = filename.unwrap_or_else(|| {
+                file.file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_else(|| "file".to_owned())
+            });
+            // The send queue keeps its own copy, so the file may go away afterwards.
+            let bytes = tokio::fs::read(&file).await?;
+            let file = MediaFile {
+                filename,
+                mime: parsed,
+                data: bytes,
+                info: None,
+            };
+            self.enqueue_media(room, file, progress, options).await
+        })
+    }
 
-            if let Some(p) = progress {
-                if let Ok(md) = std::fs::metadata(&path) {
-                    let sz = md.len();
-                    p.on_progress(sz, Some(sz));
-                }
+    /// Send a recorded voice note (Ogg/Opus or AAC) as an MSC3245 voice message.
+    /// `waveform` samples are 0.0..=1.0; other clients expect ~30-120 of them.
+    pub fn send_voice_message(
+        &self,
+        room_id: String,
+        path: String,
+        duration_ms: u64,
+        waveform: Vec<f32>,
+        progress: Option<Box<dyn ProgressObserver>>,
+        options: Option<AttachmentOptions>,
+    ) -> Result<Arc<UploadHandle>, FfiError> {
+        use matrix_sdk::attachment::{AttachmentInfo as SdkAttachmentInfo, BaseAudioInfo};
+
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
+            let file = std::path::PathBuf::from(&path);
+            let bytes = tokio::fs::read(&file).await?;
+            let size = bytes.len() as u64;
+            let mime: Mime = match file.extension().and_then(|e| e.to_str()) {
+                Some("m4a" | "mp4" | "aac") => "audio/mp4",
+                Some("mp3") => "audio/mpeg",
+                Some("wav") => "audio/wav",
+                _ => "audio/ogg",
             }
-            res.is_ok()
+            .parse()
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+
+            let filename = file
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "voice-message.ogg".to_owned());
+            // MSC3246 amplitudes run from 0 to 1024.
+            let info = SdkAttachmentInfo::Voice {
+                audio_info: BaseAudioInfo {
+                    duration: Some(Duration::from_millis(duration_ms)),
+                    size: UInt::new(size),
+                },
+                waveform: Some(
+                    waveform
+                        .iter()
+                        .map(|v| (v.clamp(0.0, 1.0) * 1024.0).round() as u16)
+                        .collect(),
+                ),
+            };
+
+            let file = MediaFile {
+                filename,
+                mime,
+                data: bytes,
+                info: Some(info),
+            };
+            self.enqueue_media(room, file, progress, options).await
         })
     }
 
@@ -1982,13 +3084,203 @@ impl Client {
             .wrapping_add(1)
     }
 
-    pub fn recover_with_key(&self, recovery_key: String) -> bool {
+    pub fn recover_with_key(&self, recovery_key: String) -> Result<(), FfiError> {
+        RT.block_on(async {
+            let rec = self.inner.encryption().recovery();
+            rec.recover(&recovery_key).await?;
+            Ok(())
+        })
+    }
+
+    /// Write our room keys to `path` as a passphrase-encrypted
+    /// `MEGOLM SESSION DATA` file, readable by Element and other clients.
+    /// `room_ids` limits the export to those rooms. Returns the number of keys.
+    pub fn export_room_keys(
+        &self,
+        path: String,
+        passphrase: String,
+        room_ids: Option<Vec<String>>,
+    ) -> Result<u64, FfiError> {
+        let rooms: Option<HashSet<OwnedRoomId>> = room_ids
+            .map(|ids| ids.into_iter().map(OwnedRoomId::try_from).collect())
+            .transpose()?;
+        let mut count = 0u64;
+        RT.block_on(async {
+            self.inner
+                .encryption()
+                .export_room_keys(PathBuf::from(&path), &passphrase, |session| {
+                    let keep = rooms.as_ref().is_none_or(|r| r.contains(session.room_id()));
+                    count += keep as u64;
+                    keep
+                })
+                .await
+        })?;
+        Ok(count)
+    }
+
+    /// Import a `MEGOLM SESSION DATA` file. Open timelines of the affected
+    /// rooms retry decrypting their events with the imported sessions.
+    pub fn import_room_keys(
+        &self,
+        path: String,
+        passphrase: String,
+    ) -> Result<KeyImportResult, FfiError> {
+        use matrix_sdk::encryption::RoomKeyImportError;
+
+        RT.block_on(async {
+            let result = self
+                .inner
+                .encryption()
+                .import_room_keys(PathBuf::from(&path), &passphrase)
+                .await
+                .map_err(|e| match e {
+                    RoomKeyImportError::Io(e) => FfiError::from(e),
+                    // Usually a wrong passphrase.
+                    other => FfiError::Crypto {
+                        message: other.to_string(),
+                    },
+                })?;
+
+            for (room_id, sessions) in &result.keys {
+                let tl = self.timelines.lock().unwrap().get(room_id).cloned();
+                if let Some(tl) = tl {
+                    tl.retry_decryption(sessions.values().flatten().cloned())
+                        .await;
+                }
+            }
+
+            Ok(KeyImportResult {
+                imported: result.imported_count as u64,
+                total: result.total_count as u64,
+                room_ids: result.keys.keys().map(|r| r.to_string()).collect(),
+            })
+        })
+    }
+
+    /// Set up recovery: create a key backup and store our secrets on the
+    /// server, encrypted by a new recovery key (derived from `passphrase` when
+    /// given). Returns the recovery key to show to the user.
+    pub fn enable_recovery(&self, passphrase: Option<String>) -> Result<String, FfiError> {
+        RT.block_on(async {
+            let rec = self.inner.encryption().recovery();
+            let enable = rec.enable();
+            let key = match passphrase.as_deref() {
+                Some(p) => enable.with_passphrase(p).await?,
+                None => enable.await?,
+            };
+            Ok(key)
+        })
+    }
+
+    /// Replace the recovery key, e.g. when the old one was lost. Needs the
+    /// secrets on this device (recovery state `Enabled`).
+    pub fn reset_recovery_key(&self, passphrase: Option<String>) -> Result<String, FfiError> {
         RT.block_on(async {
             let rec = self.inner.encryption().recovery();
-            rec.recover(&recovery_key).await.is_ok()
+            let reset = rec.reset_key();
+            let key = match passphrase.as_deref() {
+                Some(p) => reset.with_passphrase(p).await?,
+                None => reset.await?,
+            };
+            Ok(key)
+        })
+    }
+
+    /// Delete the key backup and the server-side secrets.
+    pub fn disable_recovery(&self) -> Result<(), FfiError> {
+        RT.block_on(async {
+            self.inner.encryption().recovery().disable().await?;
+            Ok(())
+        })
+    }
+
+    pub fn recovery_state(&self) -> RecoveryState {
+        map_recovery_state(self.inner.encryption().recovery().state())
+    }
+
+    pub fn backup_state(&self) -> BackupState {
+        map_backup_state(self.inner.encryption().backups().state())
+    }
+
+    /// Whether any key backup exists on the server, even one this device
+    /// doesn't use.
+    pub fn backup_exists_on_server(&self) -> Result<bool, FfiError> {
+        RT.block_on(async {
+            self.inner
+                .encryption()
+                .backups()
+                .exists_on_server()
+                .await
+                .map_err(FfiError::from)
+        })
+    }
+
+    /// Current recovery and backup states, then every change, plus room key
+    /// upload progress whenever keys are uploaded to the backup.
+    pub fn observe_backup(&self, observer: Box<dyn BackupObserver>) -> u64 {
+        use futures_util::future::{BoxFuture, Fuse, FutureExt};
+        use matrix_sdk::encryption::backups::{UploadState, futures::SteadyStateError};
+        use std::future::IntoFuture;
+
+        let client = self.inner.clone();
+        let obs: Arc<dyn BackupObserver> = Arc::from(observer);
+
+        sub_manager!(self, backup_subs, async move {
+            let encryption = client.encryption();
+            let recovery = encryption.recovery();
+            let backups = encryption.backups();
+
+            let recovery_states = recovery.state_stream();
+            let backup_states = backups.state_stream();
+            // Every upload reports here, whoever started it.
+            let progress = backups.wait_for_steady_state().subscribe_to_progress();
+            tokio::pin!(recovery_states, backup_states, progress);
+            // Upload what is pending once the backup gets enabled.
+            let mut upload: Fuse<BoxFuture<'_, Result<(), SteadyStateError>>> = Fuse::terminated();
+
+            loop {
+                tokio::select! {
+                    state = recovery_states.next() => {
+                        let Some(state) = state else { break };
+                        let state = map_recovery_state(state);
+                        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                            obs.on_recovery_state(state)
+                        }));
+                    }
+                    state = backup_states.next() => {
+                        let Some(state) = state else { break };
+                        // Lagged: the next update carries the current state.
+                        let Ok(state) = state else { continue };
+                        let mapped = map_backup_state(state);
+                        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                            obs.on_backup_state(mapped)
+                        }));
+                        if matches!(mapped, BackupState::Enabled) {
+                            upload = backups.wait_for_steady_state().into_future().fuse();
+                        }
+                    }
+                    _ = &mut upload => {}
+                    update = progress.next() => match update {
+                        Some(Ok(UploadState::Uploading(counts))) => {
+                            let (done, total) = (counts.backed_up as u64, counts.total as u64);
+                            let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                                obs.on_upload_progress(done, total)
+                            }));
+                        }
+                        Some(_) => {}
+                        None => break,
+                    },
+                }
+            }
         })
     }
 
+    pub fn unobserve_backup(&self, sub_id: u64) -> bool {
+        unsub!(self, backup_subs, sub_id)
+    }
+
+    /// Our sessions as listed by the server, with the crypto state of each.
+    /// Falls back to the crypto store alone when `/devices` fails.
     pub fn list_my_devices(&self) -> Vec<DeviceSummary> {
         RT.block_on(async {
             let Some(me) = self.inner.user_id() else {
@@ -1999,62 +3291,291 @@ impl Client {
                 return vec![];
             };
 
-            user_devs
-                .devices()
-                .map(|dev| {
-                    use matrix_sdk_crypto::LocalTrust;
+            let mut server: Option<HashMap<String, ServerDevice>> =
+                match fetch_server_devices(&self.inner).await {
+                    Ok(devices) => Some(
+                        devices
+                            .into_iter()
+                            .map(|d| (d.device_id.clone(), d))
+                            .collect(),
+                    ),
+                    Err(e) => {
+                        warn!("list_my_devices: /devices failed: {e}");
+                        None
+                    }
+                };
+            let is_own = |id: &str| {
+                self.inner
+                    .device_id()
+                    .map(|my| my.as_str() == id)
+                    .unwrap_or(false)
+            };
 
+            let mut out: Vec<DeviceSummary> = user_devs
+                .devices()
+                .filter_map(|dev| {
+                    let meta = match server.as_mut() {
+                        // Signed out; the crypto store has not caught up yet.
+                        Some(server) => Some(server.remove(dev.device_id().as_str())?),
+                        None => None,
+                    };
                     let ed25519 = dev.ed25519_key().map(|k| k.to_base64()).unwrap_or_default();
-                    let is_own = self
-                        .inner
-                        .device_id()
-                        .map(|my| my == dev.device_id())
-                        .unwrap_or(false);
+                    let display_name = meta
+                        .as_ref()
+                        .and_then(|m| m.display_name.clone())
+                        .or_else(|| dev.display_name().map(str::to_owned))
+                        .unwrap_or_default();
 
-                    DeviceSummary {
+                    Some(DeviceSummary {
                         device_id: dev.device_id().to_string(),
-                        display_name: dev.display_name().unwrap_or_default().to_string(),
+                        display_name,
                         ed25519,
-                        is_own,
+                        is_own: is_own(dev.device_id().as_str()),
                         verified: dev.is_verified(),
-                    }
+                        last_seen_ip: meta.as_ref().and_then(|m| m.last_seen_ip.clone()),
+                        last_seen_ts_ms: meta.as_ref().and_then(|m| m.last_seen_ts),
+                        user_agent: meta.and_then(|m| m.last_seen_user_agent),
+                    })
                 })
-                .collect()
+                .collect();
+
+            // Sessions that never uploaded keys, e.g. bots or old clients.
+            out.extend(
+                server
+                    .into_iter()
+                    .flat_map(|m| m.into_values())
+                    .map(|d| DeviceSummary {
+                        is_own: is_own(&d.device_id),
+                        device_id: d.device_id,
+                        display_name: d.display_name.unwrap_or_default(),
+                        ed25519: String::new(),
+                        verified: false,
+                        last_seen_ip: d.last_seen_ip,
+                        last_seen_ts_ms: d.last_seen_ts,
+                        user_agent: d.last_seen_user_agent,
+                    }),
+            );
+            out.sort_by(|a, b| b.last_seen_ts_ms.cmp(&a.last_seen_ts_ms));
+            out
         })
     }
 
-    pub fn start_self_sas(
+    pub fn rename_device(&self, device_id: String, display_name: String) -> Result<(), FfiError> {
+        let device_id = OwnedDeviceId::from(device_id);
+        RT.block_on(async {
+            self.inner.rename_device(&device_id, &display_name).await?;
+            Ok(())
+        })
+    }
+
+    /// Sign out other sessions. Servers usually ask to re-enter the account
+    /// password; without one this fails with `InvalidInput` so the app can
+    /// prompt and call again.
+    pub fn delete_devices(
         &self,
-        device_id: String,
-        observer: Box<dyn VerificationObserver>,
-    ) -> String {
-        let obs: Arc<dyn VerificationObserver> = Arc::from(observer);
+        device_ids: Vec<String>,
+        password: Option<String>,
+    ) -> Result<(), FfiError> {
+        use ruma::api::client::uiaa::AuthType;
+
+        let ids: Vec<OwnedDeviceId> = device_ids.into_iter().map(OwnedDeviceId::from).collect();
         RT.block_on(async {
-            info!("start_self_sas: device_id={}", device_id);
+            let uiaa = match self.inner.delete_devices(&ids, None).await {
+                Ok(_) => return Ok(()),
+                Err(e) => match e.as_uiaa_response() {
+                    Some(uiaa) => uiaa.clone(),
+                    None => return Err(e.into()),
+                },
+            };
+            // Only a password stage can be completed from here.
+            let by_password = uiaa
+                .flows
+                .iter()
+                .any(|flow| flow.stages == [AuthType::Password]);
+            if !by_password {
+                return Err(FfiError::Forbidden {
+                    message: "the server doesn't accept a password to sign out sessions".into(),
+                });
+            }
+            let (Some(password), Some(me)) = (password, self.inner.user_id()) else {
+                return Err(FfiError::invalid_input("password required"));
+            };
+            let auth = password_auth(me, password, uiaa.session);
+            self.inner.delete_devices(&ids, Some(auth)).await?;
+            Ok(())
+        })
+    }
 
-            let Some(me) = self.inner.user_id() else {
-                warn!("start_self_sas: no user");
-                obs.on_error("".into(), "No user".into());
-                return "".into();
+    /// Cross-signing identity of `user_id` and our trust in it, or `None`
+    /// when the user has not set up cross-signing.
+    pub fn user_identity(&self, user_id: String) -> Result<Option<UserIdentityInfo>, FfiError> {
+        let uid = OwnedUserId::try_from(user_id)?;
+        RT.block_on(async {
+            let identity = self.fetch_user_identity(&uid).await?;
+            let Some(identity) = identity else {
+                return Ok(None);
             };
+            let changed = pin_violation_in_rooms(&self.inner, &uid).await;
+            Ok(Some(map_user_identity(&identity, changed)))
+        })
+    }
 
-            // Ensure crypto is fully initialised
-            self.inner
-                .encryption()
-                .wait_for_e2ee_initialization_tasks()
-                .await;
+    /// Accept a changed identity of `user_id` as the new pinned one, clearing
+    /// the `IdentityChanged` warning.
+    pub fn pin_user_identity(&self, user_id: String) -> Result<(), FfiError> {
+        let uid = OwnedUserId::try_from(user_id)?;
+        RT.block_on(async {
+            let identity = self
+                .fetch_user_identity(&uid)
+                .await?
+                .ok_or_else(|| FfiError::not_found("user has no cross-signing identity"))?;
+            identity.pin().await?;
+            Ok(())
+        })
+    }
 
-            let Ok(devices) = self.inner.encryption().get_user_devices(me).await else {
-                warn!("start_self_sas: Devices unavailable");
-                obs.on_error("".into(), "Devices unavailable".into());
-                return "".into();
-            };
+    /// Stop flagging `user_id` for having changed a previously verified
+    /// identity, without verifying the new one.
+    pub fn withdraw_verification(&self, user_id: String) -> Result<(), FfiError> {
+        let uid = OwnedUserId::try_from(user_id)?;
+        RT.block_on(async {
+            let identity = self
+                .fetch_user_identity(&uid)
+                .await?
+                .ok_or_else(|| FfiError::not_found("user has no cross-signing identity"))?;
+            identity.withdraw_verification().await?;
+            Ok(())
+        })
+    }
 
-            let mut target = None;
-            for d in devices.devices() {
-                if d.device_id().as_str() == device_id {
-                    target = Some(d);
-                    break;
+    /// Replace our cross-signing keys. Other users see an identity reset
+    /// and all our devices become unverified. The server asks for approval
+    /// either by `password` (UIA) or on the OAuth account page, opened via
+    /// `opener`; this blocks until approved, `cancel_browser_auth` is called
+    /// or it times out.
+    pub fn reset_cross_signing(
+        &self,
+        password: Option<String>,
+        opener: Option<Box<dyn UrlOpener>>,
+    ) -> Result<(), FfiError> {
+        use matrix_sdk::encryption::CrossSigningResetAuthType;
+
+        RT.block_on(async {
+            let Some(handle) = self.inner.encryption().reset_cross_signing().await? else {
+                return Ok(());
+            };
+            match handle.auth_type() {
+                CrossSigningResetAuthType::Uiaa(uiaa) => {
+                    let (Some(password), Some(me)) = (password, self.inner.user_id()) else {
+                        handle.cancel().await;
+                        return Err(FfiError::invalid_input("password required"));
+                    };
+                    let auth = password_auth(me, password, uiaa.session.clone());
+                    handle.auth(Some(auth)).await?;
+                }
+                CrossSigningResetAuthType::OAuth(info) => {
+                    let Some(opener) = opener else {
+                        handle.cancel().await;
+                        return Err(FfiError::invalid_input("approval url opener required"));
+                    };
+                    let cancel = self.arm_browser_auth_cancel();
+                    if !opener.open(info.approval_url.to_string()) {
+                        handle.cancel().await;
+                        return Err(FfiError::Msg("could not open the approval URL".into()));
+                    }
+                    // Polls until the reset is approved on the account page.
+                    let approved =
+                        await_browser(handle.auth(None), cancel, "cross-signing reset").await;
+                    match approved {
+                        Ok(result) => result?,
+                        Err(e) => {
+                            handle.cancel().await;
+                            return Err(e);
+                        }
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Identity trust changes of the members of `room_id`, e.g. to show an
+    /// "identity reset" warning in the composer.
+    pub fn observe_identity_changes(
+        &self,
+        room_id: String,
+        observer: Box<dyn IdentityObserver>,
+    ) -> u64 {
+        let client = self.inner.clone();
+        let Ok(rid) = OwnedRoomId::try_from(room_id) else {
+            return 0;
+        };
+        let obs: Arc<dyn IdentityObserver> = Arc::from(observer);
+
+        sub_manager!(self, identity_subs, async move {
+            let Some(room) = client.get_room(&rid) else {
+                return;
+            };
+            let stream = match room.subscribe_to_identity_status_changes().await {
+                Ok(s) => s,
+                Err(e) => {
+                    warn!("observe_identity_changes: {e}");
+                    return;
+                }
+            };
+
+            use futures_util::{StreamExt, pin_mut};
+            pin_mut!(stream);
+
+            while let Some(batch) = stream.next().await {
+                let changes = batch
+                    .into_iter()
+                    .map(|c| IdentityChange {
+                        user_id: c.user_id.to_string(),
+                        trust: map_identity_state(c.changed_to),
+                    })
+                    .collect();
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| obs.on_changes(changes)));
+            }
+        })
+    }
+
+    pub fn unobserve_identity_changes(&self, sub_id: u64) -> bool {
+        unsub!(self, identity_subs, sub_id)
+    }
+
+    pub fn start_self_sas(
+        &self,
+        device_id: String,
+        observer: Box<dyn VerificationObserver>,
+    ) -> String {
+        let obs: Arc<dyn VerificationObserver> = Arc::from(observer);
+        RT.block_on(async {
+            info!("start_self_sas: device_id={}", device_id);
+
+            let Some(me) = self.inner.user_id() else {
+                warn!("start_self_sas: no user");
+                obs.on_error("".into(), "No user".into());
+                return "".into();
+            };
+
+            // Ensure crypto is fully initialised
+            self.inner
+                .encryption()
+                .wait_for_e2ee_initialization_tasks()
+                .await;
+
+            let Ok(devices) = self.inner.encryption().get_user_devices(me).await else {
+                warn!("start_self_sas: Devices unavailable");
+                obs.on_error("".into(), "Devices unavailable".into());
+                return "".into();
+            };
+
+            let mut target = None;
+            for d in devices.devices() {
+                if d.device_id().as_str() == device_id {
+                    target = Some(d);
+                    break;
                 }
             }
             let Some(dev) = target else {
@@ -2138,7 +3659,7 @@ impl Client {
         flow_id: String,
         other_user_id: Option<String>,
         observer: Box<dyn VerificationObserver>,
-    ) -> bool {
+    ) -> Result<(), FfiError> {
         let obs: Arc<dyn VerificationObserver> = Arc::from(observer);
         RT.block_on(async {
             info!(
@@ -2146,27 +3667,31 @@ impl Client {
                 flow_id, other_user_id
             );
 
-            let user_opt = if let Some(uid) = other_user_id {
-                uid.parse::<OwnedUserId>().ok()
-            } else {
-                self.inbox
+            let user = match other_user_id {
+                Some(uid) => uid.parse::<OwnedUserId>()?,
+                None => self
+                    .inbox
                     .lock()
                     .unwrap()
                     .get(&flow_id)
                     .map(|p| p.0.clone())
-            };
-            let Some(user) = user_opt else {
-                warn!(
-                    "accept_verification: could not resolve user for flow_id={}",
-                    flow_id
-                );
-                return false;
+                    .ok_or_else(|| FfiError::not_found("verification request not found"))?,
             };
             info!("accept_verification: resolved user={}", user);
 
-            if let Some(f) = self.verifs.lock().unwrap().get(&flow_id) {
-                info!("accept_verification: found existing SAS in verifs, calling accept()");
-                return f.sas.accept().await.is_ok();
+            let kind = self
+                .verifs
+                .lock()
+                .unwrap()
+                .get(&flow_id)
+                .map(|f| f.kind.clone());
+            if let Some(kind) = kind {
+                info!("accept_verification: found existing flow in verifs, calling accept()");
+                return match kind {
+                    VerifKind::Sas(sas) => Ok(sas.accept().await?),
+                    // Nothing to accept; the QR flow is already running.
+                    VerifKind::Qr(_) => Ok(()),
+                };
             }
 
             if let Some(req) = self
@@ -2176,58 +3701,66 @@ impl Client {
                 .await
             {
                 info!("accept_verification: found VerificationRequest, accepting and starting sas");
-                if req.accept().await.is_err() {
-                    warn!("accept_verification: req.accept() failed");
-                    return false;
-                }
+                req.accept().await?;
                 self.wait_and_start_sas(flow_id.clone(), req, obs.clone());
-                return true;
+                return Ok(());
             }
 
-            if let Some(verification) = self
+            let Some(verification) = self
                 .inner
                 .encryption()
                 .get_verification(&user, &flow_id)
                 .await
-            {
-                info!("accept_verification: found Verification, trying sas().accept()");
-                if let Some(sas) = verification.clone().sas() {
-                    return sas.accept().await.is_ok();
-                }
-                for _ in 0..5 {
-                    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
-                    if let Some(sas) = verification.clone().sas() {
-                        info!("accept_verification: sas became available, accepting");
-                        return sas.accept().await.is_ok();
-                    }
-                }
-                warn!("accept_verification: sas() never became available");
-            } else {
+            else {
                 warn!(
                     "accept_verification: no Verification found for user={} flow_id={}",
                     user, flow_id
                 );
+                return Err(FfiError::not_found("verification not found"));
+            };
+            info!("accept_verification: found Verification, trying sas().accept()");
+            for attempt in 0..6 {
+                if attempt > 0 {
+                    tokio::time::sleep(std::time::Duration::from_millis(150)).await;
+                }
+                if let Some(sas) = verification.clone().sas() {
+                    return Ok(sas.accept().await?);
+                }
             }
-
-            false
+            warn!("accept_verification: sas() never became available");
+            Err(FfiError::Msg("verification has no SAS to accept".into()))
         })
     }
 
-    pub fn confirm_verification(&self, flow_id: String) -> bool {
+    pub fn confirm_verification(&self, flow_id: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            if let Some(f) = self.verifs.lock().unwrap().get(&flow_id) {
-                f.sas.confirm().await.is_ok()
-            } else {
-                false
+            let kind = self
+                .verifs
+                .lock()
+                .unwrap()
+                .get(&flow_id)
+                .map(|f| f.kind.clone());
+            match kind {
+                Some(VerifKind::Sas(sas)) => Ok(sas.confirm().await?),
+                Some(VerifKind::Qr(qr)) => Ok(qr.confirm().await?),
+                None => Err(FfiError::not_found("verification not found")),
             }
         })
     }
 
-    pub fn cancel_verification(&self, flow_id: String) -> bool {
+    pub fn cancel_verification(&self, flow_id: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            // Cancel an active SAS if we have it cached
-            if let Some(f) = self.verifs.lock().unwrap().get(&flow_id) {
-                return f.sas.cancel().await.is_ok();
+            // Cancel an active flow if we have it cached
+            let kind = self
+                .verifs
+                .lock()
+                .unwrap()
+                .get(&flow_id)
+                .map(|f| f.kind.clone());
+            match kind {
+                Some(VerifKind::Sas(sas)) => return Ok(sas.cancel().await?),
+                Some(VerifKind::Qr(qr)) => return Ok(qr.cancel().await?),
+                None => {}
             }
             // Else try to resolve via crypto and cancel there as a best‑effort
             let user = match self
@@ -2238,22 +3771,22 @@ impl Client {
                 .map(|p| p.0.clone())
             {
                 Some(u) => u,
-                None => match self.inner.user_id() {
-                    Some(me) => me.to_owned(),
-                    None => return false,
-                },
+                None => self
+                    .inner
+                    .user_id()
+                    .ok_or_else(|| FfiError::not_found("verification not found"))?
+                    .to_owned(),
             };
-            if let Some(verification) = self
+            match self
                 .inner
                 .encryption()
                 .get_verification(&user, &flow_id)
                 .await
             {
-                if let Some(sas) = verification.sas() {
-                    return sas.cancel().await.is_ok();
-                }
+                Some(Verification::SasV1(sas)) => Ok(sas.cancel().await?),
+                Some(Verification::QrV1(qr)) => Ok(qr.cancel().await?),
+                _ => Err(FfiError::not_found("verification not found")),
             }
-            false
         })
     }
 
@@ -2261,17 +3794,17 @@ impl Client {
         &self,
         flow_id: String,
         other_user_id: Option<String>,
-    ) -> bool {
+    ) -> Result<(), FfiError> {
         RT.block_on(async {
-            let user = if let Some(uid) = other_user_id {
-                match uid.parse::<OwnedUserId>() {
-                    Ok(u) => u,
-                    Err(_) => return false,
-                }
-            } else if let Some((u, _)) = self.inbox.lock().unwrap().get(&flow_id).cloned() {
-                u
-            } else {
-                return false;
+            let user = match other_user_id {
+                Some(uid) => uid.parse::<OwnedUserId>()?,
+                None => self
+                    .inbox
+                    .lock()
+                    .unwrap()
+                    .get(&flow_id)
+                    .map(|p| p.0.clone())
+                    .ok_or_else(|| FfiError::not_found("verification request not found"))?,
             };
 
             if let Some(req) = self
@@ -2280,19 +3813,125 @@ impl Client {
                 .get_verification_request(&user, &flow_id)
                 .await
             {
-                return req.cancel().await.is_ok();
+                return Ok(req.cancel().await?);
             }
-            if let Some(verification) = self
+            match self
                 .inner
                 .encryption()
                 .get_verification(&user, &flow_id)
                 .await
             {
-                if let Some(sas) = verification.sas() {
-                    return sas.cancel().await.is_ok();
-                }
+                Some(Verification::SasV1(sas)) => Ok(sas.cancel().await?),
+                Some(Verification::QrV1(qr)) => Ok(qr.cancel().await?),
+                _ => Err(FfiError::not_found("verification request not found")),
             }
-            false
+        })
+    }
+
+    /// Switch the request `flow_id` to QR and return the code to display.
+    /// Accepts an incoming request first. Progress arrives on `observer`;
+    /// call `confirm_verification` after `QrScanned` once the other device
+    /// shows success.
+    pub fn show_verification_qr(
+        &self,
+        flow_id: String,
+        other_user_id: Option<String>,
+        observer: Box<dyn VerificationObserver>,
+    ) -> Result<Vec<u8>, FfiError> {
+        let obs: Arc<dyn VerificationObserver> = Arc::from(observer);
+        RT.block_on(async {
+            let req = self.qr_request(&flow_id, other_user_id).await?;
+            let qr = req
+                .generate_qr_code()
+                .await?
+                .ok_or_else(|| FfiError::invalid_input("other device cannot scan QR codes"))?;
+            let bytes = qr.to_bytes().map_err(|e| FfiError::Crypto {
+                message: e.to_string(),
+            })?;
+            info!("show_verification_qr: flow_id={flow_id}");
+            let h = RT.spawn(attach_qr_stream(self.verifs.clone(), flow_id, qr, obs));
+            self.guards.lock().unwrap().push(h);
+            Ok(bytes)
+        })
+    }
+
+    /// Continue the request `flow_id` by scanning the other device's QR code.
+    /// Accepts an incoming request first. Reports `QrReciprocated`, then
+    /// `Done` once the other side confirms.
+    pub fn scan_verification_qr(
+        &self,
+        flow_id: String,
+        other_user_id: Option<String>,
+        data: Vec<u8>,
+        observer: Box<dyn VerificationObserver>,
+    ) -> Result<(), FfiError> {
+        let obs: Arc<dyn VerificationObserver> = Arc::from(observer);
+        RT.block_on(async {
+            let data = QrVerificationData::from_bytes(data)
+                .map_err(|e| FfiError::invalid_input(format!("bad verification QR code: {e}")))?;
+            let req = self.qr_request(&flow_id, other_user_id).await?;
+            let qr = req
+                .scan_qr_code(data)
+                .await?
+                .ok_or_else(|| FfiError::invalid_input("verification request not ready"))?;
+            info!("scan_verification_qr: flow_id={flow_id}");
+            // Already reciprocated; the stream only reports what follows.
+            obs.on_phase(flow_id.clone(), SasPhase::QrReciprocated);
+            let h = RT.spawn(attach_qr_stream(self.verifs.clone(), flow_id, qr, obs));
+            self.guards.lock().unwrap().push(h);
+            Ok(())
+        })
+    }
+
+    /// Sign in this (not yet logged in) client by scanning the QR code shown
+    /// by an existing session (MSC4108). The homeserver must use OAuth 2.0
+    /// and match the one this client was built for. Secrets are imported
+    /// from the other device, so no separate verification is needed.
+    pub fn login_with_qr(
+        &self,
+        data: Vec<u8>,
+        client_name: Option<String>,
+        observer: Option<Box<dyn QrLoginObserver>>,
+    ) -> Result<(), FfiError> {
+        use matrix_sdk::authentication::oauth::qrcode::{LoginProgress, QrCodeData};
+
+        let data = QrCodeData::from_bytes(&data)
+            .map_err(|e| FfiError::invalid_input(format!("bad login QR code: {e}")))?;
+        let obs: Option<Arc<dyn QrLoginObserver>> = observer.map(Arc::from);
+
+        RT.block_on(async {
+            let registration = oauth_registration_data(None, client_name)?;
+            let oauth = self.inner.oauth();
+            let login = oauth.login_with_qr_code(&data, Some(&registration));
+
+            let mut progress = login.subscribe_to_progress();
+            let forward = obs.map(|obs| {
+                RT.spawn(async move {
+                    while let Some(p) = progress.next().await {
+                        let p = match p {
+                            LoginProgress::Starting => QrLoginProgress::Starting,
+                            LoginProgress::EstablishingSecureChannel { check_code } => {
+                                QrLoginProgress::EstablishingSecureChannel {
+                                    check_code: check_code.to_digit(),
+                                }
+                            }
+                            LoginProgress::WaitingForToken { user_code } => {
+                                QrLoginProgress::WaitingForToken { user_code }
+                            }
+                            LoginProgress::Done => QrLoginProgress::Done,
+                        };
+                        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| obs.on_progress(p)));
+                    }
+                })
+            });
+
+            let res = login.await;
+            if let Some(h) = forward {
+                h.abort();
+            }
+            res?;
+
+            persist_session(&self.inner, &self.store_dir, self.store_encrypted).await
         })
     }
 
@@ -2300,15 +3939,21 @@ impl Client {
         self.inner.session_meta().is_some()
     }
 
-    pub fn enqueue_text(&self, room_id: String, body: String, txn_id: Option<String>) -> String {
+    pub fn enqueue_text(
+        &self,
+        room_id: String,
+        body: String,
+        txn_id: Option<String>,
+        options: Option<TextOptions>,
+    ) -> String {
         let client_txn = txn_id.unwrap_or_else(|| format!("mages-{}", now_ms()));
 
         let tx = self.send_tx.clone();
         let client = self.inner.clone();
+        let timelines = self.timelines.clone();
         let mut handles = self.send_handles_by_txn.lock().unwrap().clone();
         let txn_id = client_txn.clone();
         RT.spawn(async move {
-            use matrix_sdk::ruma::events::room::message::RoomMessageEventContent as Msg;
             // Emit "Sending" (best-effort continuity with previous observer).
             let _ = tx.send(SendUpdate {
                 room_id: room_id.clone(),
@@ -2322,7 +3967,7 @@ impl Client {
             let Ok(rid) = OwnedRoomId::try_from(room_id.clone()) else {
                 let _ = tx.send(SendUpdate {
                     room_id,
-                    txn_id: txn_id,
+                    txn_id,
                     attempts: 0,
                     state: SendState::Failed,
                     event_id: None,
@@ -2331,20 +3976,20 @@ impl Client {
                 return;
             };
 
-            if let Some(timeline) = get_timeline_for(&client, &rid).await {
-                match timeline.send(Msg::text_plain(body.clone()).into()).await {
+            if let Some(timeline) = get_timeline_for(&client, &timelines, &rid).await {
+                let content = build_text_content(body, options).with_relation(None);
+                match timeline.send(content.into()).await {
                     Ok(handle) => {
                         // Map protocol txn id (if we can see it now) -> handle, for future precise retry.
-                        if let Some(latest) = timeline.latest_event().await {
-                            if latest.event_id().is_none() {
-                                if let Some(proto_txn) = latest.transaction_id() {
-                                    handles.insert(proto_txn.to_string(), handle);
-                                }
-                            }
+                        if let Some(latest) = timeline.latest_event().await
+                            && latest.event_id().is_none()
+                            && let Some(proto_txn) = latest.transaction_id()
+                        {
+                            handles.insert(proto_txn.to_string(), handle);
                         }
                         let _ = tx.send(SendUpdate {
                             room_id: rid.to_string(),
-                            txn_id: txn_id,
+                            txn_id,
                             attempts: 0,
                             state: SendState::Sent,
                             event_id: None,
@@ -2354,7 +3999,7 @@ impl Client {
                     Err(e) => {
                         let _ = tx.send(SendUpdate {
                             room_id: rid.to_string(),
-                            txn_id: txn_id,
+                            txn_id,
                             attempts: 0,
                             state: SendState::Failed,
                             event_id: None,
@@ -2365,7 +4010,7 @@ impl Client {
             } else {
                 let _ = tx.send(SendUpdate {
                     room_id: rid.to_string(),
-                    txn_id: txn_id,
+                    txn_id,
                     attempts: 0,
                     state: SendState::Failed,
                     event_id: None,
@@ -2377,19 +4022,16 @@ impl Client {
         client_txn
     }
 
-    pub fn retry_by_txn(&self, _room_id: String, txn_id: String) -> bool {
+    pub fn retry_by_txn(&self, _room_id: String, txn_id: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            if let Some(handle) = self
+            let handle = self
                 .send_handles_by_txn
                 .lock()
                 .unwrap()
                 .get(&txn_id)
                 .cloned()
-            {
-                handle.unwedge().await.is_ok()
-            } else {
-                false
-            }
+                .ok_or_else(|| FfiError::not_found("no send handle for txn"))?;
+            handle.unwedge().await.map_err(FfiError::from)
         })
     }
 
@@ -2424,6 +4066,7 @@ impl Client {
                     member_count,
                     is_encrypted,
                     is_dm,
+                    avatar_url: room.avatar_url().map(|mxc| mxc.to_string()),
                 });
             }
             Ok(out)
@@ -2431,27 +4074,28 @@ impl Client {
     }
 
     // Accept an invite by room ID
-    pub fn accept_invite(&self, room_id: String) -> bool {
+    pub fn accept_invite(&self, room_id: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return false;
-            };
+            let rid = OwnedRoomId::try_from(room_id)?;
             // Join-by-id is the canonical accept for invites
-            self.inner.join_room_by_id(&rid).await.is_ok()
+            self.inner
+                .join_room_by_id(&rid)
+                .await
+                .map(|_| ())
+                .map_err(FfiError::from)
         })
     }
 
     // Decline an invite (leave)
     pub fn leave_room(&self, room_id: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            let rid =
-                ruma::OwnedRoomId::try_from(room_id).map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid = ruma::OwnedRoomId::try_from(room_id)?;
             let room = self
                 .inner
                 .get_room(&rid)
-                .ok_or_else(|| FfiError::Msg("room not found".into()))?;
+                .ok_or_else(|| FfiError::not_found("room not found"))?;
 
-            room.leave().await.map_err(|e| FfiError::Msg(e.to_string()))
+            room.leave().await.map_err(FfiError::from)
         })
     }
 
@@ -2466,7 +4110,7 @@ impl Client {
         RT.block_on(async {
             // Build create room request (private visibility)
             let mut req = create_room_v3::Request::new();
-            req.preset = Some(RoomPreset::PrivateChat.into());
+            req.preset = Some(RoomPreset::PrivateChat);
             req.visibility = Visibility::Private;
             if let Some(n) = &name {
                 req.name = Some(n.clone());
@@ -2479,35 +4123,172 @@ impl Client {
                     .into_iter()
                     .map(|u| u.parse())
                     .collect::<Result<Vec<_>, _>>()
-                    .map_err(|e: ruma::IdParseError| FfiError::Msg(e.to_string()))?;
+                    .map_err(|e: ruma::IdParseError| FfiError::invalid_input(e.to_string()))?;
                 req.invite = parsed;
             }
 
             // NOTE: If you want encryption-by-default, see small fix below.
-            let resp = self
-                .inner
-                .send(req)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let resp = self.inner.send(req).await?;
             Ok(resp.room_id.to_string())
         })
     }
 
     // Set room name (state event)
-    pub fn set_room_name(&self, room_id: String, name: String) -> bool {
+    pub fn set_room_name(&self, room_id: String, name: String) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
             room.send_state_event(RoomNameEventContent::new(name))
                 .await
-                .is_ok()
+                .map(|_| ())
+                .map_err(FfiError::from)
         })
     }
 
     // Set room topic
-    pub fn set_room_topic(&self, room_id: String, topic: String) -> bool {
+    pub fn set_room_topic(&self, room_id: String, topic: String) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
             room.send_state_event(RoomTopicEventContent::new(topic))
                 .await
-                .is_ok()
+                .map(|_| ())
+                .map_err(FfiError::from)
+        })
+    }
+
+    /// Upload the image at `path` (metadata stripped) and make it the room avatar.
+    pub fn set_room_avatar(&self, room_id: String, path: String) -> Result<(), FfiError> {
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
+            let (mime, data, (width, height)) = read_avatar(&path).await?;
+            let size = data.len() as u64;
+            let upload = self.inner.media().upload(&mime, data, None).await?;
+
+            let mut info = ruma::events::room::avatar::ImageInfo::new();
+            info.mimetype = Some(mime.to_string());
+            info.size = UInt::new(size);
+            info.width = Some(UInt::from(width));
+            info.height = Some(UInt::from(height));
+
+            let mut content = RoomAvatarEventContent::new();
+            content.url = Some(upload.content_uri);
+            content.info = Some(Box::new(info));
+            room.send_state_event(content)
+                .await
+                .map(|_| ())
+                .map_err(FfiError::from)
+        })
+    }
+
+    pub fn set_own_display_name(&self, name: String) -> Result<(), FfiError> {
+        RT.block_on(async {
+            let name = Some(name.trim()).filter(|n| !n.is_empty());
+            self.inner
+                .account()
+                .set_display_name(name)
+                .await
+                .map_err(FfiError::from)
+        })
+    }
+
+    /// Upload the image at `path` (metadata stripped) as our avatar and
+    /// return its mxc URI.
+    pub fn set_own_avatar(&self, path: String) -> Result<String, FfiError> {
+        RT.block_on(async {
+            let (mime, data, _) = read_avatar(&path).await?;
+            let mxc = self.inner.account().upload_avatar(&mime, data).await?;
+            Ok(mxc.to_string())
+        })
+    }
+
+    pub fn remove_own_avatar(&self) -> Result<(), FfiError> {
+        RT.block_on(async {
+            self.inner
+                .account()
+                .set_avatar_url(None)
+                .await
+                .map_err(FfiError::from)
+        })
+    }
+
+    /// Global display name and avatar of any user, ourselves included.
+    /// Fetch the avatar itself with `resolve_media`.
+    pub fn get_user_profile(&self, user_id: String) -> Result<UserProfile, FfiError> {
+        RT.block_on(async {
+            let uid = OwnedUserId::try_from(user_id)?;
+            let profile = self.inner.account().fetch_user_profile_of(&uid).await?;
+            Ok(UserProfile {
+                user_id: uid.to_string(),
+                display_name: profile
+                    .get("displayname")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_owned),
+                avatar_url: profile
+                    .get("avatar_url")
+                    .and_then(|v| v.as_str())
+                    .map(str::to_owned),
+            })
+        })
+    }
+
+    /// Preview of `url` from the homeserver's `/preview_url`, cached in the
+    /// store. `ts_ms` asks for the page as it was at that time (e.g. the
+    /// message's timestamp). None when the page has no preview data.
+    pub fn url_preview(
+        &self,
+        url: String,
+        ts_ms: Option<u64>,
+    ) -> Result<Option<UrlPreview>, FfiError> {
+        RT.block_on(async {
+            load_url_previews(&self.inner, &self.url_previews).await;
+            let now = now_ms();
+            if let Some(hit) = self.url_previews.lock().unwrap().get(&url, now) {
+                return Ok(hit.preview.clone());
+            }
+
+            let preview = fetch_url_preview(&self.inner, &url, ts_ms).await?;
+            self.url_previews.lock().unwrap().insert(
+                url,
+                CachedUrlPreview {
+                    fetched_ms: now,
+                    preview: preview.clone(),
+                },
+            );
+            save_url_previews(&self.inner, &self.url_previews).await;
+            Ok(preview)
+        })
+    }
+
+    /// `url_preview` for a link posted in `room_id`. In encrypted rooms this is
+    /// None unless `set_url_previews_in_encrypted_rooms(true)`, since the
+    /// homeserver would learn the link.
+    pub fn room_url_preview(
+        &self,
+        room_id: String,
+        url: String,
+        ts_ms: Option<u64>,
+    ) -> Result<Option<UrlPreview>, FfiError> {
+        let encrypted = with_room_async!(self, room_id, |room: Room, _rid| async move {
+            Ok::<_, FfiError>(
+                room.latest_encryption_state()
+                    .await
+                    .map(|s| s.is_encrypted())
+                    // Unknown state: assume the worst.
+                    .unwrap_or(true),
+            )
+        })?;
+        if encrypted && !self.url_previews_in_encrypted_rooms() {
+            return Ok(None);
+        }
+        self.url_preview(url, ts_ms)
+    }
+
+    pub fn url_previews_in_encrypted_rooms(&self) -> bool {
+        RT.block_on(load_url_previews(&self.inner, &self.url_previews));
+        self.url_previews.lock().unwrap().encrypted_rooms
+    }
+
+    pub fn set_url_previews_in_encrypted_rooms(&self, enabled: bool) {
+        RT.block_on(async {
+            load_url_previews(&self.inner, &self.url_previews).await;
+            self.url_previews.lock().unwrap().encrypted_rooms = enabled;
+            save_url_previews(&self.inner, &self.url_previews).await;
         })
     }
 
@@ -2515,12 +4296,11 @@ impl Client {
         RT.block_on(async {
             use matrix_sdk_base::RoomMemberships;
 
-            let rid =
-                ruma::OwnedRoomId::try_from(room_id).map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid = ruma::OwnedRoomId::try_from(room_id)?;
             let room = self
                 .inner
                 .get_room(&rid)
-                .ok_or_else(|| FfiError::Msg("room not found".into()))?;
+                .ok_or_else(|| FfiError::not_found("room not found"))?;
 
             let name = room
                 .display_name()
@@ -2560,6 +4340,7 @@ impl Client {
                 member_count,
                 is_encrypted,
                 is_dm,
+                avatar_url: room.avatar_url().map(|mxc| mxc.to_string()),
             })
         })
     }
@@ -2569,9 +4350,7 @@ impl Client {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
                 return None;
             };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return None;
-            };
+            let room = self.inner.get_room(&rid)?;
 
             let mode = room.notification_mode().await?;
 
@@ -2590,7 +4369,7 @@ impl Client {
     ) -> Result<(), FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
 
             let sdk_mode = match mode {
@@ -2604,7 +4383,7 @@ impl Client {
                 .await
                 .set_room_notification_mode(rid.as_ref(), sdk_mode)
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
@@ -2612,19 +4391,15 @@ impl Client {
         RT.block_on(async {
             use matrix_sdk_base::RoomMemberships;
 
-            let rid =
-                ruma::OwnedRoomId::try_from(room_id).map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid = ruma::OwnedRoomId::try_from(room_id)?;
             let room = self
                 .inner
                 .get_room(&rid)
-                .ok_or_else(|| FfiError::Msg("room not found".into()))?;
+                .ok_or_else(|| FfiError::not_found("room not found"))?;
 
             let me = self.inner.user_id();
 
-            let members = room
-                .members(RoomMemberships::ACTIVE)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let members = room.members(RoomMemberships::ACTIVE).await?;
 
             let out: Vec<MemberSummary> = members
                 .into_iter()
@@ -2633,6 +4408,12 @@ impl Client {
                     display_name: m.display_name().map(|n| n.to_string()),
                     is_me: me.map(|u| u == m.user_id()).unwrap_or(false),
                     membership: m.membership().to_string(),
+                    avatar_url: m.avatar_url().map(|mxc| mxc.to_string()),
+                    power_level: match m.power_level() {
+                        UserPowerLevel::Infinite => i64::MAX,
+                        UserPowerLevel::Int(level) => level.into(),
+                        _ => 0,
+                    },
                 })
                 .collect();
 
@@ -2649,7 +4430,7 @@ impl Client {
         device_display_name: String,
         lang: String,
         profile_tag: Option<String>,
-    ) -> bool {
+    ) -> Result<(), FfiError> {
         RT.block_on(async {
             // Test with a cs secret (like the json output)
             let client_secret = self
@@ -2669,7 +4450,7 @@ impl Client {
 
             http_data.data.insert(
                 "default_payload".to_owned(),
-                serde_json::json!({ "cs": client_secret }).into(),
+                serde_json::json!({ "cs": client_secret }),
             );
 
             let init = PusherInit {
@@ -2688,15 +4469,15 @@ impl Client {
                 app_id, gateway_url, client_secret
             );
 
-            self.inner.pusher().set(pusher).await.is_ok()
+            Ok(self.inner.pusher().set(pusher).await?)
         })
     }
 
     /// Unregister HTTP pusher by ids
-    pub fn unregister_unifiedpush(&self, app_id: String, pushkey: String) -> bool {
+    pub fn unregister_unifiedpush(&self, app_id: String, pushkey: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            let ids = PusherIds::new(app_id.into(), pushkey.into());
-            self.inner.pusher().delete(ids).await.is_ok()
+            let ids = PusherIds::new(pushkey, app_id);
+            Ok(self.inner.pusher().delete(ids).await?)
         })
     }
 
@@ -2715,9 +4496,7 @@ impl Client {
             let Ok(rid) = ruma::OwnedRoomId::try_from(room_id) else {
                 return None;
             };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return None;
-            };
+            let room = self.inner.get_room(&rid)?;
             Some(UnreadStats {
                 messages: room.num_unread_messages(),
                 notifications: room.num_unread_notifications(),
@@ -2735,7 +4514,7 @@ impl Client {
                 };
             };
 
-            let Some(tl) = get_timeline_for(&self.inner, &rid).await else {
+            let Some(tl) = get_timeline_for(&self.inner, &self.timelines, &rid).await else {
                 return OwnReceipt {
                     event_id: None,
                     ts_ms: None,
@@ -2786,22 +4565,15 @@ impl Client {
         id
     }
 
-    pub fn mark_fully_read_at(&self, room_id: String, event_id: String) -> bool {
-        RT.block_on(async {
-            let Ok(rid) = ruma::OwnedRoomId::try_from(room_id) else {
-                return false;
-            };
-            let Ok(eid) = ruma::OwnedEventId::try_from(event_id) else {
-                return false;
-            };
-            if let Some(room) = self.inner.get_room(&rid) {
-                let receipts = matrix_sdk::room::Receipts::new()
-                    .private_read_receipt(eid.clone())
-                    .fully_read_marker(eid);
-                room.send_multiple_receipts(receipts).await.is_ok()
-            } else {
-                false
-            }
+    pub fn mark_fully_read_at(&self, room_id: String, event_id: String) -> Result<(), FfiError> {
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
+            let eid = ruma::OwnedEventId::try_from(event_id)?;
+            let receipts = matrix_sdk::room::Receipts::new()
+                .private_read_receipt(eid.clone())
+                .fully_read_marker(eid);
+            room.send_multiple_receipts(receipts)
+                .await
+                .map_err(FfiError::from)
         })
     }
 
@@ -2826,6 +4598,7 @@ impl Client {
         let obs: std::sync::Arc<dyn RoomListObserver> = std::sync::Arc::from(observer);
         let svc_slot = self.sync_service.clone();
         let client = self.inner.clone();
+        let timelines = self.timelines.clone();
         let id = self.next_sub_id();
 
         let (cmd_tx, mut cmd_rx) = tokio::sync::mpsc::unbounded_channel::<RoomListCmd>();
@@ -2906,28 +4679,28 @@ impl Client {
                                     changed = true;
                                 }
                                 VectorDiff::Insert { index, value } => {
-                                    let idx = index as usize;
+                                    let idx = index;
                                     if idx <= rooms.len() {
                                         rooms.insert(idx, value);
                                         changed = true;
                                     }
                                 }
                                 VectorDiff::Set { index, value } => {
-                                    let idx = index as usize;
+                                    let idx = index;
                                     if idx < rooms.len() {
                                         rooms[idx] = value;
                                         changed = true;
                                     }
                                 }
                                 VectorDiff::Remove { index } => {
-                                    let idx = index as usize;
+                                    let idx = index;
                                     if idx < rooms.len() {
                                         rooms.remove(idx);
                                         changed = true;
                                     }
                                 }
                                 VectorDiff::Truncate { length } => {
-                                    rooms.truncate(length as usize);
+                                    rooms.truncate(length);
                                     changed = true;
                                 }
                                 VectorDiff::Append { values } => {
@@ -2962,7 +4735,7 @@ impl Client {
                                 let member_count = member_count_u64.min(u32::MAX as u64) as u32;
                                 let topic = room.topic();
 
-                                let latest_event: Option<LatestRoomEvent> = latest_room_event_for(&client, &room).await;
+                                let latest_event: Option<LatestRoomEvent> = latest_room_event_for(&client, &timelines, &room).await;
 
                                 snapshot.push(RoomListEntry {
                                     room_id: room.room_id().to_string(),
@@ -3007,17 +4780,239 @@ impl Client {
         unsub!(self, room_list_subs, token)
     }
 
-    pub fn search_users(
+    /// Full-text message search. Unencrypted rooms are searched by the server;
+    /// encrypted rooms by the on-device index. `room_ids = None` searches all rooms.
+    ///
+    /// The index holds decrypted text, so it is only saved when the stores have a
+    /// passphrase. Otherwise it starts empty on every launch and only covers the
+    /// messages decrypted since.
+    pub fn search_messages(
         &self,
-        search_term: String,
-        limit: u64,
-    ) -> Result<Vec<DirectoryUser>, FfiError> {
+        query: String,
+        room_ids: Option<Vec<String>>,
+        next_batch: Option<String>,
+        limit: u32,
+        context: u32,
+    ) -> Result<SearchPage, FfiError> {
+        use ruma::api::client::{filter::RoomEventFilter, search::search_events::v3 as search_v3};
+
         RT.block_on(async {
-            let resp = self
-                .inner
-                .search_users(&search_term, limit)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?; // matrix-sdk 0.14's helper
+            let limit = limit.max(1);
+            let mut cursor: SearchCursor = match next_batch.as_deref() {
+                Some(token) => serde_json::from_str(token)?,
+                None => SearchCursor::default(),
+            };
+
+            // Split the requested rooms: unencrypted → server, encrypted → local.
+            let mut server_rooms: Option<Vec<OwnedRoomId>> = None;
+            let mut local_rooms: Option<HashSet<String>> = None;
+            if let Some(ids) = room_ids {
+                let mut srv = Vec::new();
+                let mut loc = HashSet::new();
+                for id in ids {
+                    let rid = OwnedRoomId::try_from(id)?;
+                    let encrypted = match self.inner.get_room(&rid) {
+                        Some(room) => room
+                            .latest_encryption_state()
+                            .await
+                            .map(|s| s.is_encrypted())
+                            .unwrap_or(false),
+                        None => false,
+                    };
+                    if encrypted {
+                        loc.insert(rid.to_string());
+                    } else {
+                        srv.push(rid);
+                    }
+                }
+                cursor.server_done |= srv.is_empty();
+                cursor.local_done |= loc.is_empty();
+                server_rooms = Some(srv);
+                local_rooms = Some(loc);
+            }
+
+            let mut hits: Vec<SearchHit> = Vec::new();
+            let mut server_count = None;
+
+            if !cursor.server_done {
+                let mut filter = RoomEventFilter::default();
+                filter.limit = Some(UInt::from(limit));
+                filter.rooms = server_rooms;
+
+                let mut event_context = search_v3::EventContext::new();
+                event_context.before_limit = UInt::from(context);
+                event_context.after_limit = UInt::from(context);
+
+                let mut criteria = search_v3::Criteria::new(query.clone());
+                criteria.filter = filter;
+                criteria.order_by = Some(search_v3::OrderBy::Recent);
+                criteria.event_context = event_context;
+
+                let mut categories = search_v3::Categories::new();
+                categories.room_events = Some(criteria);
+                let mut req = search_v3::Request::new(categories);
+                req.next_batch = cursor.server.take();
+
+                let resp = self.inner.send(req).await?;
+                let room_events = resp.search_categories.room_events;
+                server_count = room_events.count.map(u64::from);
+
+                for result in room_events.results {
+                    let Some(event) = result.result.as_ref().and_then(map_raw_message) else {
+                        continue;
+                    };
+                    let mut context_before: Vec<MessageEvent> = result
+                        .context
+                        .events_before
+                        .iter()
+                        .filter_map(map_raw_message)
+                        .collect();
+                    context_before.sort_by_key(|e| e.timestamp_ms);
+                    let mut context_after: Vec<MessageEvent> = result
+                        .context
+                        .events_after
+                        .iter()
+                        .filter_map(map_raw_message)
+                        .collect();
+                    context_after.sort_by_key(|e| e.timestamp_ms);
+
+                    hits.push(SearchHit {
+                        event,
+                        context_before,
+                        context_after,
+                        rank: result.rank,
+                        local: false,
+                    });
+                }
+
+                cursor.server = room_events.next_batch;
+                cursor.server_done = cursor.server.is_none();
+            }
+
+            if !cursor.local_done {
+                let index = self.search_index.lock().unwrap();
+                let all = index.query(&query, local_rooms.as_ref());
+                let page: Vec<&IndexedMessage> = all
+                    .iter()
+                    .skip(cursor.local_offset)
+                    .take(limit as usize)
+                    .collect();
+                for doc in &page {
+                    let (before, after) = index.context(doc, context as usize);
+                    hits.push(SearchHit {
+                        event: indexed_message_event(doc),
+                        context_before: before.iter().map(indexed_message_event).collect(),
+                        context_after: after.iter().map(indexed_message_event).collect(),
+                        rank: None,
+                        local: true,
+                    });
+                }
+                cursor.local_offset += page.len();
+                cursor.local_done = cursor.local_offset >= all.len();
+            }
+
+            hits.sort_by(|a, b| b.event.timestamp_ms.cmp(&a.event.timestamp_ms));
+
+            let next_batch = if cursor.server_done && cursor.local_done {
+                None
+            } else {
+                Some(serde_json::to_string(&cursor)?)
+            };
+
+            Ok(SearchPage {
+                hits,
+                server_count,
+                next_batch,
+            })
+        })
+    }
+
+    /// Rebuild the local search index from the (back-paginated) timelines of
+    /// encrypted rooms, up to `max_events_per_room` each. `room_ids = None`
+    /// drops and rebuilds the whole index. Returns the number of indexed messages.
+    pub fn rebuild_search_index(
+        &self,
+        room_ids: Option<Vec<String>>,
+        max_events_per_room: u32,
+    ) -> Result<u64, FfiError> {
+        RT.block_on(async {
+            let rooms: Vec<Room> = match &room_ids {
+                Some(ids) => {
+                    let mut out = Vec::new();
+                    for id in ids {
+                        let rid = OwnedRoomId::try_from(id.as_str())?;
+                        let room = self
+                            .inner
+                            .get_room(&rid)
+                            .ok_or_else(|| FfiError::not_found("room not found"))?;
+                        out.push(room);
+                    }
+                    out
+                }
+                None => self.inner.joined_rooms(),
+            };
+
+            let mut encrypted = Vec::new();
+            for room in rooms {
+                if room
+                    .latest_encryption_state()
+                    .await
+                    .map(|s| s.is_encrypted())
+                    .unwrap_or(false)
+                {
+                    encrypted.push(room);
+                }
+            }
+
+            let scope: Option<HashSet<String>> = room_ids
+                .as_ref()
+                .map(|_| encrypted.iter().map(|r| r.room_id().to_string()).collect());
+            self.search_index.lock().unwrap().clear(scope.as_ref());
+
+            let mut indexed = 0u64;
+            for room in encrypted {
+                let rid = room.room_id().to_owned();
+                let Some(tl) = get_timeline_for(&self.inner, &self.timelines, &rid).await else {
+                    continue;
+                };
+
+                loop {
+                    let have = tl
+                        .items()
+                        .await
+                        .iter()
+                        .filter(|it| it.as_event().is_some())
+                        .count();
+                    if have >= max_events_per_room as usize {
+                        break;
+                    }
+                    match tl.paginate_backwards(50).await {
+                        Ok(false) => {}
+                        _ => break, // reached the start, or failed
+                    }
+                }
+
+                for it in tl.items().await.iter() {
+                    if let Some(ev) = it.as_event()
+                        && index_timeline_item(&self.search_index, &rid, ev)
+                    {
+                        indexed += 1;
+                    }
+                }
+            }
+
+            flush_search_index(&self.inner, &self.search_index, self.store_encrypted).await;
+            Ok(indexed)
+        })
+    }
+
+    pub fn search_users(
+        &self,
+        search_term: String,
+        limit: u64,
+    ) -> Result<Vec<DirectoryUser>, FfiError> {
+        RT.block_on(async {
+            let resp = self.inner.search_users(&search_term, limit).await?; // matrix-sdk 0.14's helper
             let out = resp
                 .results
                 .into_iter()
@@ -3041,9 +5036,7 @@ impl Client {
         RT.block_on(async {
             // Parse server name if provided
             let server_name: Option<OwnedServerName> = match server {
-                Some(s) => {
-                    Some(OwnedServerName::try_from(s).map_err(|e| FfiError::Msg(e.to_string()))?)
-                }
+                Some(s) => Some(OwnedServerName::try_from(s)?),
                 None => None,
             };
 
@@ -3063,11 +5056,7 @@ impl Client {
                     req.server = Some(sn.clone());
                 }
 
-                let resp = self
-                    .inner
-                    .public_rooms_filtered(req)
-                    .await
-                    .map_err(|e| FfiError::Msg(e.to_string()))?;
+                let resp = self.inner.public_rooms_filtered(req).await?;
 
                 let rooms = resp
                     .chunk
@@ -3094,8 +5083,7 @@ impl Client {
                 let resp = self
                     .inner
                     .public_rooms(Some(limit), since.as_deref(), server_name.as_deref())
-                    .await
-                    .map_err(|e| FfiError::Msg(e.to_string()))?;
+                    .await?;
 
                 let rooms = resp
                     .chunk
@@ -3121,15 +5109,14 @@ impl Client {
         })
     }
 
-    pub fn join_by_id_or_alias(&self, id_or_alias: String) -> bool {
+    pub fn join_by_id_or_alias(&self, id_or_alias: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            let Ok(target) = OwnedRoomOrAliasId::try_from(id_or_alias) else {
-                return false;
-            };
+            let target = OwnedRoomOrAliasId::try_from(id_or_alias)?;
             self.inner
                 .join_room_by_id_or_alias(&target, &[])
                 .await
-                .is_ok()
+                .map(|_| ())
+                .map_err(FfiError::from)
         })
     }
 
@@ -3139,33 +5126,22 @@ impl Client {
                 return Ok(id_or_alias);
             }
             if id_or_alias.starts_with('#') {
-                let alias = OwnedRoomAliasId::try_from(id_or_alias)
-                    .map_err(|e| FfiError::Msg(e.to_string()))?;
-                let resp = self
-                    .inner
-                    .resolve_room_alias(&alias)
-                    .await
-                    .map_err(|e| FfiError::Msg(e.to_string()))?;
+                let alias = OwnedRoomAliasId::try_from(id_or_alias)?;
+                let resp = self.inner.resolve_room_alias(&alias).await?;
                 return Ok(resp.room_id.to_string());
             }
-            Err(FfiError::Msg("not a room id or alias".into()))
+            Err(FfiError::invalid_input("not a room id or alias"))
         })
     }
 
     // Ensure a DM exists with a user: reuse if present, else create one.
     pub fn ensure_dm(&self, user_id: String) -> Result<String, FfiError> {
         RT.block_on(async {
-            let uid = user_id
-                .parse::<OwnedUserId>()
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let uid = user_id.parse::<OwnedUserId>()?;
             if let Some(room) = self.inner.get_dm_room(&uid) {
                 return Ok(room.room_id().to_string());
             }
-            let room = self
-                .inner
-                .create_dm(&uid)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let room = self.inner.create_dm(&uid).await?;
             Ok(room.room_id().to_string())
         })
     }
@@ -3177,6 +5153,53 @@ impl Client {
         att: AttachmentInfo,
         filename_hint: Option<String>,
     ) -> Result<DownloadResult, FfiError> {
+        self.download_media_to_cache(att, filename_hint, None)?
+            .wait()
+    }
+
+    /// Download full media described by AttachmentInfo directly to `save_path`.
+    pub fn download_attachment_to_path(
+        &self,
+        att: AttachmentInfo,
+        save_path: String,
+        progress: Option<Box<dyn ProgressObserver>>,
+    ) -> Result<DownloadResult, FfiError> {
+        self.download_media(att, save_path, progress)?.wait()
+    }
+
+    /// Start streaming the media to `save_path` and return immediately.
+    /// A request for media that is already downloading joins that download.
+    pub fn download_media(
+        &self,
+        att: AttachmentInfo,
+        save_path: String,
+        progress: Option<Box<dyn ProgressObserver>>,
+    ) -> Result<Arc<DownloadHandle>, FfiError> {
+        let source = attachment_source(&att)?;
+        let path = PathBuf::from(save_path);
+        let job = self.downloads.start(
+            &self.inner,
+            att.mxc_uri,
+            source,
+            None,
+            path.clone(),
+            progress.map(Arc::from),
+        );
+        Ok(Arc::new(DownloadHandle {
+            job,
+            path,
+            manager: self.downloads.clone(),
+        }))
+    }
+
+    /// Like `download_media`, into the cache dir under a name stable per media,
+    /// so finished files are reused and interrupted ones resume.
+    pub fn download_media_to_cache(
+        &self,
+        att: AttachmentInfo,
+        filename_hint: Option<String>,
+        progress: Option<Box<dyn ProgressObserver>>,
+    ) -> Result<Arc<DownloadHandle>, FfiError> {
         let dir = cache_dir(&self.store_dir);
         ensure_dir(&dir);
 
@@ -3197,99 +5220,76 @@ impl Client {
             .map(sanitize)
             .filter(|s| !s.is_empty())
             .unwrap_or_else(|| "file.bin".into());
-        let out = dir.join(format!("dl_{}_{}", now_ms(), hint));
-
-        RT.block_on(async {
-            use mime::APPLICATION_OCTET_STREAM;
-
-            // IMPORTANT: use encryption info when available
-            let source = if let Some(enc) = att.encrypted.as_ref() {
-                let ef: EncryptedFile = serde_json::from_str(&enc.json)
-                    .map_err(|e| FfiError::Msg(format!("file enc parse: {e}")))?;
-                MediaSource::Encrypted(Box::new(ef))
-            } else {
-                MediaSource::Plain(att.mxc_uri.clone().into())
-            };
-
-            let req = MediaRequestParameters {
-                source,
-                format: MediaFormat::File,
-            };
+        let key = blake3::hash(att.mxc_uri.as_bytes()).to_hex();
+        let out = dir.join(format!("dl_{}_{}", &key[..16], hint));
+
+        if let Ok(md) = std::fs::metadata(&out) {
+            let job = self.downloads.completed(att.mxc_uri, out.clone(), md.len());
+            return Ok(Arc::new(DownloadHandle {
+                job,
+                path: out,
+                manager: self.downloads.clone(),
+            }));
+        }
 
-            let handle = self
-                .inner
-                .media()
-                .get_media_file(&req, None, &APPLICATION_OCTET_STREAM, true, None)
-                .await
-                .map_err(|e| FfiError::Msg(format!("download: {e}")))?;
-
-            // Try persist then copy (cross-device link fallback)
-            match handle.persist(&out) {
-                Ok(_) => {}
-                Err(persist_error) => {
-                    let src_path = persist_error.file.path();
-                    std::fs::copy(src_path, &out)
-                        .map_err(|e| FfiError::Msg(format!("copy fallback failed: {e}")))?;
-                }
-            }
+        let source = attachment_source(&att)?;
+        let job = self.downloads.start(
+            &self.inner,
+            att.mxc_uri,
+            source,
+            None,
+            out.clone(),
+            progress.map(Arc::from),
+        );
+        Ok(Arc::new(DownloadHandle {
+            job,
+            path: out,
+            manager: self.downloads.clone(),
+        }))
+    }
 
-            let bytes = std::fs::metadata(&out).map(|m| m.len()).unwrap_or(0);
-            Ok(DownloadResult {
-                path: out.to_string_lossy().to_string(),
-                bytes,
-            })
-        })
+    /// Queued and running downloads.
+    pub fn downloads(&self) -> Vec<DownloadInfo> {
+        self.downloads.snapshot()
     }
 
-    /// Download full media described by AttachmentInfo directly to `save_path`.
-    pub fn download_attachment_to_path(
-        &self,
-        att: AttachmentInfo,
-        save_path: String,
-        progress: Option<Box<dyn ProgressObserver>>,
-    ) -> Result<DownloadResult, FfiError> {
-        RT.block_on(async {
-            use mime::APPLICATION_OCTET_STREAM;
+    pub fn cancel_download(&self, id: u64) -> bool {
+        self.downloads.cancel(id)
+    }
 
-            let source = if let Some(enc) = att.encrypted.as_ref() {
-                let ef: EncryptedFile = serde_json::from_str(&enc.json)
-                    .map_err(|e| FfiError::Msg(format!("file enc parse: {e}")))?;
-                MediaSource::Encrypted(Box::new(ef))
-            } else {
-                MediaSource::Plain(att.mxc_uri.clone().into())
-            };
+    /// How many downloads run at once; the rest wait as `Queued`. Defaults to 3.
+    pub fn set_max_concurrent_downloads(&self, max: u32) {
+        self.downloads.set_limit(max as usize);
+    }
 
-            let req = MediaRequestParameters {
-                source,
-                format: MediaFormat::File,
-            };
+    /// Current downloads, then every state change and progress step.
+    pub fn observe_downloads(&self, observer: Box<dyn DownloadObserver>) -> u64 {
+        use tokio::sync::broadcast::error::RecvError;
 
-            let handle = self
-                .inner
-                .media()
-                .get_media_file(&req, None, &APPLICATION_OCTET_STREAM, true, None)
-                .await
-                .map_err(|e| FfiError::Msg(format!("download: {e}")))?;
+        let obs: Arc<dyn DownloadObserver> = Arc::from(observer);
+        let mut rx = self.downloads.updates.subscribe();
+        let current = self.downloads.snapshot();
 
-            // simple progress callback using temp file size
-            if let Some(p) = progress.as_ref() {
-                if let Ok(md) = std::fs::metadata(handle.path()) {
-                    p.on_progress(md.len(), Some(md.len()));
+        sub_manager!(self, download_subs, async move {
+            for info in current {
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| obs.on_update(info)));
+            }
+            loop {
+                match rx.recv().await {
+                    Ok(info) => {
+                        let _ = std::panic::catch_unwind(AssertUnwindSafe(|| obs.on_update(info)));
+                    }
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
                 }
             }
-
-            handle
-                .persist(Path::new(&save_path))
-                .map_err(|e| FfiError::Msg(format!("persist: {e}")))?;
-
-            let bytes = std::fs::metadata(&save_path).map(|m| m.len()).unwrap_or(0);
-            Ok(DownloadResult {
-                path: save_path,
-                bytes,
-            })
         })
     }
 
+    pub fn unobserve_downloads(&self, sub_id: u64) -> bool {
+        unsub!(self, download_subs, sub_id)
+    }
+
     pub fn room_list_set_unread_only(&self, token: u64, unread_only: bool) -> bool {
         if let Some(tx) = self.room_list_cmds.lock().unwrap().get(&token).cloned() {
             tx.send(RoomListCmd::SetUnreadOnly(unread_only)).is_ok()
@@ -3304,9 +5304,9 @@ impl Client {
         event_id: String,
     ) -> Result<Option<RenderedNotification>, FfiError> {
         let rid = ruma::OwnedRoomId::try_from(room_id)
-            .map_err(|e| FfiError::Msg(format!("bad room id: {e}")))?;
+            .map_err(|e| FfiError::invalid_input(format!("bad room id: {e}")))?;
         let eid = ruma::OwnedEventId::try_from(event_id)
-            .map_err(|e| FfiError::Msg(format!("bad event id: {e}")))?;
+            .map_err(|e| FfiError::invalid_input(format!("bad event id: {e}")))?;
 
         let sync = {
             let g = self.sync_service.lock().unwrap();
@@ -3355,19 +5355,17 @@ impl Client {
                     .unwrap_or_else(|| item.event.sender().localpart().to_string());
 
                 let mut body = String::from("New event");
-                if let NotificationEvent::Timeline(ev) = &item.event {
-                    if let ruma::events::AnySyncTimelineEvent::MessageLike(
+                if let NotificationEvent::Timeline(ev) = &item.event
+                    && let ruma::events::AnySyncTimelineEvent::MessageLike(
                         ruma::events::AnySyncMessageLikeEvent::RoomMessage(m),
                     ) = ev.as_ref()
-                    {
-                        if let Some(orig) = m.as_original() {
-                            sender = item
-                                .sender_display_name
-                                .clone()
-                                .unwrap_or_else(|| orig.sender.localpart().to_string());
-                            body = orig.content.body().to_string();
-                        }
-                    }
+                    && let Some(orig) = m.as_original()
+                {
+                    sender = item
+                        .sender_display_name
+                        .clone()
+                        .unwrap_or_else(|| orig.sender.localpart().to_string());
+                    body = orig.content.body().to_string();
                 }
 
                 Ok(Some(RenderedNotification {
@@ -3404,8 +5402,7 @@ impl Client {
                 self.inner.clone(),
                 NotificationProcessSetup::SingleProcess { sync_service: sync },
             )
-            .await
-            .map_err(|e| FfiError::Msg(e.to_string()))?;
+            .await?;
 
             let mut out = Vec::new();
 
@@ -3454,19 +5451,17 @@ impl Client {
                         .unwrap_or_else(|| item.event.sender().localpart().to_string());
 
                     let mut body = String::from("New event");
-                    if let NotificationEvent::Timeline(tev) = &item.event {
-                        if let ruma::events::AnySyncTimelineEvent::MessageLike(
+                    if let NotificationEvent::Timeline(tev) = &item.event
+                        && let ruma::events::AnySyncTimelineEvent::MessageLike(
                             ruma::events::AnySyncMessageLikeEvent::RoomMessage(m),
                         ) = tev.as_ref()
-                        {
-                            if let Some(orig) = m.as_original() {
-                                sender = item
-                                    .sender_display_name
-                                    .clone()
-                                    .unwrap_or_else(|| orig.sender.localpart().to_string());
-                                body = orig.content.body().to_string();
-                            }
-                        }
+                        && let Some(orig) = m.as_original()
+                    {
+                        sender = item
+                            .sender_display_name
+                            .clone()
+                            .unwrap_or_else(|| orig.sender.localpart().to_string());
+                        body = orig.content.body().to_string();
                     }
 
                     out.push(RenderedNotification {
@@ -3506,29 +5501,126 @@ impl Client {
                 })
                 .initial_device_display_name(device_name.as_deref().unwrap_or("Mages"))
                 .send()
+                .await?;
+
+            persist_session(&self.inner, &self.store_dir, self.store_encrypted).await
+        })
+    }
+
+    /// Whether the homeserver delegates auth to an OAuth 2.0 server (MSC3861 / MAS).
+    pub fn oauth_supported(&self) -> bool {
+        RT.block_on(async { self.inner.oauth().server_metadata().await.is_ok() })
+    }
+
+    /// OAuth 2.0 / OIDC login with a built-in loopback redirect server.
+    /// Registers the client dynamically, opens the authorization URL and completes login.
+    pub fn login_oauth_loopback(
+        &self,
+        opener: Box<dyn UrlOpener>,
+        client_name: Option<String>,
+    ) -> Result<(), FfiError> {
+        use matrix_sdk::utils::local_server::LocalServerBuilder;
+
+        RT.block_on(async {
+            let (redirect_uri, redirect_handle) = LocalServerBuilder::new()
+                .spawn()
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
-
-            if let Some(sess) = self.inner.matrix_auth().session() {
-                tokio::fs::create_dir_all(&self.store_dir).await?;
-                let info = SessionInfo {
-                    user_id: sess.meta.user_id.to_string(),
-                    device_id: sess.meta.device_id.to_string(),
-                    access_token: sess.tokens.access_token.clone(),
-                    refresh_token: sess.tokens.refresh_token.clone(),
-                    homeserver: self.inner.homeserver().to_string(),
-                };
-                tokio::fs::write(
-                    session_file(&self.store_dir),
-                    serde_json::to_string(&info).unwrap(),
-                )
+                .map_err(|e| FfiError::Msg(format!("loopback server: {e}")))?;
+
+            let cancel = self.arm_browser_auth_cancel();
+            let oauth = self.inner.oauth();
+            let registration = oauth_registration_data(Some(&redirect_uri), client_name)?;
+            let data = oauth
+                .login(redirect_uri, None, Some(registration), None)
+                .build()
                 .await?;
+
+            if !opener.open(data.url.to_string()) {
+                oauth.abort_login(&data.state).await;
+                return Err(FfiError::Msg("could not open the authorization URL".into()));
             }
 
+            let query = match await_browser(redirect_handle, cancel, "OAuth login").await {
+                Ok(Some(query)) => query,
+                Ok(None) => {
+                    oauth.abort_login(&data.state).await;
+                    return Err(FfiError::Msg("OAuth login cancelled".into()));
+                }
+                Err(e) => {
+                    oauth.abort_login(&data.state).await;
+                    return Err(e);
+                }
+            };
+
+            oauth.finish_login(UrlOrQuery::Query(query.0)).await?;
+
+            persist_session(&self.inner, &self.store_dir, self.store_encrypted).await?;
+            Ok(())
+        })
+    }
+
+    /// Start an OAuth 2.0 / OIDC login for a custom-scheme (or externally handled)
+    /// `redirect_uri`. Open the returned URL, then pass the redirect to
+    /// `oauth_finish_login`.
+    pub fn oauth_login_url(
+        &self,
+        redirect_uri: String,
+        client_name: Option<String>,
+    ) -> Result<OAuthAuthorization, FfiError> {
+        use matrix_sdk::reqwest::Url;
+
+        RT.block_on(async {
+            let redirect_uri = Url::parse(&redirect_uri)
+                .map_err(|e| FfiError::invalid_input(format!("bad redirect URI: {e}")))?;
+            let registration = oauth_registration_data(Some(&redirect_uri), client_name)?;
+            let data = self
+                .inner
+                .oauth()
+                .login(redirect_uri, None, Some(registration), None)
+                .build()
+                .await?;
+
+            Ok(OAuthAuthorization {
+                url: data.url.to_string(),
+                state: data.state.secret().to_owned(),
+            })
+        })
+    }
+
+    /// Complete an OAuth login with the full redirect URL received by the app.
+    pub fn oauth_finish_login(&self, callback_url: String) -> Result<(), FfiError> {
+        use matrix_sdk::reqwest::Url;
+
+        RT.block_on(async {
+            let url = Url::parse(&callback_url)
+                .map_err(|e| FfiError::invalid_input(format!("bad callback URL: {e}")))?;
+            self.inner
+                .oauth()
+                .finish_login(UrlOrQuery::Url(url))
+                .await?;
+
+            persist_session(&self.inner, &self.store_dir, self.store_encrypted).await?;
             Ok(())
         })
     }
 
+    /// Give up on a pending browser step of `login_oauth_loopback` or
+    /// `reset_cross_signing`.
+    pub fn cancel_browser_auth(&self) {
+        if let Some(tx) = self.browser_auth_cancel.lock().unwrap().take() {
+            let _ = tx.send(());
+        }
+    }
+
+    /// Drop the pending authorization data for `state` (user backed out).
+    pub fn oauth_abort_login(&self, state: String) {
+        use matrix_sdk::authentication::oauth::CsrfToken;
+
+        RT.block_on(async {
+            self.inner.oauth().abort_login(&CsrfToken::new(state)).await;
+        })
+    }
+
     /// Return reactions (emoji -> count, me).
     pub fn reactions_for_event(&self, room_id: String, event_id: String) -> Vec<ReactionSummary> {
         RT.block_on(async {
@@ -3541,29 +5633,72 @@ impl Client {
                 Err(_) => return vec![],
             };
 
-            let Some(tl) = get_timeline_for(&self.inner, &rid).await else {
+            let Some(tl) = get_timeline_for(&self.inner, &self.timelines, &rid).await else {
                 return vec![];
             };
             let Some(item) = tl.item_by_event_id(&eid).await else {
                 return vec![];
             };
 
-            let me = self.inner.user_id();
+            map_reactions(&item, self.inner.user_id())
+        })
+    }
+
+    /// Every revision of a message, oldest first (the original, then each edit).
+    pub fn edit_history(
+        &self,
+        room_id: String,
+        event_id: String,
+    ) -> Result<Vec<EditRevision>, FfiError> {
+        with_room_async!(self, room_id, |room: Room, rid: OwnedRoomId| async move {
+            use ruma::api::client::relations::get_relating_events_with_rel_type;
+
+            let eid = ruma::OwnedEventId::try_from(event_id)?;
+
             let mut out = Vec::new();
+            let original = room.event(&eid, None).await?;
+            out.extend(edit_revision(original.raw()));
+            // Only the original sender may edit a message; anything else is spoofed.
+            let sender = original
+                .raw()
+                .get_field::<OwnedUserId>("sender")
+                .ok()
+                .flatten();
 
-            if let Some(reactions) = item.content().reactions() {
-                for (key, by_sender) in reactions.iter() {
-                    let count = by_sender.len() as u32;
-                    let me_reacted = me.map(|u| by_sender.contains_key(u)).unwrap_or(false);
-                    out.push(ReactionSummary {
-                        key: key.to_string(),
-                        count,
-                        me: me_reacted,
-                    });
+            let mut from: Option<String> = None;
+            loop {
+                // No event-type filter: in encrypted rooms edits are `m.room.encrypted`.
+                let mut req = get_relating_events_with_rel_type::v1::Request::new(
+                    rid.clone(),
+                    eid.clone(),
+                    RelationType::Replacement,
+                );
+                req.dir = Direction::Forward;
+                req.from = from.take();
+                let resp = self.inner.send(req).await?;
+
+                for raw in resp.chunk.iter() {
+                    let Ok(Some(id)) = raw.get_field::<ruma::OwnedEventId>("event_id") else {
+                        continue;
+                    };
+                    // Fetch through the room so encrypted edits get decrypted.
+                    let Ok(ev) = room.event(&id, None).await else {
+                        continue;
+                    };
+                    let edit_sender = ev.raw().get_field::<OwnedUserId>("sender").ok().flatten();
+                    if sender.is_some() && edit_sender == sender {
+                        out.extend(edit_revision(ev.raw()));
+                    }
+                }
+
+                match resp.next_batch {
+                    Some(next) => from = Some(next),
+                    None => break,
                 }
             }
 
-            out
+            out.sort_by_key(|r| r.timestamp_ms);
+            Ok(out)
         })
     }
 
@@ -3572,9 +5707,7 @@ impl Client {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
                 return None;
             };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return None;
-            };
+            let room = self.inner.get_room(&rid)?;
             Some(RoomTags {
                 is_favourite: room.is_favourite(),
                 is_low_priority: room.is_low_priority(),
@@ -3582,15 +5715,19 @@ impl Client {
         })
     }
 
-    pub fn set_room_favourite(&self, room_id: String, fav: bool) -> bool {
+    pub fn set_room_favourite(&self, room_id: String, fav: bool) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            room.set_is_favourite(fav, None).await.is_ok()
+            room.set_is_favourite(fav, None)
+                .await
+                .map_err(FfiError::from)
         })
     }
 
-    pub fn set_room_low_priority(&self, room_id: String, low: bool) -> bool {
+    pub fn set_room_low_priority(&self, room_id: String, low: bool) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            room.set_is_low_priority(low, None).await.is_ok()
+            room.set_is_low_priority(low, None)
+                .await
+                .map_err(FfiError::from)
         })
     }
 
@@ -3601,19 +5738,12 @@ impl Client {
         root_event_id: String,
         body: String,
         reply_to_event_id: Option<String>,
-    ) -> bool {
-        RT.block_on(async {
-            let Ok(rid) = ruma::OwnedRoomId::try_from(room_id) else {
-                return false;
-            };
-            let Ok(root) = ruma::OwnedEventId::try_from(root_event_id) else {
-                return false;
-            };
-            let Some(tl) = get_timeline_for(&self.inner, &rid).await else {
-                return false;
-            };
+        options: Option<TextOptions>,
+    ) -> Result<(), FfiError> {
+        with_timeline_async!(self, room_id, |tl: Arc<Timeline>, _rid| async move {
+            let root = ruma::OwnedEventId::try_from(root_event_id)?;
 
-            let mut content: RoomMessageEventContent = RoomMessageEventContent::text_plain(body);
+            let mut content = build_text_content(body, options).with_relation(None);
 
             let relation = if let Some(reply_to) = reply_to_event_id {
                 if let Ok(eid) = ruma::OwnedEventId::try_from(reply_to) {
@@ -3626,7 +5756,8 @@ impl Client {
             };
 
             content.relates_to = Some(relation);
-            tl.send(content.into()).await.is_ok()
+            tl.send(content.into()).await?;
+            Ok(())
         })
     }
 
@@ -3639,10 +5770,8 @@ impl Client {
         direction_forward: bool,
     ) -> Result<ThreadPage, FfiError> {
         RT.block_on(async {
-            let rid = ruma::OwnedRoomId::try_from(room_id.clone())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
-            let root = ruma::OwnedEventId::try_from(root_event_id.clone())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid = ruma::OwnedRoomId::try_from(room_id.clone())?;
+            let root = ruma::OwnedEventId::try_from(root_event_id.clone())?;
 
             let mut req = get_relating::v1::Request::new(
                 rid.clone(),
@@ -3662,16 +5791,14 @@ impl Client {
                 Direction::Backward
             };
 
-            let resp = self
-                .inner
-                .send(req)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let resp = self.inner.send(req).await?;
 
             let mut out: Vec<MessageEvent> = Vec::new();
 
             // Include the root first (mapped via timeline for consistent formatting)
-            if let Some(root_ev) = map_event_id_via_timeline(&self.inner, &rid, &root).await {
+            if let Some(root_ev) =
+                map_event_id_via_timeline(&self.inner, &self.timelines, &rid, &root).await
+            {
                 out.push(root_ev);
             }
 
@@ -3679,7 +5806,9 @@ impl Client {
             for raw in resp.chunk.iter() {
                 if let Ok(ml) = raw.deserialize() {
                     let eid = ml.event_id().to_owned();
-                    if let Some(mev) = map_event_id_via_timeline(&self.inner, &rid, &eid).await {
+                    if let Some(mev) =
+                        map_event_id_via_timeline(&self.inner, &self.timelines, &rid, &eid).await
+                    {
                         out.push(mev);
                     }
                 }
@@ -3689,8 +5818,8 @@ impl Client {
             out.sort_by_key(|e| e.timestamp_ms);
 
             Ok(ThreadPage {
-                root_event_id: root_event_id,
-                room_id: room_id,
+                root_event_id,
+                room_id,
                 messages: out,
                 next_batch: resp.next_batch.clone(),
                 prev_batch: resp.prev_batch.clone(),
@@ -3707,10 +5836,8 @@ impl Client {
         max_pages: u32,
     ) -> Result<ThreadSummary, FfiError> {
         RT.block_on(async {
-            let rid = ruma::OwnedRoomId::try_from(room_id.clone())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
-            let root = ruma::OwnedEventId::try_from(root_event_id.clone())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid = ruma::OwnedRoomId::try_from(room_id.clone())?;
+            let root = ruma::OwnedEventId::try_from(root_event_id.clone())?;
 
             let mut from: Option<String> = None;
             let mut pages = 0u32;
@@ -3737,21 +5864,18 @@ impl Client {
                     req.limit = Some(per_page.into());
                 }
 
-                let resp = self
-                    .inner
-                    .send(req)
-                    .await
-                    .map_err(|e| FfiError::Msg(e.to_string()))?;
+                let resp = self.inner.send(req).await?;
 
                 for raw in resp.chunk.iter() {
                     if let Ok(ml) = raw.deserialize() {
                         let eid = ml.event_id().to_owned();
                         count += 1;
-                        if let Some(mev) = map_event_id_via_timeline(&self.inner, &rid, &eid).await
+                        if let Some(mev) =
+                            map_event_id_via_timeline(&self.inner, &self.timelines, &rid, &eid)
+                                .await
+                            && latest.is_none_or(|l| mev.timestamp_ms > l)
                         {
-                            if latest.map_or(true, |l| mev.timestamp_ms > l) {
-                                latest = Some(mev.timestamp_ms);
-                            }
+                            latest = Some(mev.timestamp_ms);
                         }
                     }
                 }
@@ -3842,7 +5966,7 @@ impl Client {
             // Set m.space via CreationContent.room_type
             let mut cc = create_room_v3::CreationContent::new();
             cc.room_type = Some(RoomType::Space);
-            req.creation_content = Some(Raw::new(&cc).map_err(|e| FfiError::Msg(e.to_string()))?);
+            req.creation_content = Some(Raw::new(&cc)?);
 
             req.name = Some(name);
             req.topic = topic;
@@ -3862,16 +5986,12 @@ impl Client {
                     .into_iter()
                     .map(|u| u.parse())
                     .collect::<Result<Vec<_>, _>>()
-                    .map_err(|e: ruma::IdParseError| FfiError::Msg(e.to_string()))?;
+                    .map_err(|e: ruma::IdParseError| FfiError::invalid_input(e.to_string()))?;
                 req.invite = parsed;
             }
 
             // Using Client::send so we can return the room_id from the response
-            let resp = self
-                .inner
-                .send(req)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let resp = self.inner.send(req).await?;
             Ok(resp.room_id.to_string())
         })
     }
@@ -3890,15 +6010,13 @@ impl Client {
             };
 
             // Parse room IDs
-            let rid_space = OwnedRoomId::try_from(space_id.as_str())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
-            let rid_child = OwnedRoomId::try_from(child_room_id.as_str())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid_space = OwnedRoomId::try_from(space_id.as_str())?;
+            let rid_child = OwnedRoomId::try_from(child_room_id.as_str())?;
 
             let room = self
                 .inner
                 .get_room(&rid_space)
-                .ok_or_else(|| FfiError::Msg("space not found".into()))?;
+                .ok_or_else(|| FfiError::not_found("space not found"))?;
 
             // Build via list from child's server
             let via: Vec<OwnedServerName> = rid_child
@@ -3911,16 +6029,14 @@ impl Client {
 
             if let Some(o) = order {
                 let ord = <&SpaceChildOrder>::try_from(o.as_str())
-                    .map_err(|e| FfiError::Msg(format!("Invalid order string: {}", e)))?
+                    .map_err(|e| FfiError::invalid_input(format!("invalid order string: {e}")))?
                     .to_owned();
                 content.order = Some(ord);
             }
 
             content.suggested = suggested.unwrap_or(false);
 
-            room.send_state_event_for_key(&rid_child, content)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            room.send_state_event_for_key(&rid_child, content).await?;
 
             Ok(())
         })
@@ -3936,17 +6052,15 @@ impl Client {
             use ruma::OwnedRoomId;
             use serde_json::json;
 
-            let rid_space = OwnedRoomId::try_from(space_id.as_str())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid_space = OwnedRoomId::try_from(space_id.as_str())?;
             let room = self
                 .inner
                 .get_room(&rid_space)
-                .ok_or_else(|| FfiError::Msg("space not found".into()))?;
+                .ok_or_else(|| FfiError::not_found("space not found"))?;
 
             // Return type here is Response; we ignore it and return ()
             room.send_state_event_raw("m.space.child", child_room_id.as_str(), json!({}))
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+                .await?;
 
             Ok(())
         })
@@ -3964,8 +6078,7 @@ impl Client {
         RT.block_on(async {
             use ruma::{OwnedRoomId, api::client::space::get_hierarchy::v1 as space_hierarchy_v1};
 
-            let rid_space = OwnedRoomId::try_from(space_id.as_str())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid_space = OwnedRoomId::try_from(space_id.as_str())?;
 
             let mut req = space_hierarchy_v1::Request::new(rid_space);
             req.from = from;
@@ -3975,11 +6088,7 @@ impl Client {
             req.max_depth = max_depth.map(Into::into);
             req.suggested_only = suggested_only; // bool
 
-            let resp = self
-                .inner
-                .send(req)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let resp = self.inner.send(req).await?;
 
             // Fields are on chunk.summary (not on the chunk itself)
             let children = resp
@@ -4012,12 +6121,10 @@ impl Client {
     }
 
     /// Invite a user to a space.
-    pub fn space_invite_user(&self, space_id: String, user_id: String) -> bool {
+    pub fn space_invite_user(&self, space_id: String, user_id: String) -> Result<(), FfiError> {
         with_room_async!(self, space_id, |room: Room, _rid| async move {
-            let Ok(uid) = ruma::OwnedUserId::try_from(user_id) else {
-                return false;
-            };
-            room.invite_user_by_id(&uid).await.is_ok()
+            let uid = ruma::OwnedUserId::try_from(user_id)?;
+            room.invite_user_by_id(&uid).await.map_err(FfiError::from)
         })
     }
 
@@ -4032,19 +6139,16 @@ impl Client {
 
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             let content = build_unstable_poll_content(&def)?;
             let any = AnyMessageLikeEventContent::UnstablePollStart(content.into());
 
-            let send_res = room
-                .send(any)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let send_res = room.send(any).await?;
             Ok(send_res.event_id.to_string())
         })
     }
@@ -4061,21 +6165,19 @@ impl Client {
 
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Ok(eid) = EventId::parse(&poll_event_id) else {
-                return Err(FfiError::Msg("bad poll event id".into()));
+                return Err(FfiError::invalid_input("bad poll event id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             let content = UnstablePollResponseEventContent::new(answers, eid.to_owned());
             let any = AnyMessageLikeEventContent::UnstablePollResponse(content);
 
-            room.send(any)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            room.send(any).await?;
             Ok(())
         })
     }
@@ -4089,13 +6191,13 @@ impl Client {
 
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Ok(poll_eid) = EventId::parse(&poll_event_id) else {
-                return Err(FfiError::Msg("bad poll event id".into()));
+                return Err(FfiError::invalid_input("bad poll event id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             // Minimal end, only fallback string.
@@ -4103,14 +6205,12 @@ impl Client {
 
             let any = AnyMessageLikeEventContent::UnstablePollEnd(end_content);
 
-            room.send(any)
-                .await
-                .map(|_| ())
-                .map_err(|e| FfiError::Msg(e.to_string()))
+            room.send(any).await.map(|_| ()).map_err(FfiError::from)
         })
     }
 
     /// Start sharing live location in a room for `duration_ms` milliseconds.
+    /// The share is stopped when that time is up (while this client runs).
     pub fn start_live_location(
         &self,
         room_id: String,
@@ -4119,16 +6219,26 @@ impl Client {
     ) -> Result<(), FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             room.start_live_location_share(duration_ms, description)
-                .await
-                .map(|_| ())
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .await?;
+
+            let expiry_room = room.clone();
+            let timer = RT.spawn(async move {
+                tokio::time::sleep(Duration::from_millis(duration_ms)).await;
+                if let Err(e) = expiry_room.stop_live_location_share().await {
+                    warn!("live location: stopping expired share failed: {e}");
+                }
+            });
+            if let Some(old) = self.live_location_timers.lock().unwrap().insert(rid, timer) {
+                old.abort();
+            }
+            Ok(())
         })
     }
 
@@ -4136,16 +6246,19 @@ impl Client {
     pub fn stop_live_location(&self, room_id: String) -> Result<(), FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
+            if let Some(timer) = self.live_location_timers.lock().unwrap().remove(&rid) {
+                timer.abort();
+            }
             room.stop_live_location_share()
                 .await
                 .map(|_| ())
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(|e| FfiError::from_wrapped(&e))
         })
     }
 
@@ -4153,16 +6266,16 @@ impl Client {
     pub fn send_live_location(&self, room_id: String, geo_uri: String) -> Result<(), FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             room.send_location_beacon(geo_uri)
                 .await
                 .map(|_| ())
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(|e| FfiError::from_wrapped(&e))
         })
     }
 
@@ -4186,26 +6299,100 @@ impl Client {
             let stream = observable.subscribe();
 
             use futures_util::{StreamExt, pin_mut};
+            use ruma::events::location::AssetType;
 
             pin_mut!(stream);
 
-            while let Some(event) = stream.next().await {
-                let Some(beacon_info) = event.beacon_info else {
-                    continue;
-                };
-                let info = LiveLocationShareInfo {
-                    user_id: event.user_id.to_string(),
-                    geo_uri: event.last_location.location.uri.to_string(),
-                    ts_ms: event.last_location.ts.0.into(),
-                    is_live: beacon_info.is_live(),
+            let mut shares: HashMap<String, LiveLocationShareInfo> = HashMap::new();
+            let mut expiry = tokio::time::interval(LIVE_LOCATION_EXPIRY_CHECK);
+
+            loop {
+                let changed = tokio::select! {
+                    event = stream.next() => {
+                        let Some(event) = event else { break };
+                        let user_id = event.user_id.to_string();
+                        match event.beacon_info.filter(|b| b.is_live()) {
+                            Some(beacon_info) => {
+                                let started: u64 = beacon_info.ts.0.into();
+                                let info = LiveLocationShareInfo {
+                                    user_id: user_id.clone(),
+                                    geo_uri: event.last_location.location.uri.to_string(),
+                                    ts_ms: event.last_location.ts.0.into(),
+                                    is_live: true,
+                                    description: beacon_info.description.clone(),
+                                    expires_at_ms: Some(
+                                        started + beacon_info.timeout.as_millis() as u64,
+                                    ),
+                                    is_pin: beacon_info.asset.type_ == AssetType::Pin,
+                                };
+                                shares.insert(user_id, info);
+                                true
+                            }
+                            None => shares.remove(&user_id).is_some(),
+                        }
+                    }
+                    _ = expiry.tick() => {
+                        let now = now_ms();
+                        let before = shares.len();
+                        shares.retain(|_, s| s.expires_at_ms.is_none_or(|t| t > now));
+                        shares.len() != before
+                    }
                 };
-                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
-                    obs.on_update(vec![info.clone()])
-                }));
+                if !changed {
+                    continue;
+                }
+
+                let mut snapshot: Vec<LiveLocationShareInfo> = shares.values().cloned().collect();
+                snapshot.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| obs.on_update(snapshot)));
             }
         })
     }
 
+    /// Send a one-off location pin (`m.location`). `zoom` is a map zoom level,
+    /// 0..=20.
+    pub fn send_static_location(
+        &self,
+        room_id: String,
+        geo_uri: String,
+        description: Option<String>,
+        zoom: Option<u32>,
+    ) -> Result<(), FfiError> {
+        use ruma::events::{
+            location::{AssetContent, AssetType, LocationContent, ZoomLevel},
+            room::message::LocationMessageEventContent,
+        };
+
+        if !geo_uri.starts_with("geo:") || parse_geo_uri(&geo_uri).is_none() {
+            return Err(FfiError::invalid_input(format!("bad geo URI: {geo_uri}")));
+        }
+
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
+            let body = match &description {
+                Some(d) => format!("{d} ({geo_uri})"),
+                None => format!("Location: {geo_uri}"),
+            };
+            let mut location = LocationContent::new(geo_uri.clone());
+            location.description = description;
+            location.zoom_level = zoom
+                .and_then(|z| u8::try_from(z).ok())
+                .and_then(ZoomLevel::new);
+
+            let mut content = LocationMessageEventContent::new(body, geo_uri);
+            content.location = Some(location);
+            let mut asset = AssetContent::new();
+            asset.type_ = AssetType::Pin;
+            content.asset = Some(asset);
+            content.ts = Some(ruma::MilliSecondsSinceUnixEpoch::now());
+
+            room.send_queue()
+                .send(RoomMessageEventContent::new(MessageType::Location(content)).into())
+                .await
+                .map(|_| ())
+                .map_err(FfiError::from)
+        })
+    }
+
     pub fn unobserve_live_location(&self, sub_id: u64) -> bool {
         unsub!(self, live_location_subs, sub_id)
     }
@@ -4213,38 +6400,36 @@ impl Client {
     pub fn publish_room_alias(&self, room_id: String, alias: String) -> Result<bool, FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id.as_str()) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
-            let alias_id =
-                OwnedRoomAliasId::try_from(alias).map_err(|e| FfiError::Msg(e.to_string()))?;
+            let alias_id = OwnedRoomAliasId::try_from(alias)?;
 
             room.privacy_settings()
                 .publish_room_alias_in_room_directory(alias_id.as_ref())
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
     pub fn unpublish_room_alias(&self, room_id: String, alias: String) -> Result<bool, FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id.as_str()) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
-            let alias_id =
-                OwnedRoomAliasId::try_from(alias).map_err(|e| FfiError::Msg(e.to_string()))?;
+            let alias_id = OwnedRoomAliasId::try_from(alias)?;
 
             room.privacy_settings()
                 .remove_room_alias_from_room_directory(alias_id.as_ref())
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
@@ -4256,27 +6441,27 @@ impl Client {
     ) -> Result<(), FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id.as_str()) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             let alias_opt = if let Some(a) = alias {
-                Some(OwnedRoomAliasId::try_from(a).map_err(|e| FfiError::Msg(e.to_string()))?)
+                Some(OwnedRoomAliasId::try_from(a)?)
             } else {
                 None
             };
 
             let mut alts = Vec::new();
             for s in alt_aliases {
-                alts.push(OwnedRoomAliasId::try_from(s).map_err(|e| FfiError::Msg(e.to_string()))?);
+                alts.push(OwnedRoomAliasId::try_from(s)?);
             }
 
             room.privacy_settings()
                 .update_canonical_alias(alias_opt, alts)
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
@@ -4287,10 +6472,10 @@ impl Client {
     ) -> Result<(), FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             let vs = match visibility {
@@ -4301,7 +6486,7 @@ impl Client {
             room.privacy_settings()
                 .update_room_visibility(vs)
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
@@ -4311,17 +6496,13 @@ impl Client {
     ) -> Result<RoomDirectoryVisibility, FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
-            let vis = room
-                .privacy_settings()
-                .get_room_visibility()
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let vis = room.privacy_settings().get_room_visibility().await?;
 
             Ok(match vis {
                 Visibility::Public => RoomDirectoryVisibility::Public,
@@ -4334,28 +6515,24 @@ impl Client {
     /// Add a user to the ignore list (muting them across all rooms).
     pub fn ignore_user(&self, user_id: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            let uid = user_id
-                .parse::<OwnedUserId>()
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let uid = user_id.parse::<OwnedUserId>()?;
             self.inner
                 .account()
                 .ignore_user(uid.as_ref())
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
     /// Remove a user from the ignore list.
     pub fn unignore_user(&self, user_id: String) -> Result<(), FfiError> {
         RT.block_on(async {
-            let uid = user_id
-                .parse::<OwnedUserId>()
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let uid = user_id.parse::<OwnedUserId>()?;
             self.inner
                 .account()
                 .unignore_user(uid.as_ref())
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
@@ -4365,16 +6542,13 @@ impl Client {
 
             let raw_opt = account
                 .account_data::<IgnoredUserListEventContent>()
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+                .await?;
 
             let Some(raw) = raw_opt else {
                 return Ok(Vec::new());
             };
 
-            let content = raw
-                .deserialize()
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let content = raw.deserialize()?;
 
             let users = content
                 .ignored_users
@@ -4396,9 +6570,9 @@ impl Client {
         })
     }
 
-    pub fn enable_room_encryption(&self, room_id: String) -> bool {
+    pub fn enable_room_encryption(&self, room_id: String) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            room.enable_encryption().await.is_ok()
+            room.enable_encryption().await.map_err(FfiError::from)
         })
     }
 
@@ -4410,7 +6584,10 @@ impl Client {
     ) -> Result<(), FfiError> {
         RT.block_on(async {
             let Some(me) = self.inner.user_id() else {
-                return Err(FfiError::Msg("No logged-in user".into()));
+                return Err(FfiError::UnknownToken {
+                    soft_logout: false,
+                    message: "no logged-in user".into(),
+                });
             };
 
             let presence = match state {
@@ -4426,22 +6603,16 @@ impl Client {
                 .send(req)
                 .await
                 .map(|_: set_presence_v3::Response| ())
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
     pub fn get_presence(&self, user_id: String) -> Result<PresenceInfo, FfiError> {
         RT.block_on(async {
-            let uid = user_id
-                .parse::<OwnedUserId>()
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let uid = user_id.parse::<OwnedUserId>()?;
 
             let req = get_presence_v3::Request::new(uid);
-            let resp = self
-                .inner
-                .send(req)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let resp = self.inner.send(req).await?;
 
             let presence = match resp.presence {
                 PresenceState::Online => Presence::Online,
@@ -4460,10 +6631,10 @@ impl Client {
     pub fn set_room_join_rule(&self, room_id: String, rule: RoomJoinRule) -> Result<(), FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             let join = match rule {
@@ -4477,7 +6648,7 @@ impl Client {
             room.privacy_settings()
                 .update_join_rule(join)
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
@@ -4488,10 +6659,10 @@ impl Client {
     ) -> Result<(), FfiError> {
         RT.block_on(async {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return Err(FfiError::Msg("bad room id".into()));
+                return Err(FfiError::invalid_input("bad room id"));
             };
             let Some(room) = self.inner.get_room(&rid) else {
-                return Err(FfiError::Msg("room not found".into()));
+                return Err(FfiError::not_found("room not found"));
             };
 
             let hv = match vis {
@@ -4504,7 +6675,7 @@ impl Client {
             room.privacy_settings()
                 .update_room_history_visibility(hv)
                 .await
-                .map_err(|e| FfiError::Msg(e.to_string()))
+                .map_err(FfiError::from)
         })
     }
 
@@ -4513,16 +6684,11 @@ impl Client {
     /// Returns the new room ID on success.
     pub fn upgrade_room(&self, room_id: String, new_version: String) -> Result<String, FfiError> {
         RT.block_on(async {
-            let rid = OwnedRoomId::try_from(room_id).map_err(|e| FfiError::Msg(e.to_string()))?;
-            let version = RoomVersionId::try_from(new_version.as_str())
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let rid = OwnedRoomId::try_from(room_id)?;
+            let version = RoomVersionId::try_from(new_version.as_str())?;
 
             let req = upgrade_room_v3::Request::new(rid.clone(), version);
-            let resp = self
-                .inner
-                .send(req)
-                .await
-                .map_err(|e| FfiError::Msg(e.to_string()))?;
+            let resp = self.inner.send(req).await?;
             Ok(resp.replacement_room.to_string())
         })
     }
@@ -4533,9 +6699,7 @@ impl Client {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
                 return None;
             };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return None;
-            };
+            let room = self.inner.get_room(&rid)?;
 
             let is_tombstoned = room.is_tombstoned();
             let successor = room.successor_room().map(Into::into);
@@ -4549,43 +6713,54 @@ impl Client {
         })
     }
 
-    pub fn ban_user(&self, room_id: String, user_id: String, reason: Option<String>) -> bool {
+    pub fn ban_user(
+        &self,
+        room_id: String,
+        user_id: String,
+        reason: Option<String>,
+    ) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            let Ok(uid) = OwnedUserId::try_from(user_id) else {
-                return false;
-            };
-            room.ban_user(uid.as_ref(), reason.as_deref()).await.is_ok()
+            let uid = OwnedUserId::try_from(user_id)?;
+            room.ban_user(uid.as_ref(), reason.as_deref())
+                .await
+                .map_err(FfiError::from)
         })
     }
 
-    pub fn unban_user(&self, room_id: String, user_id: String, reason: Option<String>) -> bool {
+    pub fn unban_user(
+        &self,
+        room_id: String,
+        user_id: String,
+        reason: Option<String>,
+    ) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            let Ok(uid) = OwnedUserId::try_from(user_id) else {
-                return false;
-            };
+            let uid = OwnedUserId::try_from(user_id)?;
             room.unban_user(uid.as_ref(), reason.as_deref())
                 .await
-                .is_ok()
+                .map_err(FfiError::from)
         })
     }
 
-    pub fn kick_user(&self, room_id: String, user_id: String, reason: Option<String>) -> bool {
+    pub fn kick_user(
+        &self,
+        room_id: String,
+        user_id: String,
+        reason: Option<String>,
+    ) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            let Ok(uid) = OwnedUserId::try_from(user_id) else {
-                return false;
-            };
+            let uid = OwnedUserId::try_from(user_id)?;
             room.kick_user(uid.as_ref(), reason.as_deref())
                 .await
-                .is_ok()
+                .map_err(FfiError::from)
         })
     }
 
-    pub fn invite_user(&self, room_id: String, user_id: String) -> bool {
+    pub fn invite_user(&self, room_id: String, user_id: String) -> Result<(), FfiError> {
         with_room_async!(self, room_id, |room: Room, _rid| async move {
-            let Ok(uid) = OwnedUserId::try_from(user_id) else {
-                return false;
-            };
-            room.invite_user_by_id(uid.as_ref()).await.is_ok()
+            let uid = OwnedUserId::try_from(user_id)?;
+            room.invite_user_by_id(uid.as_ref())
+                .await
+                .map_err(FfiError::from)
         })
     }
 
@@ -4595,9 +6770,7 @@ impl Client {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
                 return None;
             };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return None;
-            };
+            let room = self.inner.get_room(&rid)?;
             room.successor_room().map(Into::into)
         })
     }
@@ -4608,9 +6781,7 @@ impl Client {
             let Ok(rid) = OwnedRoomId::try_from(room_id) else {
                 return None;
             };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return None;
-            };
+            let room = self.inner.get_room(&rid)?;
             room.predecessor_room().map(Into::into)
         })
     }
@@ -4619,51 +6790,59 @@ impl Client {
         &self,
         room_id: String,
         att: AttachmentInfo,
-        body: Option<String>,
+        options: Option<AttachmentOptions>,
         progress: Option<Box<dyn ProgressObserver>>,
-    ) -> bool {
-        RT.block_on(async {
-            let Ok(rid) = OwnedRoomId::try_from(room_id) else {
-                return false;
-            };
-            let Some(room) = self.inner.get_room(&rid) else {
-                return false;
-            };
-
-            // Text shown in the timeline for this media
-            let default_caption = match att.kind {
+    ) -> Result<(), FfiError> {
+        with_room_async!(self, room_id, |room: Room, _rid| async move {
+            let default_name = match att.kind {
                 AttachmentKind::Image => "Image",
                 AttachmentKind::Video => "Video",
+                AttachmentKind::Audio if att.is_voice => "Voice message",
+                AttachmentKind::Audio => "Audio",
                 AttachmentKind::File => "File",
+                AttachmentKind::Sticker => "Sticker",
             };
-            let caption = body.unwrap_or_else(|| default_caption.to_string());
+            let filename = att
+                .filename
+                .clone()
+                .unwrap_or_else(|| default_name.to_string());
+
+            // MSC2530: with a caption, `body` is the caption and `filename` is set.
+            let (caption_body, formatted, mentions) = caption_parts(options.as_ref())?;
+            let filename_field = caption_body.is_some().then(|| filename.clone());
+            let caption = caption_body.unwrap_or(filename);
+            let reply = media_reply(options.as_ref())?;
 
             let media_source = if let Some(enc) = att.encrypted.as_ref() {
                 // Encrypted attachment: parse full EncryptedFile JSON
-                let ef: EncryptedFile = match serde_json::from_str(&enc.json) {
-                    Ok(f) => f,
-                    Err(e) => {
-                        eprintln!("send_existing_attachment: enc parse error: {e}");
-                        return false;
-                    }
-                };
+                let ef: EncryptedFile = serde_json::from_str(&enc.json)?;
                 MediaSource::Encrypted(Box::new(ef))
             } else {
                 // Plain mxc:// URL
                 MediaSource::Plain(att.mxc_uri.clone().into())
             };
 
+            // Stickers are their own event type and carry no caption or relation.
+            if matches!(att.kind, AttachmentKind::Sticker) {
+                // `m.sticker` only has a plain `url`; the key would be dropped.
+                if att.encrypted.is_some() {
+                    return Err(FfiError::invalid_input(
+                        "stickers can't be encrypted; send it as an image",
+                    ));
+                }
+                let body = att.caption.clone().unwrap_or(caption);
+                let content = sticker_content(body, att.mxc_uri.clone(), image_info(&att));
+                room.send_queue().send(content.into()).await?;
+                return Ok(());
+            }
+
             // Build MessageType based on kind + basic metadata
             let msgtype = match att.kind {
                 AttachmentKind::Image => {
-                    let mut info = ImageInfo::new();
-                    info.mimetype = att.mime.clone();
-                    info.size = att.size_bytes.and_then(UInt::new);
-                    info.width = att.width.map(UInt::from);
-                    info.height = att.height.map(UInt::from);
-
                     let mut img = ImageMessageEventContent::new(caption.clone(), media_source);
-                    img.info = Some(Box::new(info));
+                    img.info = Some(Box::new(image_info(&att)));
+                    img.filename = filename_field.clone();
+                    img.formatted = formatted.clone();
                     MessageType::Image(img)
                 }
 
@@ -4673,15 +6852,33 @@ impl Client {
                     info.size = att.size_bytes.and_then(UInt::new);
                     info.width = att.width.map(UInt::from);
                     info.height = att.height.map(UInt::from);
-                    info.duration = att
-                        .duration_ms
-                        .map(|ms| std::time::Duration::from_millis(ms));
+                    info.duration = att.duration_ms.map(std::time::Duration::from_millis);
 
                     let mut vid = VideoMessageEventContent::new(caption.clone(), media_source);
                     vid.info = Some(Box::new(info));
+                    vid.filename = filename_field.clone();
+                    vid.formatted = formatted.clone();
                     MessageType::Video(vid)
                 }
 
+                AttachmentKind::Audio => {
+                    let mut info = AudioInfo::new();
+                    info.mimetype = att.mime.clone();
+                    info.size = att.size_bytes.and_then(UInt::new);
+                    info.duration = att.duration_ms.map(std::time::Duration::from_millis);
+
+                    let mut audio = AudioMessageEventContent::new(caption.clone(), media_source);
+                    audio.info = Some(Box::new(info));
+                    audio.filename = filename_field.clone();
+                    audio.formatted = formatted.clone();
+                    if att.is_voice {
+                        audio.audio =
+                            Some(voice_details(att.duration_ms.unwrap_or(0), &att.waveform));
+                        audio.voice = Some(UnstableVoiceContentBlock::new());
+                    }
+                    MessageType::Audio(audio)
+                }
+
                 AttachmentKind::File => {
                     let mut info = FileInfo::new();
                     info.mimetype = att.mime.clone();
@@ -4689,323 +6886,2938 @@ impl Client {
 
                     let mut file = FileMessageEventContent::new(caption.clone(), media_source);
                     file.info = Some(Box::new(info));
+                    file.filename = filename_field.clone();
+                    file.formatted = formatted.clone();
                     MessageType::File(file)
                 }
-            };
 
-            let content = RoomMessageEventContent::new(msgtype);
-
-            // Reuse has no upload, but keep API symmetric: 0 → 1
-            if let Some(p) = progress.as_ref() {
-                p.on_progress(0, None);
-            }
+                AttachmentKind::Sticker => unreachable!("sent as m.sticker above"),
+            };
 
-            let res = room.send(content).await;
+            let mut content =
+                ruma::events::room::message::RoomMessageEventContentWithoutRelation::new(msgtype);
+            content.mentions = mentions;
+            // Same placement as an upload through the send queue.
+            let content = match reply {
+                Some(reply) => room.make_reply_event(content, reply).await?,
+                None => content.with_relation(None),
+            };
 
+            // Nothing to upload: the media is already on the server.
+            room.send_queue().send(content.into()).await?;
             if let Some(p) = progress {
-                p.on_progress(1, Some(1));
+                let size = att.size_bytes.unwrap_or(0);
+                p.on_progress(size, Some(size));
             }
-
-            res.is_ok()
+            Ok(())
         })
     }
 }
 
+/// A file to upload, with the attachment info already known about it, if any.
+struct MediaFile {
+    filename: String,
+    mime: Mime,
+    data: Vec<u8>,
+    info: Option<matrix_sdk::attachment::AttachmentInfo>,
+}
+
 impl Client {
-    fn wait_and_start_sas(
+    /// Queue a media upload + event on the room's send queue (persisted, so it
+    /// resumes after a restart), then forward its upload progress and send state
+    /// to `progress` and the send observers until it is sent, fails or is cancelled.
+    async fn enqueue_media(
         &self,
-        flow_id: String,
-        req: VerificationRequest,
-        obs: Arc<dyn VerificationObserver>,
-    ) {
-        let verifs = self.verifs.clone();
-        let h = RT.spawn(async move {
-            let deadline = Instant::now() + Duration::from_secs(120);
-            obs.on_phase(flow_id.clone(), SasPhase::Requested);
+        room: Room,
+        file: MediaFile,
+        progress: Option<Box<dyn ProgressObserver>>,
+        options: Option<AttachmentOptions>,
+    ) -> Result<Arc<UploadHandle>, FfiError> {
+        use matrix_sdk::send_queue::RoomSendQueueUpdate;
+        use tokio::sync::broadcast::error::RecvError;
+
+        let MediaFile {
+            filename,
+            mime,
+            data,
+            info,
+        } = file;
+        let (caption, formatted_caption, mentions) = caption_parts(options.as_ref())?;
+        let reply = media_reply(options.as_ref())?;
+
+        let queue = room.send_queue();
+        // Subscribe first so no update for this upload can be missed.
+        let (_, mut updates) = queue.subscribe().await?;
+
+        // Probe images/videos for metadata + a thumbnail, off the async workers.
+        let (data, info, thumbnail) = match info {
+            Some(info) => (data, Some(info), None),
+            None => {
+                let mime = mime.clone();
+                let prepared = tokio::task::spawn_blocking(move || prepare_media(&mime, data))
+                    .await
+                    .map_err(|e| FfiError::Msg(e.to_string()))?;
+                (prepared.data, prepared.info, prepared.thumbnail)
+            }
+        };
+
+        let txn = ruma::TransactionId::new();
+        let config = matrix_sdk::attachment::AttachmentConfig {
+            txn_id: Some(txn.clone()),
+            info,
+            thumbnail,
+            caption,
+            formatted_caption,
+            mentions,
+            reply,
+        };
+        let handle = queue.send_attachment(filename, mime, data, config).await?;
+
+        let room_id = room.room_id().to_string();
+        let txn_id = txn.to_string();
+        let tx = self.send_tx.clone();
+        let update = move |state: SendState, event_id: Option<String>, error: Option<String>| {
+            let _ = tx.send(SendUpdate {
+                room_id: room.room_id().to_string(),
+                txn_id: txn.to_string(),
+                attempts: 0,
+                state,
+                event_id,
+                error,
+            });
+        };
+        update(SendState::Enqueued, None, None);
 
+        let progress: Option<Arc<dyn ProgressObserver>> = progress.map(Arc::from);
+        let watched_txn = txn_id.clone();
+        let h = RT.spawn(async move {
+            let mut uploading = false;
             loop {
-                match req.start_sas().await {
-                    Ok(Some(sas)) => {
-                        obs.on_phase(flow_id.clone(), SasPhase::Ready);
-                        attach_sas_stream(verifs.clone(), flow_id.clone(), sas, obs.clone()).await;
+                let upd = match updates.recv().await {
+                    Ok(u) => u,
+                    Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => break,
+                };
+                match upd {
+                    RoomSendQueueUpdate::MediaUpload {
+                        related_to,
+                        progress: p,
+                        ..
+                    } if related_to.as_str() == watched_txn => {
+                        if !uploading {
+                            uploading = true;
+                            update(SendState::Sending, None, None);
+                        }
+                        if let Some(obs) = progress.as_ref() {
+                            let (sent, total) = (p.current as u64, p.total as u64);
+                            let _ = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                                obs.on_progress(sent, Some(total))
+                            }));
+                        }
+                    }
+                    RoomSendQueueUpdate::SentEvent {
+                        transaction_id,
+                        event_id,
+                    } if transaction_id.as_str() == watched_txn => {
+                        update(SendState::Sent, Some(event_id.to_string()), None);
                         break;
                     }
-                    Ok(None) => {
-                        if Instant::now() >= deadline {
-                            obs.on_error(flow_id.clone(), "SAS not ready".to_string());
+                    RoomSendQueueUpdate::SendError {
+                        transaction_id,
+                        error,
+                        is_recoverable,
+                    } if transaction_id.as_str() == watched_txn => {
+                        // Recoverable errors are retried by the queue itself.
+                        let state = if is_recoverable {
+                            SendState::Retrying
+                        } else {
+                            SendState::Failed
+                        };
+                        update(state, None, Some(error.to_string()));
+                        if !is_recoverable {
                             break;
                         }
-                        tokio::time::sleep(Duration::from_millis(800)).await;
                     }
-                    Err(e) => {
-                        if Instant::now() >= deadline {
-                            obs.on_error(flow_id.clone(), format!("SAS failed to start: {e}"));
-                            break;
-                        }
-                        tokio::time::sleep(Duration::from_millis(800)).await;
+                    RoomSendQueueUpdate::CancelledLocalEvent { transaction_id }
+                        if transaction_id.as_str() == watched_txn =>
+                    {
+                        update(SendState::Failed, None, Some("cancelled".into()));
+                        break;
                     }
+                    _ => {}
                 }
             }
         });
-        self.guards.lock().unwrap().push(h);
-    }
-}
-
-// ---------- Helpers ----------
+        // One watcher per upload; drop the finished ones so the list stays short.
+        let mut guards = self.guards.lock().unwrap();
+        guards.retain(|g| !g.is_finished());
+        guards.push(h);
+        drop(guards);
+
+        Ok(Arc::new(UploadHandle {
+            room_id,
+            txn_id,
+            handle,
+        }))
+    }
+
+    /// Cancel channel for a flow about to wait on the browser; a newer flow
+    /// replaces (and thereby detaches) the previous one.
+    fn arm_browser_auth_cancel(&self) -> tokio::sync::oneshot::Receiver<()> {
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        *self.browser_auth_cancel.lock().unwrap() = Some(tx);
+        rx
+    }
+
+    fn try_build(
+        homeserver: HomeserverRef,
+        store_dir: String,
+        passphrase: Option<String>,
+    ) -> Result<Self, FfiError> {
+        init_tracing();
 
-fn build_unstable_poll_content(
-    def: &PollDefinition,
-) -> Result<NewUnstablePollStartEventContent, FfiError> {
-    // Build answers with simple stable IDs: "a", "b", "c", ...
-    let mut answers = Vec::with_capacity(def.answers.len());
-    for (idx, text) in def.answers.iter().enumerate() {
-        let id = ((b'a' + (idx as u8)) as char).to_string();
-        answers.push(UnstablePollAnswer::new(id, text.clone()));
-    }
+        let store_dir_path = std::path::PathBuf::from(&store_dir);
+        std::fs::create_dir_all(&store_dir_path)?;
+        prepare_store_dir(&store_dir_path, passphrase.is_some())?;
 
-    let unstable_answers =
-        UnstablePollAnswers::try_from(answers).map_err(|e| FfiError::Msg(e.to_string()))?;
+        let inner = RT.block_on(async {
+            let builder = match &homeserver {
+                HomeserverRef::Url(url) => SdkClient::builder().homeserver_url(url),
+                HomeserverRef::Discover(server) => {
+                    SdkClient::builder().server_name_or_homeserver_url(server_from_input(server))
+                }
+            };
+            builder
+                .sqlite_store(&store_dir_path, passphrase.as_deref())
+                .handle_refresh_tokens()
+                .with_encryption_settings(EncryptionSettings {
+                    auto_enable_cross_signing: true,
+                    auto_enable_backups: true,
+                    backup_download_strategy: BackupDownloadStrategy::OneShot,
+                })
+                .build()
+                .await
+                .map_err(FfiError::from)
+        })?;
+        if passphrase.is_some() {
+            std::fs::write(store_encrypted_marker(&store_dir_path), b"")?;
+        }
 
-    let mut block = UnstablePollStartContentBlock::new(def.question.clone(), unstable_answers);
+        // Report `RoomSendQueueUpdate::MediaUpload` progress for attachments.
+        inner.send_queue().enable_upload_progress(true);
 
-    // Map kind + max_selections
-    block.kind = match def.kind {
-        PollKind::Disclosed => RumaPollKind::Disclosed,
-        PollKind::Undisclosed => RumaPollKind::Undisclosed,
-    };
-    block.max_selections = js_int::UInt::from(def.max_selections.max(1));
+        let (send_tx, mut send_rx) = tokio::sync::mpsc::unbounded_channel::<SendUpdate>();
+        let this = Self {
+            inner,
+            store_dir: store_dir_path,
+            store_encrypted: passphrase.is_some(),
+            guards: Mutex::new(vec![]),
+            verifs: Arc::new(Mutex::new(HashMap::new())),
+            sas_starters: Arc::new(Mutex::new(HashSet::new())),
+            timelines: Arc::new(Mutex::new(HashMap::new())),
+            search_index: Arc::new(Mutex::new(SearchIndex::default())),
+            send_observers: Arc::new(Mutex::new(HashMap::new())),
+            send_obs_counter: AtomicU64::new(0),
+            send_tx,
+            inbox: Arc::new(Mutex::new(HashMap::new())),
+            sync_service: Arc::new(Mutex::new(None)),
+            subs_counter: AtomicU64::new(0),
+            timeline_subs: Mutex::new(HashMap::new()),
+            typing_subs: Mutex::new(HashMap::new()),
+            connection_subs: Mutex::new(HashMap::new()),
+            inbox_subs: Mutex::new(HashMap::new()),
+            receipts_subs: Mutex::new(HashMap::new()),
+            room_list_subs: Mutex::new(HashMap::new()),
+            room_list_cmds: Mutex::new(HashMap::new()),
+            send_handles_by_txn: Mutex::new(HashMap::new()),
+            call_subs: Mutex::new(HashMap::new()),
+            live_location_subs: Mutex::new(HashMap::new()),
+            backup_subs: Mutex::new(HashMap::new()),
+            identity_subs: Mutex::new(HashMap::new()),
+            live_location_timers: Mutex::new(HashMap::new()),
+            downloads: Arc::new(DownloadManager::new()),
+            download_subs: Mutex::new(HashMap::new()),
+            media_proxy: Mutex::new(None),
+            url_previews: Arc::new(Mutex::new(UrlPreviewCache::default())),
+            browser_auth_cancel: Mutex::new(None),
+        };
 
-    Ok(NewUnstablePollStartEventContent::plain_text(
+        {
+            let client = this.inner.clone();
+            let timelines = this.timelines.clone();
+            let h = RT.spawn(async move {
+                if let Some(mut stream) = client.encryption().room_keys_received_stream().await {
+                    while let Some(batch) = stream.next().await {
+                        let Ok(infos) = batch else { continue };
+                        use std::collections::HashMap;
+                        let mut by_room: HashMap<OwnedRoomId, Vec<String>> = HashMap::new();
+                        for info in infos {
+                            by_room
+                                .entry(info.room_id.clone())
+                                .or_default()
+                                .push(info.session_id.clone());
+                        }
+                        for (rid, sessions) in by_room {
+                            if let Some(tl) = get_timeline_for(&client, &timelines, &rid).await {
+                                tl.retry_decryption(sessions).await;
+                            }
+                        }
+                    }
+                }
+            });
+            this.guards.lock().unwrap().push(h);
+        }
+
+        {
+            let client = this.inner.clone();
+            let index = this.search_index.clone();
+            let store_encrypted = this.store_encrypted;
+            let h = RT.spawn(async move {
+                use matrix_sdk::ruma::events::room::redaction::SyncRoomRedactionEvent;
+
+                let loaded = load_search_index(&client, store_encrypted).await;
+                {
+                    let mut guard = index.lock().unwrap();
+                    // Keep whatever a timeline observer indexed while we were loading.
+                    let fresh = std::mem::replace(&mut *guard, loaded);
+                    for doc in fresh.docs.into_values() {
+                        guard.insert(doc);
+                    }
+                }
+
+                let msg_handler = client.observe_events::<SyncRoomMessageEvent, Room>();
+                let mut msg_sub = msg_handler.subscribe();
+                let red_handler = client.observe_events::<SyncRoomRedactionEvent, Room>();
+                let mut red_sub = red_handler.subscribe();
+                let mut flush = tokio::time::interval(Duration::from_secs(30));
+
+                loop {
+                    tokio::select! {
+                        Some((ev, room)) = msg_sub.next() => {
+                            let SyncRoomMessageEvent::Original(ev) = ev else { continue };
+                            let encrypted = room
+                                .latest_encryption_state()
+                                .await
+                                .map(|s| s.is_encrypted())
+                                .unwrap_or(false);
+                            if encrypted {
+                                index_sync_message(&index, room.room_id(), &ev);
+                            }
+                        }
+                        Some((ev, _room)) = red_sub.next() => {
+                            let target = ev
+                                .as_original()
+                                .and_then(|o| o.content.redacts.clone().or_else(|| o.redacts.clone()));
+                            if let Some(target) = target {
+                                index.lock().unwrap().remove(target.as_str());
+                            }
+                        }
+                        _ = flush.tick() => {
+                            flush_search_index(&client, &index, store_encrypted).await;
+                        }
+                    }
+                }
+            });
+            this.guards.lock().unwrap().push(h);
+        }
+
+        {
+            let observers = this.send_observers.clone();
+            let h = RT.spawn(async move {
+                while let Some(upd) = send_rx.recv().await {
+                    let list: Vec<Arc<dyn SendObserver>> = {
+                        let guard = observers.lock().expect("send_observers");
+                        guard.values().cloned().collect()
+                    };
+                    for obs in list {
+                        let upd_clone = upd.clone();
+                        let _ = std::panic::catch_unwind(AssertUnwindSafe(move || {
+                            obs.on_update(upd_clone)
+                        }));
+                    }
+                }
+            });
+            this.guards.lock().unwrap().push(h);
+        }
+
+        RT.block_on(async {
+            match this.inner.whoami().await {
+                Ok(_) => {
+                    if this.sync_service.lock().unwrap().is_none()
+                        && let Ok(service) = SyncService::builder(this.inner.clone()).build().await
+                    {
+                        this.sync_service.lock().unwrap().replace(service.into());
+                    }
+                }
+                Err(_) => {
+                    if let Some(info) =
+                        load_session(&this.inner, &this.store_dir, this.store_encrypted).await
+                        && let Ok(user_id) = info.user_id.parse::<OwnedUserId>()
+                    {
+                        let meta = matrix_sdk::SessionMeta {
+                            user_id,
+                            device_id: info.device_id.clone().into(),
+                        };
+                        let tokens = SessionTokens {
+                            access_token: info.access_token.clone(),
+                            refresh_token: info.refresh_token.clone(),
+                        };
+                        let restored = match info.oauth_client_id.clone() {
+                            Some(client_id) => {
+                                let session = OAuthSession {
+                                    client_id: ClientId::new(client_id),
+                                    user: UserSession { meta, tokens },
+                                };
+                                this.inner.restore_session(session).await
+                            }
+                            None => {
+                                let session = MatrixSession { meta, tokens };
+                                this.inner.restore_session(session).await
+                            }
+                        };
+                        if restored.is_ok() {
+                            if this.sync_service.lock().unwrap().is_none()
+                                && let Ok(service) =
+                                    SyncService::builder(this.inner.clone()).build().await
+                            {
+                                this.sync_service.lock().unwrap().replace(service.into());
+                            }
+                        } else {
+                            reset_store_dir(&this.store_dir);
+                        }
+                    }
+                }
+            }
+        });
+
+        {
+            let inner = this.inner.clone();
+            let store_dir = this.store_dir.clone();
+            let encrypted = this.store_encrypted;
+            let h = RT.spawn(async move {
+                let mut session_rx = inner.subscribe_to_session_changes();
+                while let Ok(update) = session_rx.recv().await {
+                    if let matrix_sdk::SessionChange::TokensRefreshed = update {
+                        let _ = persist_session(&inner, &store_dir, encrypted).await;
+                    }
+                }
+            });
+            this.guards.lock().unwrap().push(h);
+        }
+
+        Ok(this)
+    }
+
+    /// The locally known identity, or else the one on the server.
+    async fn fetch_user_identity(
+        &self,
+        user_id: &OwnedUserId,
+    ) -> Result<Option<matrix_sdk::encryption::identities::UserIdentity>, FfiError> {
+        let enc = self.inner.encryption();
+        if let Some(identity) = enc.get_user_identity(user_id).await? {
+            return Ok(Some(identity));
+        }
+        Ok(enc.request_user_identity(user_id).await?)
+    }
+
+    /// Resolve `flow_id` for a QR flow: claim it from the SAS starter,
+    /// accept it if it is incoming and wait until both sides are ready.
+    async fn qr_request(
+        &self,
+        flow_id: &str,
+        other_user_id: Option<String>,
+    ) -> Result<VerificationRequest, FfiError> {
+        let user = match other_user_id {
+            Some(uid) => OwnedUserId::try_from(uid)?,
+            None => match self.inbox.lock().unwrap().get(flow_id) {
+                Some((u, _)) => u.clone(),
+                None => self
+                    .inner
+                    .user_id()
+                    .ok_or_else(|| FfiError::UnknownToken {
+                        soft_logout: false,
+                        message: "no logged-in user".into(),
+                    })?
+                    .to_owned(),
+            },
+        };
+        let req = self
+            .inner
+            .encryption()
+            .get_verification_request(&user, flow_id)
+            .await
+            .ok_or_else(|| FfiError::not_found("verification request not found"))?;
+        self.sas_starters.lock().unwrap().remove(flow_id);
+
+        if !req.we_started() && matches!(req.state(), VerificationRequestState::Requested { .. }) {
+            req.accept().await?;
+        }
+        let deadline = Instant::now() + Duration::from_secs(120);
+        while !req.is_ready() {
+            if req.is_done() || req.is_cancelled() || Instant::now() >= deadline {
+                return Err(FfiError::Msg("verification request not ready".into()));
+            }
+            tokio::time::sleep(Duration::from_millis(300)).await;
+        }
+        Ok(req)
+    }
+
+    fn wait_and_start_sas(
+        &self,
+        flow_id: String,
+        req: VerificationRequest,
+        obs: Arc<dyn VerificationObserver>,
+    ) {
+        let verifs = self.verifs.clone();
+        let sas_starters = self.sas_starters.clone();
+        sas_starters.lock().unwrap().insert(flow_id.clone());
+        let h = RT.spawn(async move {
+            let deadline = Instant::now() + Duration::from_secs(120);
+            obs.on_phase(flow_id.clone(), SasPhase::Requested);
+
+            loop {
+                if !sas_starters.lock().unwrap().contains(&flow_id) {
+                    info!("wait_and_start_sas: flow_id={} moved to QR", flow_id);
+                    break;
+                }
+                // The other side may have scanned our code or started SAS itself.
+                if let VerificationRequestState::Transitioned { verification } = req.state() {
+                    match verification {
+                        Verification::SasV1(sas) => {
+                            obs.on_phase(flow_id.clone(), SasPhase::Ready);
+                            attach_sas_stream(verifs.clone(), flow_id.clone(), sas, obs.clone())
+                                .await;
+                        }
+                        Verification::QrV1(qr) => {
+                            attach_qr_stream(verifs.clone(), flow_id.clone(), qr, obs.clone())
+                                .await;
+                        }
+                        _ => {}
+                    }
+                    break;
+                }
+                match req.start_sas().await {
+                    Ok(Some(sas)) => {
+                        obs.on_phase(flow_id.clone(), SasPhase::Ready);
+                        attach_sas_stream(verifs.clone(), flow_id.clone(), sas, obs.clone()).await;
+                        break;
+                    }
+                    Ok(None) => {
+                        if Instant::now() >= deadline {
+                            obs.on_error(flow_id.clone(), "SAS not ready".to_string());
+                            break;
+                        }
+                        tokio::time::sleep(Duration::from_millis(800)).await;
+                    }
+                    Err(e) => {
+                        if Instant::now() >= deadline {
+                            obs.on_error(flow_id.clone(), format!("SAS failed to start: {e}"));
+                            break;
+                        }
+                        tokio::time::sleep(Duration::from_millis(800)).await;
+                    }
+                }
+            }
+            sas_starters.lock().unwrap().remove(&flow_id);
+        });
+        self.guards.lock().unwrap().push(h);
+    }
+}
+
+/// A queued attachment send. Dropping it does not cancel the upload.
+#[derive(Object)]
+pub struct UploadHandle {
+    room_id: String,
+    txn_id: String,
+    handle: matrix_sdk::send_queue::SendHandle,
+}
+
+#[export]
+impl UploadHandle {
+    pub fn room_id(&self) -> String {
+        self.room_id.clone()
+    }
+
+    /// Transaction id of the event, as reported in `SendUpdate`s.
+    pub fn txn_id(&self) -> String {
+        self.txn_id.clone()
+    }
+
+    /// Cancel the upload and drop the pending event. Returns false when it is
+    /// too late (already sent).
+    pub fn cancel(&self) -> bool {
+        RT.block_on(async { self.handle.abort().await.unwrap_or(false) })
+    }
+}
+
+/// A media download started by `download_media`. Dropping it does not cancel it.
+#[derive(Object)]
+pub struct DownloadHandle {
+    job: Arc<DownloadJob>,
+    /// Where this request wants the file; may differ from the shared job's path.
+    path: PathBuf,
+    manager: Arc<DownloadManager>,
+}
+
+#[export]
+impl DownloadHandle {
+    pub fn id(&self) -> u64 {
+        self.job.id
+    }
+
+    pub fn info(&self) -> DownloadInfo {
+        self.job.info.lock().unwrap().clone()
+    }
+
+    /// Cancel the download, for every request sharing it. The partial file is
+    /// kept so a later request resumes it.
+    pub fn cancel(&self) -> bool {
+        self.manager.cancel(self.job.id)
+    }
+
+    /// Block until the download finishes.
+    pub fn wait(&self) -> Result<DownloadResult, FfiError> {
+        RT.block_on(self.job.finished())?;
+        // Only missing when copying the shared download here failed.
+        let bytes = std::fs::metadata(&self.path)
+            .map_err(|e| FfiError::Store {
+                message: format!("download to {}: {e}", self.path.display()),
+            })?
+            .len();
+        Ok(DownloadResult {
+            path: self.path.to_string_lossy().to_string(),
+            bytes,
+        })
+    }
+}
+
+/// Default number of media downloads running at once.
+const MAX_CONCURRENT_DOWNLOADS: usize = 3;
+
+/// Progress updates are published to the download queue at most every this many bytes.
+const DOWNLOAD_PUBLISH_STEP: u64 = 256 * 1024;
+
+struct DownloadJob {
+    id: u64,
+    /// The mxc URI, plus the size for thumbnails.
+    key: String,
+    path: PathBuf,
+    /// Other destinations asked for the same media while it was in flight.
+    copies: Mutex<Vec<PathBuf>>,
+    observers: Mutex<Vec<Arc<dyn ProgressObserver>>>,
+    info: Mutex<DownloadInfo>,
+    done: tokio::sync::watch::Sender<Option<Result<u64, FfiError>>>,
+    task: Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+impl DownloadJob {
+    async fn finished(&self) -> Result<u64, FfiError> {
+        let mut rx = self.done.subscribe();
+        match rx.wait_for(Option::is_some).await {
+            Ok(r) => r
+                .clone()
+                .unwrap_or_else(|| Err(FfiError::Msg("download dropped".into()))),
+            Err(_) => Err(FfiError::Msg("download dropped".into())),
+        }
+    }
+}
+
+/// Streams media to `<path>.part`, decrypting on the fly, and renames it when
+/// complete. Jobs are keyed by mxc URI (and thumbnail size) so concurrent
+/// requests share one transfer.
+struct DownloadManager {
+    jobs: Mutex<HashMap<String, Arc<DownloadJob>>>,
+    counter: AtomicU64,
+    permits: Arc<tokio::sync::Semaphore>,
+    limit: Mutex<usize>,
+    updates: tokio::sync::broadcast::Sender<DownloadInfo>,
+}
+
+impl DownloadManager {
+    fn new() -> Self {
+        let (updates, _) = tokio::sync::broadcast::channel(256);
+        Self {
+            jobs: Mutex::new(HashMap::new()),
+            counter: AtomicU64::new(0),
+            permits: Arc::new(tokio::sync::Semaphore::new(MAX_CONCURRENT_DOWNLOADS)),
+            limit: Mutex::new(MAX_CONCURRENT_DOWNLOADS),
+            updates,
+        }
+    }
+
+    fn new_job(
+        &self,
+        key: String,
+        mxc_uri: String,
+        path: PathBuf,
+        state: DownloadState,
+    ) -> DownloadJob {
+        let id = self.counter.fetch_add(1, Ordering::Relaxed).wrapping_add(1);
+        let info = DownloadInfo {
+            id,
+            mxc_uri: mxc_uri.clone(),
+            path: path.to_string_lossy().to_string(),
+            state,
+            downloaded: 0,
+            total: None,
+            error: None,
+        };
+        DownloadJob {
+            id,
+            key,
+            path,
+            copies: Mutex::new(vec![]),
+            observers: Mutex::new(vec![]),
+            info: Mutex::new(info),
+            done: tokio::sync::watch::Sender::new(None),
+            task: Mutex::new(None),
+        }
+    }
+
+    fn publish(&self, job: &DownloadJob, f: impl FnOnce(&mut DownloadInfo)) {
+        let info = {
+            let mut guard = job.info.lock().unwrap();
+            f(&mut guard);
+            guard.clone()
+        };
+        let _ = self.updates.send(info);
+    }
+
+    fn snapshot(&self) -> Vec<DownloadInfo> {
+        let mut out: Vec<DownloadInfo> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .map(|j| j.info.lock().unwrap().clone())
+            .collect();
+        out.sort_by_key(|i| i.id);
+        out
+    }
+
+    fn set_limit(&self, max: usize) {
+        let max = max.max(1);
+        let mut limit = self.limit.lock().unwrap();
+        if max > *limit {
+            self.permits.add_permits(max - *limit);
+        } else if max < *limit {
+            // Take the surplus out of circulation as running downloads release it.
+            let permits = self.permits.clone();
+            let surplus = (*limit - max) as u32;
+            RT.spawn(async move {
+                if let Ok(p) = permits.acquire_many_owned(surplus).await {
+                    p.forget();
+                }
+            });
+        }
+        *limit = max;
+    }
+
+    /// An already finished download, for files found on disk.
+    fn completed(&self, mxc_uri: String, path: PathBuf, bytes: u64) -> Arc<DownloadJob> {
+        let job = self.new_job(mxc_uri.clone(), mxc_uri, path, DownloadState::Completed);
+        {
+            let mut info = job.info.lock().unwrap();
+            info.downloaded = bytes;
+            info.total = Some(bytes);
+        }
+        job.done.send_replace(Some(Ok(bytes)));
+        Arc::new(job)
+    }
+
+    fn start(
+        self: &Arc<Self>,
+        client: &SdkClient,
+        mxc_uri: String,
+        source: MediaSource,
+        thumbnail: Option<ThumbnailSize>,
+        path: PathBuf,
+        progress: Option<Arc<dyn ProgressObserver>>,
+    ) -> Arc<DownloadJob> {
+        let key = download_key(&mxc_uri, thumbnail);
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            if let Some(job) = jobs.get(&key) {
+                if job.path != path {
+                    let mut copies = job.copies.lock().unwrap();
+                    if !copies.contains(&path) {
+                        copies.push(path);
+                    }
+                }
+                if let Some(p) = progress {
+                    job.observers.lock().unwrap().push(p);
+                }
+                return job.clone();
+            }
+            let job = Arc::new(self.new_job(key.clone(), mxc_uri, path, DownloadState::Queued));
+            job.observers.lock().unwrap().extend(progress);
+            jobs.insert(key, job.clone());
+            job
+        };
+        let _ = self.updates.send(job.info.lock().unwrap().clone());
+
+        let this = self.clone();
+        let client = client.clone();
+        let j = job.clone();
+        let h = RT.spawn(async move {
+            let result = match this.permits.clone().acquire_owned().await {
+                Ok(_permit) => {
+                    this.publish(&j, |i| i.state = DownloadState::Downloading);
+                    this.fetch(&client, &source, thumbnail, &j).await
+                }
+                Err(_) => Err(FfiError::Msg("download manager closed".into())),
+            };
+            if result.is_ok() {
+                let copies = j.copies.lock().unwrap().clone();
+                for copy in copies {
+                    if let Some(dir) = copy.parent() {
+                        let _ = tokio::fs::create_dir_all(dir).await;
+                    }
+                    if let Err(e) = tokio::fs::copy(&j.path, &copy).await {
+                        warn!("download copy to {} failed: {e}", copy.display());
+                        // Leave nothing behind, so the handle for this path fails.
+                        let _ = tokio::fs::remove_file(&copy).await;
+                    }
+                }
+            }
+            this.finish(&j, result);
+        });
+        *job.task.lock().unwrap() = Some(h);
+        job
+    }
+
+    fn finish(&self, job: &DownloadJob, result: Result<u64, FfiError>) {
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            if jobs.get(&job.key).is_some_and(|j| j.id == job.id) {
+                jobs.remove(&job.key);
+            }
+        }
+        self.publish(job, |i| match &result {
+            Ok(bytes) => {
+                i.state = DownloadState::Completed;
+                i.downloaded = *bytes;
+                i.total = Some(*bytes);
+            }
+            Err(e) => {
+                i.state = DownloadState::Failed;
+                i.error = Some(e.to_string());
+            }
+        });
+        job.done.send_replace(Some(result));
+    }
+
+    fn cancel(&self, id: u64) -> bool {
+        let job = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let Some(key) = jobs
+                .iter()
+                .find(|(_, j)| j.id == id)
+                .map(|(k, _)| k.clone())
+            else {
+                return false;
+            };
+            jobs.remove(&key)
+        };
+        let Some(job) = job else { return false };
+        Self::abort_job(&job);
+        self.publish(&job, |i| i.state = DownloadState::Cancelled);
+        job.done
+            .send_replace(Some(Err(FfiError::Msg("download cancelled".into()))));
+        true
+    }
+
+    fn abort_all(&self) {
+        let jobs: Vec<_> = self.jobs.lock().unwrap().drain().map(|(_, j)| j).collect();
+        for job in jobs {
+            Self::abort_job(&job);
+            self.publish(&job, |i| i.state = DownloadState::Cancelled);
+            job.done
+                .send_replace(Some(Err(FfiError::Msg("download cancelled".into()))));
+        }
+    }
+
+    fn abort_job(job: &DownloadJob) {
+        if let Some(h) = job.task.lock().unwrap().take() {
+            h.abort();
+        }
+    }
+
+    /// GET the media (or a thumbnail of it), resuming from `offset` with a Range
+    /// request. The request is built by ruma, so the authenticated endpoints are
+    /// used when the server supports them; an expired token is refreshed once.
+    async fn request(
+        client: &SdkClient,
+        mxc: &OwnedMxcUri,
+        thumbnail: Option<ThumbnailSize>,
+        mut offset: u64,
+    ) -> Result<matrix_sdk::reqwest::Response, FfiError> {
+        use matrix_sdk::reqwest::{StatusCode, header};
+
+        let versions = client.supported_versions().await?;
+        let mut refreshed = false;
+        loop {
+            let mut request = media_request(client, &versions, mxc, thumbnail)?;
+            if offset > 0 {
+                let range = header::HeaderValue::try_from(format!("bytes={offset}-"))
+                    .map_err(|e| FfiError::Msg(e.to_string()))?;
+                request.headers_mut().insert(header::RANGE, range);
+            }
+            let resp = client
+                .http_client()
+                .execute(request)
+                .await
+                .map_err(reqwest_error)?;
+
+            let status = resp.status();
+            if status.is_success() {
+                return Ok(resp);
+            }
+            if offset > 0 && status == StatusCode::RANGE_NOT_SATISFIABLE {
+                // Partial file is stale or already complete: fetch it whole.
+                offset = 0;
+                continue;
+            }
+            let body = resp.text().await.unwrap_or_default();
+            let err = http_status_error(status, body);
+            if !refreshed
+                && matches!(err, FfiError::UnknownToken { .. })
+                && client.refresh_access_token().await.is_ok()
+            {
+                refreshed = true;
+                continue;
+            }
+            return Err(err);
+        }
+    }
+
+    /// Stream the media (or a thumbnail of it) into `<path>.part`, resuming what
+    /// an earlier attempt left there, and rename it into place when complete.
+    /// For encrypted media `.part` keeps the ciphertext, so it can be resumed,
+    /// while `AttachmentDecryptor` writes the plaintext next to it as it arrives.
+    async fn fetch(
+        &self,
+        client: &SdkClient,
+        source: &MediaSource,
+        thumbnail: Option<ThumbnailSize>,
+        job: &DownloadJob,
+    ) -> Result<u64, FfiError> {
+        use matrix_sdk::reqwest::StatusCode;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mxc, encryption) = match source {
+            MediaSource::Plain(uri) => (uri, None),
+            MediaSource::Encrypted(file) => (
+                &file.url,
+                Some(matrix_sdk_crypto::MediaEncryptionInfo::from(
+                    (**file).clone(),
+                )),
+            ),
+        };
+        // The server cannot thumbnail what it cannot read.
+        let thumbnail = thumbnail.filter(|_| encryption.is_none());
+
+        let part = partial_path(&job.path);
+        if let Some(dir) = job.path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        // Thumbnails are generated per request, so only full files resume.
+        let mut have = match thumbnail {
+            Some(_) => 0,
+            None => tokio::fs::metadata(&part)
+                .await
+                .map(|m| m.len())
+                .unwrap_or(0),
+        };
+
+        let mut resp = match Self::request(client, mxc, thumbnail, have).await {
+            // No thumbnail for this media (not an image, too small…): use the file.
+            Err(FfiError::NotFound { .. } | FfiError::InvalidInput { .. })
+                if thumbnail.is_some() =>
+            {
+                Self::request(client, mxc, None, 0).await?
+            }
+            other => other?,
+        };
+        if have > 0 && resp.status() != StatusCode::PARTIAL_CONTENT {
+            // Range was ignored: start over.
+            have = 0;
+        }
+        let total = resp.content_length().map(|len| len + have);
+
+        let plain = decrypted_partial_path(&job.path);
+        let decrypt = encryption.map(|info| {
+            let (tx, rx) = tokio::sync::mpsc::channel::<Vec<u8>>(16);
+            let out = plain.clone();
+            let task = tokio::task::spawn_blocking(move || decrypt_to_file(rx, info, &out));
+            (tx, task)
+        });
+        // The decryptor has to see the ciphertext from the start.
+        if let Some((tx, _)) = &decrypt
+            && have > 0
+        {
+            let mut file = tokio::fs::File::open(&part).await?.take(have);
+            loop {
+                let mut buf = vec![0u8; 64 * 1024];
+                let n = file.read(&mut buf).await?;
+                if n == 0 {
+                    break;
+                }
+                buf.truncate(n);
+                if tx.send(buf).await.is_err() {
+                    break;
+                }
+            }
+        }
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(have > 0)
+            .truncate(have == 0)
+            .open(&part)
+            .await?;
+
+        let mut done = have;
+        let mut published = done;
+        self.publish(job, |i| {
+            i.downloaded = done;
+            i.total = total;
+        });
+
+        while let Some(chunk) = resp.chunk().await.map_err(reqwest_error)? {
+            file.write_all(&chunk).await?;
+            if let Some((tx, _)) = &decrypt {
+                // A failed decryptor reports its error once the download ends.
+                let _ = tx.send(chunk.to_vec()).await;
+            }
+            done += chunk.len() as u64;
+
+            let observers = job.observers.lock().unwrap().clone();
+            for obs in observers {
+                let _ = std::panic::catch_unwind(AssertUnwindSafe(|| obs.on_progress(done, total)));
+            }
+            if done - published >= DOWNLOAD_PUBLISH_STEP {
+                published = done;
+                self.publish(job, |i| i.downloaded = done);
+            }
+        }
+        file.flush().await?;
+        drop(file);
+
+        match decrypt {
+            Some((tx, task)) => {
+                drop(tx);
+                let decrypted = task
+                    .await
+                    .map_err(|e| FfiError::Msg(format!("decrypt task: {e}")))?;
+                if let Err(e) = decrypted {
+                    let _ = tokio::fs::remove_file(&part).await;
+                    let _ = tokio::fs::remove_file(&plain).await;
+                    return Err(e);
+                }
+                tokio::fs::rename(&plain, &job.path).await?;
+                let _ = tokio::fs::remove_file(&part).await;
+            }
+            None => tokio::fs::rename(&part, &job.path).await?,
+        }
+        Ok(done)
+    }
+}
+
+/// The HTTP request for a media download (or a thumbnail), on the authenticated
+/// endpoints when the server supports them.
+#[allow(deprecated)]
+fn media_request(
+    client: &SdkClient,
+    versions: &ruma::api::SupportedVersions,
+    mxc: &OwnedMxcUri,
+    thumbnail: Option<ThumbnailSize>,
+) -> Result<matrix_sdk::reqwest::Request, FfiError> {
+    use ruma::api::client::authenticated_media::{get_content, get_content_thumbnail};
+    use ruma::api::client::media::{self, get_content_thumbnail::v3::Method};
+    use ruma::api::{OutgoingRequest, SendAccessToken};
+
+    let base = client.homeserver().to_string();
+    let token = client.access_token();
+    let access = token
+        .as_deref()
+        .map_or(SendAccessToken::None, SendAccessToken::IfRequired);
+    let authenticated = get_content::v1::Request::is_supported(versions);
+
+    let http = match thumbnail {
+        Some(t) => {
+            let (width, height) = (UInt::from(t.width), UInt::from(t.height));
+            let method = Some(if t.crop { Method::Crop } else { Method::Scale });
+            if authenticated {
+                let mut req = get_content_thumbnail::v1::Request::from_uri(mxc, width, height)?;
+                req.method = method;
+                req.try_into_http_request::<Vec<u8>>(&base, access, versions)
+            } else {
+                let mut req =
+                    media::get_content_thumbnail::v3::Request::from_url(mxc, width, height)?;
+                req.method = method;
+                req.try_into_http_request::<Vec<u8>>(&base, access, versions)
+            }
+        }
+        None if authenticated => get_content::v1::Request::from_uri(mxc)?
+            .try_into_http_request::<Vec<u8>>(&base, access, versions),
+        None => media::get_content::v3::Request::from_url(mxc)?
+            .try_into_http_request::<Vec<u8>>(&base, access, versions),
+    }
+    .map_err(|e| FfiError::invalid_input(format!("media request: {e}")))?;
+
+    matrix_sdk::reqwest::Request::try_from(http).map_err(reqwest_error)
+}
+
+fn reqwest_error(e: matrix_sdk::reqwest::Error) -> FfiError {
+    FfiError::Network {
+        message: e.to_string(),
+    }
+}
+
+/// Map a failed media response to an error, using the Matrix `errcode` when present.
+fn http_status_error(status: matrix_sdk::reqwest::StatusCode, body: String) -> FfiError {
+    use ruma::api::client::error::StandardErrorBody;
+
+    match serde_json::from_str::<StandardErrorBody>(&body) {
+        Ok(err) => FfiError::from_api_kind(&err.kind, err.message),
+        Err(_) => {
+            let message = format!("HTTP {status}");
+            match status.as_u16() {
+                401 => FfiError::UnknownToken {
+                    soft_logout: false,
+                    message,
+                },
+                403 => FfiError::Forbidden { message },
+                404 => FfiError::NotFound { message },
+                429 => FfiError::RateLimited {
+                    retry_after_ms: None,
+                    message,
+                },
+                400..=499 => FfiError::InvalidInput { message },
+                _ => FfiError::Network { message },
+            }
+        }
+    }
+}
+
+/// Blocking `Read` over the chunks of a download, sent from the async side.
+/// Reads 0 once the sender is dropped.
+struct ChunkReader {
+    rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl std::io::Read for ChunkReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Decrypt the ciphertext chunks from `rx` into `out`. The hash is checked when
+/// the sender is dropped, so a truncated download fails here too.
+fn decrypt_to_file(
+    rx: tokio::sync::mpsc::Receiver<Vec<u8>>,
+    info: matrix_sdk_crypto::MediaEncryptionInfo,
+    out: &Path,
+) -> Result<(), FfiError> {
+    use std::io::Write;
+
+    let mut reader = ChunkReader {
+        rx,
+        chunk: Vec::new(),
+        pos: 0,
+    };
+    let mut decryptor =
+        matrix_sdk_crypto::AttachmentDecryptor::new(&mut reader, info).map_err(|e| {
+            FfiError::Crypto {
+                message: format!("attachment: {e}"),
+            }
+        })?;
+    let mut file = std::io::BufWriter::new(std::fs::File::create(out)?);
+    std::io::copy(&mut decryptor, &mut file).map_err(|e| match e.kind() {
+        // What `AttachmentDecryptor` reports on a hash mismatch.
+        std::io::ErrorKind::Other => FfiError::Crypto {
+            message: format!("attachment: {e}"),
+        },
+        _ => FfiError::from(e),
+    })?;
+    file.flush()?;
+    Ok(())
+}
+
+struct MediaProxy {
+    base_url: String,
+    ctx: Arc<MediaProxyCtx>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+struct MediaProxyCtx {
+    client: SdkClient,
+    downloads: Arc<DownloadManager>,
+    dir: PathBuf,
+    /// `/<secret>/mxc/`; requests outside it are refused.
+    prefix: String,
+    /// Decryption info for encrypted media, by mxc URI.
+    keys: Mutex<MediaKeys>,
+}
+
+/// Upper bound on remembered media keys; the least recently used go first.
+const MEDIA_PROXY_KEYS_MAX: usize = 2048;
+
+#[derive(Default)]
+struct MediaKeys {
+    entries: HashMap<String, (u64, EncryptedFile)>,
+    seq: u64,
+}
+
+impl MediaKeys {
+    fn get(&mut self, mxc_uri: &str) -> Option<EncryptedFile> {
+        self.seq += 1;
+        let (used, file) = self.entries.get_mut(mxc_uri)?;
+        *used = self.seq;
+        Some(file.clone())
+    }
+
+    fn insert(&mut self, mxc_uri: String, file: EncryptedFile) {
+        self.seq += 1;
+        self.entries.insert(mxc_uri, (self.seq, file));
+        if self.entries.len() > MEDIA_PROXY_KEYS_MAX {
+            // Drop a quarter at once so this doesn't sort on every insert.
+            let mut by_use: Vec<(u64, String)> = self
+                .entries
+                .iter()
+                .map(|(k, (used, _))| (*used, k.clone()))
+                .collect();
+            by_use.sort();
+            let excess = self.entries.len() - MEDIA_PROXY_KEYS_MAX * 3 / 4;
+            for (_, k) in by_use.into_iter().take(excess) {
+                self.entries.remove(&k);
+            }
+        }
+    }
+}
+
+/// Keeps several accounts side by side, each with its own `store_dir` under
+/// `base_dir/accounts/<account_id>` and its own `Client`.
+#[derive(Object)]
+pub struct AccountManager {
+    base_dir: PathBuf,
+    index: Mutex<AccountIndex>,
+    clients: Mutex<HashMap<String, Arc<Client>>>,
+    key_provider: Option<Arc<dyn StoreKeyProvider>>,
+}
+
+#[export]
+impl AccountManager {
+    /// `key_provider`, when set, supplies each account's store passphrase.
+    #[uniffi::constructor]
+    pub fn new(base_dir: String, key_provider: Option<Box<dyn StoreKeyProvider>>) -> Self {
+        init_tracing();
+
+        let base_dir = PathBuf::from(base_dir);
+        ensure_dir(&base_dir);
+        let index = std::fs::read_to_string(accounts_file(&base_dir))
+            .ok()
+            .and_then(|txt| serde_json::from_str::<AccountIndex>(&txt).ok())
+            .unwrap_or_default();
+
+        Self {
+            base_dir,
+            index: Mutex::new(index),
+            clients: Mutex::new(HashMap::new()),
+            key_provider: key_provider.map(Arc::from),
+        }
+    }
+
+    /// All known accounts, with `user_id` refreshed from any live client.
+    pub fn list_accounts(&self) -> Vec<AccountInfo> {
+        self.refresh_user_ids();
+        self.index.lock().unwrap().accounts.clone()
+    }
+
+    /// Register a new account slot with its own store directory. `homeserver_url`
+    /// may also be a bare server name or MXID (see `discover_server`).
+    /// The returned account is not logged in yet; use `client()` to log in.
+    pub fn add_account(&self, homeserver_url: String) -> AccountInfo {
+        let account_id = uuid::Uuid::new_v4().simple().to_string();
+        let store_dir = self.base_dir.join("accounts").join(&account_id);
+        ensure_dir(&store_dir);
+
+        let info = AccountInfo {
+            account_id,
+            homeserver_url,
+            user_id: None,
+            store_dir: store_dir.to_string_lossy().to_string(),
+        };
+        {
+            let mut idx = self.index.lock().unwrap();
+            idx.accounts.push(info.clone());
+            if idx.active.is_none() {
+                idx.active = Some(info.account_id.clone());
+            }
+        }
+        self.persist();
+        info
+    }
+
+    /// Return the `Client` for an account, creating it on first use.
+    pub fn client(&self, account_id: String) -> Result<Arc<Client>, FfiError> {
+        if let Some(c) = self.clients.lock().unwrap().get(&account_id).cloned() {
+            return Ok(c);
+        }
+        let info = self
+            .index
+            .lock()
+            .unwrap()
+            .accounts
+            .iter()
+            .find(|a| a.account_id == account_id)
+            .cloned()
+            .ok_or_else(|| FfiError::not_found("unknown account"))?;
+
+        let passphrase = self
+            .key_provider
+            .as_ref()
+            .and_then(|p| p.store_passphrase(info.store_dir.clone()));
+        let client = Arc::new(Client::try_build(
+            HomeserverRef::Discover(info.homeserver_url),
+            info.store_dir,
+            passphrase,
+        )?);
+        let client = self
+            .clients
+            .lock()
+            .unwrap()
+            .entry(account_id)
+            .or_insert(client)
+            .clone();
+        Ok(client)
+    }
+
+    pub fn active_account(&self) -> Option<AccountInfo> {
+        self.refresh_user_ids();
+        let idx = self.index.lock().unwrap();
+        let active = idx.active.as_ref()?;
+        idx.accounts
+            .iter()
+            .find(|a| &a.account_id == active)
+            .cloned()
+    }
+
+    /// Make `account_id` the active account and return its client.
+    /// Other clients keep running so their notifications still arrive.
+    pub fn switch_account(&self, account_id: String) -> Result<Arc<Client>, FfiError> {
+        {
+            let mut idx = self.index.lock().unwrap();
+            if !idx.accounts.iter().any(|a| a.account_id == account_id) {
+                return Err(FfiError::not_found("unknown account"));
+            }
+            idx.active = Some(account_id.clone());
+        }
+        self.persist();
+        self.client(account_id)
+    }
+
+    /// Log out (if logged in), stop the client and delete the account's store.
+    pub fn remove_account(&self, account_id: String) -> bool {
+        let info = {
+            let mut idx = self.index.lock().unwrap();
+            let Some(pos) = idx.accounts.iter().position(|a| a.account_id == account_id) else {
+                return false;
+            };
+            let info = idx.accounts.remove(pos);
+            if idx.active.as_deref() == Some(account_id.as_str()) {
+                idx.active = idx.accounts.first().map(|a| a.account_id.clone());
+            }
+            info
+        };
+        self.persist();
+
+        if let Some(client) = self.clients.lock().unwrap().remove(&account_id) {
+            if client.is_logged_in() {
+                // The account goes either way; a failed server logout only
+                // leaves a stale device behind.
+                let _ = client.logout();
+            } else {
+                client.shutdown();
+            }
+        }
+        let _ = std::fs::remove_dir_all(&info.store_dir);
+        true
+    }
+}
+
+impl AccountManager {
+    fn refresh_user_ids(&self) {
+        let clients = self.clients.lock().unwrap().clone();
+        let mut changed = false;
+        {
+            let mut idx = self.index.lock().unwrap();
+            for acc in idx.accounts.iter_mut() {
+                if let Some(uid) = clients.get(&acc.account_id).and_then(|c| c.whoami())
+                    && acc.user_id.as_deref() != Some(uid.as_str())
+                {
+                    acc.user_id = Some(uid);
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.persist();
+        }
+    }
+
+    fn persist(&self) {
+        let txt = {
+            let idx = self.index.lock().unwrap();
+            serde_json::to_string(&*idx).unwrap_or_default()
+        };
+        let _ = std::fs::write(accounts_file(&self.base_dir), txt);
+    }
+}
+
+/// Resolve a server name, homeserver URL or MXID and report what the server
+/// supports, so the login screen can adapt before a `Client` is created.
+#[export]
+pub fn discover_server(server: String) -> Result<ServerInfo, FfiError> {
+    init_tracing();
+    RT.block_on(async {
+        let client = SdkClient::builder()
+            .server_name_or_homeserver_url(server_from_input(&server))
+            .build()
+            .await?;
+
+        let versions = client
+            .send(ruma::api::client::discovery::get_supported_versions::Request::new())
+            .await
+            .map(|r| r.versions)
+            .unwrap_or_default();
+        let login_methods = fetch_login_methods(&client).await?;
+
+        Ok(ServerInfo {
+            homeserver_url: client.homeserver().to_string(),
+            supported_versions: versions,
+            login_methods,
+        })
+    })
+}
+
+// ---------- Helpers ----------
+
+/// Media source of an attachment, encrypted when it carries an `EncFile`.
+fn attachment_source(att: &AttachmentInfo) -> Result<MediaSource, FfiError> {
+    Ok(match att.encrypted.as_ref() {
+        Some(enc) => MediaSource::Encrypted(Box::new(serde_json::from_str(&enc.json)?)),
+        None => MediaSource::Plain(att.mxc_uri.clone().into()),
+    })
+}
+
+/// Key of a download: the mxc URI, plus the size for thumbnails.
+fn download_key(mxc_uri: &str, thumbnail: Option<ThumbnailSize>) -> String {
+    match thumbnail {
+        Some(t) => format!(
+            "{mxc_uri}#{}x{}{}",
+            t.width,
+            t.height,
+            if t.crop { "c" } else { "s" }
+        ),
+        None => mxc_uri.to_owned(),
+    }
+}
+
+/// Cached copy of media (or of a thumbnail of it), downloaded if missing.
+async fn resolve_media_file(
+    client: &SdkClient,
+    downloads: &Arc<DownloadManager>,
+    dir: &Path,
+    mxc_uri: String,
+    encrypted: Option<EncryptedFile>,
+    thumbnail: Option<ThumbnailSize>,
+) -> Result<PathBuf, FfiError> {
+    let thumbnail = thumbnail.filter(|_| encrypted.is_none());
+    let key = download_key(&mxc_uri, thumbnail);
+    let hash = blake3::hash(key.as_bytes()).to_hex();
+    let path = dir.join(format!("m_{}", &hash[..32]));
+    if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        return Ok(path);
+    }
+
+    let source = match encrypted {
+        Some(file) => MediaSource::Encrypted(Box::new(file)),
+        None => MediaSource::Plain(mxc_uri.clone().into()),
+    };
+    let job = downloads.start(client, mxc_uri, source, thumbnail, path.clone(), None);
+    job.finished().await?;
+    Ok(path)
+}
+
+/// Answer one proxy request: `GET <prefix><server>/<media_id>[?w=&h=&crop=]`.
+async fn serve_media_request(
+    mut stream: tokio::net::TcpStream,
+    ctx: &MediaProxyCtx,
+) -> std::io::Result<()> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut head = Vec::with_capacity(1024);
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || head.len() > 8 * 1024 {
+            return Ok(());
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let head = String::from_utf8_lossy(&head);
+    let mut parts = head.lines().next().unwrap_or_default().split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+
+    let respond = |status: &str| {
+        format!("HTTP/1.1 {status}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n")
+    };
+    if method != "GET" && method != "HEAD" {
+        let reply = respond("405 Method Not Allowed");
+        return stream.write_all(reply.as_bytes()).await;
+    }
+    let Some(rest) = target.strip_prefix(&ctx.prefix) else {
+        return stream.write_all(respond("404 Not Found").as_bytes()).await;
+    };
+    let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let mxc_uri = format!("mxc://{path}");
+
+    let mut thumb = (None, None, false);
+    for (k, v) in query.split('&').filter_map(|kv| kv.split_once('=')) {
+        match k {
+            "w" => thumb.0 = v.parse().ok(),
+            "h" => thumb.1 = v.parse().ok(),
+            "crop" => thumb.2 = v == "1",
+            _ => {}
+        }
+    }
+    let thumbnail = match thumb {
+        (Some(width), Some(height), crop) => Some(ThumbnailSize {
+            width,
+            height,
+            crop,
+        }),
+        _ => None,
+    };
+    let encrypted = ctx.keys.lock().unwrap().get(&mxc_uri);
+
+    let file = match resolve_media_file(
+        &ctx.client,
+        &ctx.downloads,
+        &ctx.dir,
+        mxc_uri,
+        encrypted,
+        thumbnail,
+    )
+    .await
+    {
+        Ok(file) => file,
+        Err(e) => {
+            let status = match e {
+                FfiError::NotFound { .. } | FfiError::InvalidInput { .. } => "404 Not Found",
+                FfiError::Forbidden { .. } => "403 Forbidden",
+                _ => "502 Bad Gateway",
+            };
+            return stream.write_all(respond(status).as_bytes()).await;
+        }
+    };
+
+    let mut f = tokio::fs::File::open(&file).await?;
+    let len = f.metadata().await?.len();
+    let mut sniff = [0u8; 64];
+    let n = f.read(&mut sniff).await?;
+    let mime = image::guess_format(&sniff[..n])
+        .map(|fmt| fmt.to_mime_type())
+        .unwrap_or("application/octet-stream");
+
+    let header = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: {mime}\r\nContent-Length: {len}\r\nCache-Control: private, max-age=31536000, immutable\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(header.as_bytes()).await?;
+    if method == "GET" {
+        stream.write_all(&sniff[..n]).await?;
+        tokio::io::copy(&mut f, &mut stream).await?;
+    }
+    stream.flush().await
+}
+
+/// Where an unfinished download of `path` is kept.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".part");
+    path.with_file_name(name)
+}
+
+/// Where an encrypted download is decrypted to while `partial_path` holds the
+/// ciphertext.
+fn decrypted_partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".dec.part");
+    path.with_file_name(name)
+}
+
+enum HomeserverRef {
+    Url(String),
+    /// Server name, URL or MXID; resolved with `.well-known` discovery.
+    Discover(String),
+}
+
+/// Accept `@user:server.org` as well as a server name or URL.
+fn server_from_input(input: &str) -> String {
+    let input = input.trim();
+    if input.starts_with('@')
+        && let Ok(uid) = input.parse::<OwnedUserId>()
+    {
+        return uid.server_name().to_string();
+    }
+    input.to_owned()
+}
+
+async fn fetch_login_methods(client: &SdkClient) -> Result<LoginMethods, FfiError> {
+    use ruma::api::client::session::get_login_types::v3::LoginType;
+
+    let oauth = client.oauth().server_metadata().await.is_ok();
+    let flows = match client.matrix_auth().get_login_types().await {
+        Ok(resp) => resp.flows,
+        // Servers fully delegated to OAuth may not answer /login at all.
+        Err(e) if oauth => {
+            debug!("fetch_login_methods: /login failed on OAuth server: {e}");
+            Vec::new()
+        }
+        Err(e) => return Err(FfiError::Msg(format!("login types: {e}"))),
+    };
+
+    let mut out = LoginMethods {
+        password: false,
+        sso: false,
+        sso_providers: Vec::new(),
+        oauth,
+        token: false,
+    };
+    for flow in flows {
+        match flow {
+            LoginType::Password(_) => out.password = true,
+            LoginType::Token(_) => out.token = true,
+            LoginType::Sso(sso) => {
+                out.sso = true;
+                out.sso_providers
+                    .extend(sso.identity_providers.into_iter().map(|p| SsoProvider {
+                        id: p.id,
+                        name: p.name,
+                        icon_mxc: p.icon.map(|m| m.to_string()),
+                    }));
+            }
+            _ => {}
+        }
+    }
+    Ok(out)
+}
+
+fn build_unstable_poll_content(
+    def: &PollDefinition,
+) -> Result<NewUnstablePollStartEventContent, FfiError> {
+    // Build answers with simple stable IDs: "a", "b", "c", ...
+    let mut answers = Vec::with_capacity(def.answers.len());
+    for (idx, text) in def.answers.iter().enumerate() {
+        let id = ((b'a' + (idx as u8)) as char).to_string();
+        answers.push(UnstablePollAnswer::new(id, text.clone()));
+    }
+
+    let unstable_answers = UnstablePollAnswers::try_from(answers)
+        .map_err(|e| FfiError::invalid_input(format!("poll answers: {e}")))?;
+
+    let mut block = UnstablePollStartContentBlock::new(def.question.clone(), unstable_answers);
+
+    // Map kind + max_selections
+    block.kind = match def.kind {
+        PollKind::Disclosed => RumaPollKind::Disclosed,
+        PollKind::Undisclosed => RumaPollKind::Undisclosed,
+    };
+    block.max_selections = js_int::UInt::from(def.max_selections.max(1));
+
+    Ok(NewUnstablePollStartEventContent::plain_text(
         def.question.clone(),
         block,
     ))
 }
 
-static TIMELINES: Lazy<Mutex<HashMap<OwnedRoomId, Arc<Timeline>>>> =
-    Lazy::new(|| Mutex::new(HashMap::new()));
+async fn get_timeline_for(
+    client: &SdkClient,
+    timelines: &TimelineCache,
+    room_id: &OwnedRoomId,
+) -> Option<Arc<Timeline>> {
+    // reuse
+    if let Some(tl) = timelines.lock().unwrap().get(room_id).cloned() {
+        return Some(tl);
+    }
+
+    // Slow path – ask the SDK for a Timeline for this room and cache it.
+    let room = client.get_room(room_id)?;
+    let tl = Arc::new(room.timeline().await.ok()?);
+    let tl = timelines
+        .lock()
+        .unwrap()
+        .entry(room_id.clone())
+        .or_insert(tl)
+        .clone();
+    Some(tl)
+}
+
+fn map_timeline_event(
+    ev: &EventTimelineItem,
+    room_id: &str,
+    item_id: Option<&str>,
+    me: Option<&ruma::UserId>,
+) -> Option<MessageEvent> {
+    let ts: u64 = ev.timestamp().0.into();
+    let event_id = ev.event_id().map(|e| e.to_string()).unwrap_or_default();
+    let txn_id = ev.transaction_id().map(|t| t.to_string());
+    let mut send_error: Option<SendFailure> = None;
+    let send_state = match ev.send_state() {
+        Some(EventSendState::NotSentYet { .. }) => Some(SendState::Sending),
+        Some(EventSendState::SendingFailed {
+            error,
+            is_recoverable,
+        }) => {
+            send_error = Some(send_failure(error, *is_recoverable));
+            Some(SendState::Failed)
+        }
+        Some(EventSendState::Sent { .. }) => Some(SendState::Sent),
+        None => None,
+    };
+
+    let item_id_str = item_id
+        .map(|s| s.to_string())
+        .unwrap_or_else(|| format!("{:?}", ev.identifier()));
+
+    let mut reply_to_event_id: Option<String> = None;
+    let mut reply_to_sender: Option<String> = None;
+    let mut reply_to_body: Option<String> = None;
+    let mut attachment: Option<AttachmentInfo> = None;
+    let mut formatted_body: Option<String> = None;
+    let mut mentioned_user_ids: Vec<String> = Vec::new();
+    let mut mentions_room = false;
+    let mut edited = false;
+    let mut edit_history: Vec<EditRevision> = Vec::new();
+    let mut profile_change: Option<ProfileChange> = None;
+    let mut location: Option<LocationInfo> = None;
+    let thread_root_event_id = ev.content().thread_root().map(|id| id.to_string());
+    let kind = message_kind(ev.content());
+    let body: String;
+
+    match ev.content() {
+        TimelineItemContent::MsgLike(ml) => {
+            if let Some(details) = &ml.in_reply_to {
+                reply_to_event_id = Some(details.event_id.to_string());
+                if let matrix_sdk_ui::timeline::TimelineDetails::Ready(embed) = &details.event {
+                    reply_to_sender = Some(embed.sender.to_string());
+                    if let Some(m) = embed.content.as_message() {
+                        reply_to_body = Some(m.body().to_owned());
+                    }
+                }
+            }
+
+            if let MsgLikeKind::Sticker(sticker) = &ml.kind {
+                attachment = Some(sticker_attachment(sticker.content()));
+            }
+            if let Some(msg) = ml.as_message() {
+                attachment = extract_attachment(&msg);
+                location = map_location(msg.msgtype());
+                formatted_body = formatted_html(msg.msgtype());
+                if let Some(m) = msg.mentions() {
+                    mentioned_user_ids = m.user_ids.iter().map(|u| u.to_string()).collect();
+                    mentions_room = m.room;
+                }
+                edited = msg.is_edited();
+                if edited {
+                    edit_history = ev
+                        .original_json()
+                        .cloned()
+                        .into_iter()
+                        .chain(ev.latest_edit_json().cloned())
+                        .filter_map(|raw| edit_revision(&raw))
+                        .collect();
+                }
+                let raw = msg.body();
+                body = if reply_to_event_id.is_some() {
+                    strip_reply_fallback(raw)
+                } else {
+                    render_message_text(&msg)
+                };
+            } else {
+                body = render_msg_like(ev, ml);
+            }
+        }
+        TimelineItemContent::ProfileChange(change) => {
+            profile_change = Some(map_profile_change(change));
+            body = render_timeline_text(ev);
+        }
+        _ => {
+            body = render_timeline_text(ev);
+        }
+    }
+
+    let links = match kind {
+        MessageKind::Text | MessageKind::Emote | MessageKind::Notice => detect_links(&body),
+        _ => vec![],
+    };
+
+    Some(MessageEvent {
+        item_id: item_id_str,
+        event_id,
+        room_id: room_id.to_string(),
+        sender: ev.sender().to_string(),
+        body,
+        timestamp_ms: ts,
+        send_state,
+        txn_id,
+        reply_to_event_id,
+        reply_to_sender,
+        reply_to_body,
+        attachment,
+        thread_root_event_id,
+        formatted_body,
+        mentioned_user_ids,
+        mentions_room,
+        kind,
+        reactions: map_reactions(ev, me),
+        edited,
+        edit_history,
+        read_by: ev
+            .read_receipts()
+            .iter()
+            .map(|(user_id, receipt)| ReadReceipt {
+                user_id: user_id.to_string(),
+                timestamp_ms: receipt.ts.map(|t| t.0.into()),
+            })
+            .collect(),
+        shield: ev
+            .encryption_info()
+            .map(|info| event_shield(&info.verification_state)),
+        send_error,
+        profile_change,
+        links,
+        location,
+    })
+}
+
+fn message_kind(content: &TimelineItemContent) -> MessageKind {
+    match content {
+        TimelineItemContent::MsgLike(ml) => match &ml.kind {
+            MsgLikeKind::Message(m) => msgtype_kind(m.msgtype()),
+            MsgLikeKind::Sticker(_) => MessageKind::Sticker,
+            MsgLikeKind::Poll(_) => MessageKind::Poll,
+            MsgLikeKind::Redacted => MessageKind::Redacted,
+            MsgLikeKind::UnableToDecrypt(_) => MessageKind::UnableToDecrypt,
+        },
+        TimelineItemContent::MembershipChange(_)
+        | TimelineItemContent::ProfileChange(_)
+        | TimelineItemContent::OtherState(_) => MessageKind::State,
+        _ => MessageKind::Other,
+    }
+}
+
+fn msgtype_kind(msgtype: &MessageType) -> MessageKind {
+    match msgtype {
+        MessageType::Text(_) => MessageKind::Text,
+        MessageType::Emote(_) => MessageKind::Emote,
+        MessageType::Notice(_) => MessageKind::Notice,
+        MessageType::Image(_) => MessageKind::Image,
+        MessageType::Video(_) => MessageKind::Video,
+        MessageType::Audio(_) => MessageKind::Audio,
+        MessageType::File(_) => MessageKind::File,
+        MessageType::Location(_) => MessageKind::Location,
+        _ => MessageKind::Other,
+    }
+}
+
+fn map_reactions(ev: &EventTimelineItem, me: Option<&ruma::UserId>) -> Vec<ReactionSummary> {
+    let Some(reactions) = ev.content().reactions() else {
+        return vec![];
+    };
+    reactions
+        .iter()
+        .map(|(key, by_sender)| ReactionSummary {
+            key: key.to_string(),
+            count: by_sender.len() as u32,
+            me: me.is_some_and(|u| by_sender.contains_key(u)),
+            senders: by_sender.keys().map(|u| u.to_string()).collect(),
+        })
+        .collect()
+}
+
+/// A message body and its timestamp; for an `m.replace`, the new content.
+fn edit_revision(
+    raw: &ruma::serde::Raw<ruma::events::AnySyncTimelineEvent>,
+) -> Option<EditRevision> {
+    use ruma::events::{AnySyncMessageLikeEvent, AnySyncTimelineEvent, SyncMessageLikeEvent};
+
+    let AnySyncTimelineEvent::MessageLike(AnySyncMessageLikeEvent::RoomMessage(
+        SyncMessageLikeEvent::Original(ev),
+    )) = raw.deserialize().ok()?
+    else {
+        return None;
+    };
+    let body = match &ev.content.relates_to {
+        Some(MsgRelation::Replacement(r)) => r.new_content.msgtype.body().to_owned(),
+        Some(MsgRelation::Reply { .. }) => strip_reply_fallback(ev.content.body()),
+        _ => ev.content.body().to_owned(),
+    };
+    Some(EditRevision {
+        event_id: Some(ev.event_id.to_string()),
+        body,
+        timestamp_ms: ev.origin_server_ts.0.into(),
+    })
+}
+
+fn event_shield(state: &matrix_sdk::deserialized_responses::VerificationState) -> EventShield {
+    use matrix_sdk::deserialized_responses::{
+        DeviceLinkProblem, VerificationLevel, VerificationState,
+    };
+
+    match state {
+        VerificationState::Verified => EventShield::Verified,
+        VerificationState::Unverified(level) => match level {
+            VerificationLevel::UnverifiedIdentity => EventShield::UnverifiedIdentity,
+            VerificationLevel::VerificationViolation => EventShield::VerificationViolation,
+            VerificationLevel::UnsignedDevice => EventShield::UnverifiedDevice,
+            VerificationLevel::None(DeviceLinkProblem::MissingDevice) => EventShield::UnknownDevice,
+            _ => EventShield::AuthenticityUnknown,
+        },
+    }
+}
+
+fn send_failure(error: &matrix_sdk::Error, recoverable: bool) -> SendFailure {
+    let (kind, message) = match FfiError::from_sdk(error) {
+        FfiError::Network { message } => (SendFailureKind::Network, message),
+        FfiError::Forbidden { message } => (SendFailureKind::Forbidden, message),
+        FfiError::RateLimited {
+            retry_after_ms,
+            message,
+        } => (SendFailureKind::RateLimited { retry_after_ms }, message),
+        FfiError::InvalidInput { message } => (SendFailureKind::InvalidContent, message),
+        FfiError::UnknownToken { message, .. } => (SendFailureKind::Unauthorized, message),
+        FfiError::Crypto { message } => (SendFailureKind::Crypto, message),
+        FfiError::NotFound { message } | FfiError::Store { message } => {
+            (SendFailureKind::Other, message)
+        }
+        FfiError::Msg(message) => (SendFailureKind::Other, message),
+    };
+    SendFailure {
+        kind,
+        message,
+        recoverable,
+    }
+}
+
+fn extract_attachment(msg: &matrix_sdk_ui::timeline::Message) -> Option<AttachmentInfo> {
+    use matrix_sdk::ruma::events::room::{MediaSource, message::MessageType as MT};
+
+    // Helper: split a MediaSource into MXC URI and optional EncFile
+    fn split_source(source: &MediaSource) -> (String, Option<EncFile>) {
+        match source {
+            MediaSource::Plain(url) => (url.to_string(), None),
+            MediaSource::Encrypted(file) => {
+                let url = file.url.to_string();
+                let enc = enc_to_record(file.as_ref());
+                (url, Some(enc))
+            }
+        }
+    }
+
+    // Helper: same, but for Option<&MediaSource> (used for thumbnails)
+    fn split_opt_source(source: Option<&MediaSource>) -> (Option<String>, Option<EncFile>) {
+        match source {
+            Some(MediaSource::Plain(url)) => (Some(url.to_string()), None),
+            Some(MediaSource::Encrypted(file)) => {
+                let url = file.url.to_string();
+                let enc = enc_to_record(file.as_ref());
+                (Some(url), Some(enc))
+            }
+            None => (None, None),
+        }
+    }
+
+    match msg.msgtype() {
+        MT::Image(c) => {
+            // main image source
+            let (mxc_uri, encrypted) = split_source(&c.source);
+
+            // metadata + thumbnail
+            let (w, h, size, mime, thumb_mxc, thumb_enc) = c
+                .info
+                .as_ref()
+                .map(|info| {
+                    let (thumb_mxc, thumb_enc) = split_opt_source(info.thumbnail_source.as_ref());
+                    (
+                        info.width.map(|v| u32::try_from(v).unwrap_or(0)),
+                        info.height.map(|v| u32::try_from(v).unwrap_or(0)),
+                        info.size.map(u64::from),
+                        info.mimetype.clone(),
+                        thumb_mxc,
+                        thumb_enc,
+                    )
+                })
+                .unwrap_or((None, None, None, None, None, None));
+
+            Some(AttachmentInfo {
+                kind: AttachmentKind::Image,
+                mxc_uri,
+                mime,
+                size_bytes: size,
+                width: w,
+                height: h,
+                duration_ms: None,
+                thumbnail_mxc_uri: thumb_mxc,
+                encrypted,
+                thumbnail_encrypted: thumb_enc,
+                filename: Some(c.filename().to_owned()),
+                caption: c.caption().map(str::to_owned),
+                is_voice: false,
+                waveform: vec![],
+            })
+        }
+
+        MT::Video(c) => {
+            let (mxc_uri, encrypted) = split_source(&c.source);
+
+            let (w, h, size, mime, dur, thumb_mxc, thumb_enc) = c
+                .info
+                .as_ref()
+                .map(|info| {
+                    let (thumb_mxc, thumb_enc) = split_opt_source(info.thumbnail_source.as_ref());
+                    (
+                        info.width.map(|v| u32::try_from(v).unwrap_or(0)),
+                        info.height.map(|v| u32::try_from(v).unwrap_or(0)),
+                        info.size.map(u64::from),
+                        info.mimetype.clone(),
+                        info.duration.map(|d| d.as_millis() as u64),
+                        thumb_mxc,
+                        thumb_enc,
+                    )
+                })
+                .unwrap_or((None, None, None, None, None, None, None));
+
+            Some(AttachmentInfo {
+                kind: AttachmentKind::Video,
+                mxc_uri: mxc_uri.clone(),
+                mime,
+                size_bytes: size,
+                width: w,
+                height: h,
+                duration_ms: dur,
+                // Fallback to full video if no explicit thumbnail
+                thumbnail_mxc_uri: thumb_mxc.or_else(|| Some(mxc_uri.clone())),
+                encrypted,
+                thumbnail_encrypted: thumb_enc,
+                filename: Some(c.filename().to_owned()),
+                caption: c.caption().map(str::to_owned),
+                is_voice: false,
+                waveform: vec![],
+            })
+        }
+
+        MT::File(c) => {
+            let (mxc_uri, encrypted) = split_source(&c.source);
+
+            let (size, mime, thumb_mxc, thumb_enc) = c
+                .info
+                .as_ref()
+                .map(|info| {
+                    let (thumb_mxc, thumb_enc) = split_opt_source(info.thumbnail_source.as_ref());
+                    (
+                        info.size.map(u64::from),
+                        info.mimetype.clone(),
+                        thumb_mxc,
+                        thumb_enc,
+                    )
+                })
+                .unwrap_or((None, None, None, None));
+
+            Some(AttachmentInfo {
+                kind: AttachmentKind::File,
+                mxc_uri,
+                mime,
+                size_bytes: size,
+                width: None,
+                height: None,
+                duration_ms: None,
+                thumbnail_mxc_uri: thumb_mxc,
+                encrypted,
+                thumbnail_encrypted: thumb_enc,
+                filename: Some(c.filename().to_owned()),
+                caption: c.caption().map(str::to_owned),
+                is_voice: false,
+                waveform: vec![],
+            })
+        }
+
+        MT::Audio(c) => {
+            let (mxc_uri, encrypted) = split_source(&c.source);
+
+            let (size, mime, dur) = c
+                .info
+                .as_ref()
+                .map(|info| {
+                    (
+                        info.size.map(u64::from),
+                        info.mimetype.clone(),
+                        info.duration.map(|d| d.as_millis() as u64),
+                    )
+                })
+                .unwrap_or((None, None, None));
+            let duration_ms =
+                dur.or_else(|| c.audio.as_ref().map(|a| a.duration.as_millis() as u64));
+            let waveform = c
+                .audio
+                .as_ref()
+                .map(|a| {
+                    a.waveform
+                        .iter()
+                        .map(|amp| u64::from(amp.get()) as f32 / 1024.0)
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            Some(AttachmentInfo {
+                kind: AttachmentKind::Audio,
+                mxc_uri,
+                mime,
+                size_bytes: size,
+                width: None,
+                height: None,
+                duration_ms,
+                thumbnail_mxc_uri: None,
+                encrypted,
+                thumbnail_encrypted: None,
+                filename: Some(c.filename().to_owned()),
+                caption: c.caption().map(str::to_owned),
+                is_voice: c.voice.is_some(),
+                waveform,
+            })
+        }
+
+        _ => None,
+    }
+}
+
+/// MSC1767 audio block for a voice message; amplitudes are scaled to 0..=1024.
+fn voice_details(duration_ms: u64, waveform: &[f32]) -> UnstableAudioDetailsContentBlock {
+    use matrix_sdk::ruma::events::room::message::UnstableAmplitude;
 
-async fn get_timeline_for(client: &SdkClient, room_id: &OwnedRoomId) -> Option<Arc<Timeline>> {
-    // reuse
-    if let Some(tl) = TIMELINES.lock().unwrap().get(room_id).cloned() {
-        return Some(tl);
+    let waveform = waveform
+        .iter()
+        .map(|v| UnstableAmplitude::new((v.clamp(0.0, 1.0) * 1024.0) as u16))
+        .collect();
+    UnstableAudioDetailsContentBlock::new(Duration::from_millis(duration_ms), waveform)
+}
+
+/// Read an avatar image: its MIME type from the content, EXIF stripped as for
+/// attachments, and its size.
+async fn read_avatar(path: &str) -> Result<(Mime, Vec<u8>, (u32, u32)), FfiError> {
+    let data = tokio::fs::read(path).await?;
+    let format = image::guess_format(&data)
+        .map_err(|_| FfiError::invalid_input("avatar is not a supported image"))?;
+    let mime: Mime = format
+        .to_mime_type()
+        .parse()
+        .map_err(|_| FfiError::invalid_input("avatar is not a supported image"))?;
+
+    let prepare_mime = mime.clone();
+    let (data, dims) = tokio::task::spawn_blocking(move || {
+        // Re-encoding for orientation keeps the format (JPEG).
+        let data = prepare_image(&prepare_mime, data).data;
+        let dims =
+            image::ImageReader::with_format(std::io::Cursor::new(&data), format).into_dimensions();
+        (data, dims)
+    })
+    .await
+    .map_err(|e| FfiError::Msg(e.to_string()))?;
+    let dims = dims.map_err(|e| FfiError::invalid_input(format!("avatar: {e}")))?;
+    Ok((mime, data, dims))
+}
+
+/// Outgoing media after preprocessing: the bytes to upload (JPEG/PNG/WebP
+/// EXIF, incl. GPS, removed) and whatever metadata/thumbnail could be derived.
+struct PreparedMedia {
+    data: Vec<u8>,
+    info: Option<matrix_sdk::attachment::AttachmentInfo>,
+    thumbnail: Option<matrix_sdk::attachment::Thumbnail>,
+}
+
+const THUMBNAIL_MAX_WIDTH: u32 = 800;
+const THUMBNAIL_MAX_HEIGHT: u32 = 600;
+
+fn prepare_media(mime: &Mime, data: Vec<u8>) -> PreparedMedia {
+    use matrix_sdk::attachment::{AttachmentInfo as SdkAttachmentInfo, BaseVideoInfo};
+
+    match mime.type_() {
+        mime::IMAGE => prepare_image(mime, data),
+        mime::VIDEO => {
+            let info = mp4_metadata(&data).map(|info| {
+                SdkAttachmentInfo::Video(BaseVideoInfo {
+                    size: UInt::new(data.len() as u64),
+                    ..info
+                })
+            });
+            PreparedMedia {
+                data,
+                info,
+                thumbnail: None,
+            }
+        }
+        _ => PreparedMedia {
+            data,
+            info: None,
+            thumbnail: None,
+        },
     }
+}
 
-    // Slow path – ask the SDK for a Timeline for this room and cache it.
-    let room = client.get_room(room_id)?;
-    let tl = Arc::new(room.timeline().await.ok()?);
-    TIMELINES
-        .lock()
-        .unwrap()
-        .insert(room_id.clone(), tl.clone());
-    Some(tl)
+fn prepare_image(mime: &Mime, data: Vec<u8>) -> PreparedMedia {
+    use image::metadata::Orientation;
+    use matrix_sdk::attachment::{AttachmentInfo as SdkAttachmentInfo, BaseImageInfo, Thumbnail};
+
+    let Some((img, orientation)) = decode_oriented(&data) else {
+        return PreparedMedia {
+            data,
+            info: None,
+            thumbnail: None,
+        };
+    };
+
+    let data = if orientation != Orientation::NoTransforms {
+        // Baking the rotation in needs a re-encode, which writes no metadata.
+        encode_as(&img, mime).unwrap_or_else(|| strip_exif(mime, &data))
+    } else {
+        strip_exif(mime, &data)
+    };
+
+    let (width, height) = (img.width(), img.height());
+    let tiny = img.thumbnail(32, 32).to_rgba8();
+    let blurhash = blurhash::encode(4, 3, tiny.width(), tiny.height(), tiny.as_raw()).ok();
+
+    let thumbnail = if width > THUMBNAIL_MAX_WIDTH || height > THUMBNAIL_MAX_HEIGHT {
+        let thumb = img.thumbnail(THUMBNAIL_MAX_WIDTH, THUMBNAIL_MAX_HEIGHT);
+        encode_jpeg(&thumb, 80).map(|bytes| Thumbnail {
+            size: UInt::new(bytes.len() as u64).unwrap_or_default(),
+            width: UInt::from(thumb.width()),
+            height: UInt::from(thumb.height()),
+            content_type: mime::IMAGE_JPEG,
+            data: bytes,
+        })
+    } else {
+        None
+    };
+
+    let info = SdkAttachmentInfo::Image(BaseImageInfo {
+        width: Some(UInt::from(width)),
+        height: Some(UInt::from(height)),
+        size: UInt::new(data.len() as u64),
+        blurhash,
+        ..Default::default()
+    });
+
+    PreparedMedia {
+        data,
+        info: Some(info),
+        thumbnail,
+    }
 }
 
-fn map_timeline_event(
-    ev: &EventTimelineItem,
-    room_id: &str,
-    item_id: Option<&str>,
-) -> Option<MessageEvent> {
-    let ts: u64 = ev.timestamp().0.into();
-    let event_id = ev.event_id().map(|e| e.to_string()).unwrap_or_default();
-    let txn_id = ev.transaction_id().map(|t| t.to_string());
-    let send_state = match ev.send_state() {
-        Some(EventSendState::NotSentYet { .. }) => Some(SendState::Sending),
-        Some(EventSendState::SendingFailed { .. }) => Some(SendState::Failed),
-        Some(EventSendState::Sent { .. }) => Some(SendState::Sent),
-        None => None,
+/// Decode an image with its EXIF orientation already applied.
+fn decode_oriented(data: &[u8]) -> Option<(image::DynamicImage, image::metadata::Orientation)> {
+    use image::{DynamicImage, ImageDecoder, ImageReader, metadata::Orientation};
+
+    let mut decoder = ImageReader::new(std::io::Cursor::new(data))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut img = DynamicImage::from_decoder(decoder).ok()?;
+    img.apply_orientation(orientation);
+    Some((img, orientation))
+}
+
+fn encode_jpeg(img: &image::DynamicImage, quality: u8) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    img.to_rgb8()
+        .write_with_encoder(image::codecs::jpeg::JpegEncoder::new_with_quality(
+            &mut out, quality,
+        ))
+        .ok()?;
+    Some(out)
+}
+
+/// Re-encode `img` in the format of `mime` (JPEG, PNG or WebP).
+fn encode_as(img: &image::DynamicImage, mime: &Mime) -> Option<Vec<u8>> {
+    let format = match mime.subtype().as_str() {
+        "jpeg" => return encode_jpeg(img, 90),
+        "png" => image::ImageFormat::Png,
+        "webp" => image::ImageFormat::WebP,
+        _ => return None,
     };
+    let mut out = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut out, format).ok()?;
+    Some(out.into_inner())
+}
 
-    let item_id_str = item_id
-        .map(|s| s.to_string())
-        .unwrap_or_else(|| format!("{:?}", ev.identifier()));
+/// The image without its EXIF block, for the formats that carry one.
+fn strip_exif(mime: &Mime, data: &[u8]) -> Vec<u8> {
+    match mime.subtype().as_str() {
+        "jpeg" => strip_jpeg_exif(data),
+        "png" => strip_png_exif(data),
+        "webp" => strip_webp_exif(data),
+        _ => data.to_vec(),
+    }
+}
 
-    let mut reply_to_event_id: Option<String> = None;
-    let mut reply_to_sender: Option<String> = None;
-    let mut reply_to_body: Option<String> = None;
-    let mut attachment: Option<AttachmentInfo> = None;
-    let thread_root_event_id = ev.content().thread_root().map(|id| id.to_string());
-    let body: String;
+/// Drop APP1 Exif segments (camera info, GPS) from a JPEG without re-encoding.
+fn strip_jpeg_exif(data: &[u8]) -> Vec<u8> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return data.to_vec();
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..2]);
+    let mut i = 2;
+    while i + 4 <= data.len() && data[i] == 0xFF {
+        let marker = data[i + 1];
+        // Start of scan: the rest is entropy-coded image data.
+        if marker == 0xDA {
+            break;
+        }
+        let len = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        let end = i + 2 + len;
+        if len < 2 || end > data.len() {
+            break;
+        }
+        let is_exif = marker == 0xE1 && data[i + 4..end].starts_with(b"Exif\0");
+        if !is_exif {
+            out.extend_from_slice(&data[i..end]);
+        }
+        i = end;
+    }
+    out.extend_from_slice(&data[i..]);
+    out
+}
 
-    match ev.content() {
-        TimelineItemContent::MsgLike(ml) => {
-            if let Some(details) = &ml.in_reply_to {
-                reply_to_event_id = Some(details.event_id.to_string());
-                if let matrix_sdk_ui::timeline::TimelineDetails::Ready(embed) = &details.event {
-                    reply_to_sender = Some(embed.sender.to_string());
-                    if let Some(m) = embed.content.as_message() {
-                        reply_to_body = Some(m.body().to_owned());
-                    }
-                }
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Drop `eXIf` chunks from a PNG without re-encoding.
+fn strip_png_exif(data: &[u8]) -> Vec<u8> {
+    if !data.starts_with(PNG_SIGNATURE) {
+        return data.to_vec();
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(PNG_SIGNATURE);
+    let mut i = PNG_SIGNATURE.len();
+    // Chunks are length, type, data, CRC.
+    while let Some(len) = be_u32(data, i) {
+        let end = i.saturating_add(12).saturating_add(len as usize);
+        if end > data.len() {
+            break;
+        }
+        if &data[i + 4..i + 8] != b"eXIf" {
+            out.extend_from_slice(&data[i..end]);
+        }
+        i = end;
+    }
+    out.extend_from_slice(&data[i..]);
+    out
+}
+
+/// Drop `EXIF` chunks from a WebP (and its flag in `VP8X`) without re-encoding.
+fn strip_webp_exif(data: &[u8]) -> Vec<u8> {
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return data.to_vec();
+    }
+    let mut out = Vec::with_capacity(data.len());
+    out.extend_from_slice(&data[..12]);
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let len = u32::from_le_bytes([data[i + 4], data[i + 5], data[i + 6], data[i + 7]]) as usize;
+        let body_end = (i + 8).saturating_add(len);
+        if body_end > data.len() {
+            break;
+        }
+        // Odd-sized chunks are padded to an even length.
+        let end = (body_end + (len & 1)).min(data.len());
+        match &data[i..i + 4] {
+            b"EXIF" => {}
+            b"VP8X" if len > 0 => {
+                let flags = out.len() + 8;
+                out.extend_from_slice(&data[i..end]);
+                out[flags] &= !0x08;
             }
+            _ => out.extend_from_slice(&data[i..end]),
+        }
+        i = end;
+    }
+    out.extend_from_slice(&data[i..]);
+    let riff_len = u32::try_from(out.len() - 8).unwrap_or(u32::MAX);
+    out[4..8].copy_from_slice(&riff_len.to_le_bytes());
+    out
+}
 
-            if let Some(msg) = ml.as_message() {
-                attachment = extract_attachment(&msg);
-                let raw = msg.body();
-                body = if reply_to_event_id.is_some() {
-                    strip_reply_fallback(raw)
-                } else {
-                    render_message_text(&msg)
-                };
-            } else {
-                body = render_msg_like(ev, ml);
+/// Duration (`mvhd`) and dimensions (first video `tkhd`) of an MP4/MOV file.
+fn mp4_metadata(data: &[u8]) -> Option<matrix_sdk::attachment::BaseVideoInfo> {
+    let (_, moov) = mp4_boxes(data).into_iter().find(|(k, _)| k == b"moov")?;
+
+    let mut duration = None;
+    let mut dims = None;
+    for (kind, body) in mp4_boxes(moov) {
+        match &kind {
+            b"mvhd" => duration = mvhd_duration(body),
+            // Audio tracks have a 0x0 tkhd; keep looking for the video one.
+            b"trak" if dims.is_none() => {
+                dims = mp4_boxes(body)
+                    .into_iter()
+                    .find(|(k, _)| k == b"tkhd")
+                    .and_then(|(_, tkhd)| tkhd_dimensions(tkhd));
             }
+            _ => {}
         }
-        _ => {
-            body = render_timeline_text(ev);
+    }
+    (duration.is_some() || dims.is_some()).then(|| matrix_sdk::attachment::BaseVideoInfo {
+        duration,
+        width: dims.map(|(w, _)| UInt::from(w)),
+        height: dims.map(|(_, h)| UInt::from(h)),
+        ..Default::default()
+    })
+}
+
+fn mp4_boxes(mut data: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut out = Vec::new();
+    while data.len() >= 8 {
+        let size = be_u32(data, 0).unwrap_or(0) as u64;
+        let kind: [u8; 4] = [data[4], data[5], data[6], data[7]];
+        let (header, size) = match size {
+            0 => (8, data.len() as u64),
+            1 => (16, be_u64(data, 8).unwrap_or(0)),
+            _ => (8, size),
+        };
+        if size < header || size > data.len() as u64 {
+            break;
         }
+        out.push((kind, &data[header as usize..size as usize]));
+        data = &data[size as usize..];
     }
+    out
+}
 
-    Some(MessageEvent {
-        item_id: item_id_str,
-        event_id,
-        room_id: room_id.to_string(),
-        sender: ev.sender().to_string(),
-        body,
-        timestamp_ms: ts,
-        send_state,
-        txn_id,
-        reply_to_event_id,
-        reply_to_sender,
-        reply_to_body,
-        attachment,
-        thread_root_event_id,
+fn mvhd_duration(b: &[u8]) -> Option<Duration> {
+    let (timescale, duration) = if *b.first()? == 1 {
+        (be_u32(b, 20)?, be_u64(b, 24)?)
+    } else {
+        (be_u32(b, 12)?, be_u32(b, 16)? as u64)
+    };
+    if timescale == 0 {
+        return None;
+    }
+    let ms = duration as u128 * 1000 / timescale as u128;
+    Some(Duration::from_millis(ms as u64))
+}
+
+/// Display size of a track: width/height with the transform matrix's
+/// quarter turns applied, as phone videos are stored sideways.
+fn tkhd_dimensions(b: &[u8]) -> Option<(u32, u32)> {
+    // Width/height are 16.16 fixed point right after the 3x3 matrix {a b u, c d v, x y w}.
+    let matrix = if *b.first()? == 1 { 52 } else { 40 };
+    let at = matrix + 36;
+    let (w, h) = (be_u32(b, at)? >> 16, be_u32(b, at + 4)? >> 16);
+    // A 90/270 degree turn has a == 0 and b == +-1.
+    let quarter_turn = be_u32(b, matrix)? == 0 && be_u32(b, matrix + 4)? != 0;
+    let (w, h) = if quarter_turn { (h, w) } else { (w, h) };
+    (w > 0 && h > 0).then_some((w, h))
+}
+
+fn be_u32(b: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(b.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(b: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(b.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// Caption body, HTML and mentions (MSC2530), from the composer text of `options`.
+/// A media event can't be an emote, so a "/me " caption is refused.
+#[allow(clippy::type_complexity)]
+fn caption_parts(
+    options: Option<&AttachmentOptions>,
+) -> Result<
+    (
+        Option<String>,
+        Option<matrix_sdk::ruma::events::room::message::FormattedBody>,
+        Option<matrix_sdk::ruma::events::Mentions>,
+    ),
+    FfiError,
+> {
+    let Some(opts) = options else {
+        return Ok((None, None, None));
+    };
+    let Some(caption) = opts.caption.clone().filter(|c| !c.trim().is_empty()) else {
+        return Ok((None, None, None));
+    };
+    let content = build_text_content(caption, opts.caption_options.clone());
+    let (body, formatted) = match content.msgtype {
+        MessageType::Text(t) => (t.body, t.formatted),
+        MessageType::Notice(t) => (t.body, t.formatted),
+        MessageType::Emote(_) => {
+            return Err(FfiError::invalid_input("a caption can't be an emote (/me)"));
+        }
+        other => (other.body().to_owned(), None),
+    };
+    Ok((Some(body), formatted, content.mentions))
+}
+
+/// Reply/thread placement for a media event, uploaded or not. With both ids
+/// set, the thread is the replied-to event's (see `AttachmentOptions`).
+fn media_reply(
+    options: Option<&AttachmentOptions>,
+) -> Result<Option<matrix_sdk::room::reply::Reply>, FfiError> {
+    use matrix_sdk::room::reply::{EnforceThread, Reply};
+    use matrix_sdk::ruma::events::room::message::ReplyWithinThread;
+
+    let Some(opts) = options else {
+        return Ok(None);
+    };
+    let reply_to = opts
+        .reply_to_event_id
+        .as_deref()
+        .map(EventId::parse)
+        .transpose()?;
+    let root = opts
+        .thread_root_event_id
+        .as_deref()
+        .map(EventId::parse)
+        .transpose()?;
+
+    Ok(match (reply_to, root) {
+        (Some(event_id), Some(_)) => Some(Reply {
+            event_id,
+            enforce_thread: EnforceThread::Threaded(ReplyWithinThread::Yes),
+        }),
+        (Some(event_id), None) => Some(Reply {
+            event_id,
+            enforce_thread: EnforceThread::MaybeThreaded,
+        }),
+        (None, Some(root)) => Some(Reply {
+            event_id: root,
+            enforce_thread: EnforceThread::Threaded(ReplyWithinThread::No),
+        }),
+        (None, None) => None,
     })
 }
 
-fn extract_attachment(msg: &matrix_sdk_ui::timeline::Message) -> Option<AttachmentInfo> {
-    use matrix_sdk::ruma::events::room::{MediaSource, message::MessageType as MT};
+fn map_recovery_state(state: matrix_sdk::encryption::recovery::RecoveryState) -> RecoveryState {
+    use matrix_sdk::encryption::recovery::RecoveryState as Sdk;
 
-    // Helper: split a MediaSource into MXC URI and optional EncFile
-    fn split_source(source: &MediaSource) -> (String, Option<EncFile>) {
-        match source {
-            MediaSource::Plain(url) => (url.to_string(), None),
-            MediaSource::Encrypted(file) => {
-                let url = file.url.to_string();
-                let enc = enc_to_record(file.as_ref());
-                (url, Some(enc))
-            }
+    match state {
+        Sdk::Enabled => RecoveryState::Enabled,
+        Sdk::Disabled => RecoveryState::Disabled,
+        Sdk::Incomplete => RecoveryState::Incomplete,
+        _ => RecoveryState::Unknown,
+    }
+}
+
+/// UIA stage re-entering the account password.
+fn password_auth(
+    user_id: &ruma::UserId,
+    password: String,
+    session: Option<String>,
+) -> ruma::api::client::uiaa::AuthData {
+    use ruma::api::client::uiaa::{AuthData, Password, UserIdentifier};
+
+    let mut pw = Password::new(
+        UserIdentifier::UserIdOrLocalpart(user_id.to_string()),
+        password,
+    );
+    pw.session = session;
+    AuthData::Password(pw)
+}
+
+/// A `/devices` entry; ruma's type drops the MSC3852 user agent.
+#[derive(Clone, Debug, Deserialize)]
+struct ServerDevice {
+    device_id: String,
+    display_name: Option<String>,
+    last_seen_ip: Option<String>,
+    last_seen_ts: Option<u64>,
+    last_seen_user_agent: Option<String>,
+    #[serde(rename = "org.matrix.msc3852.last_seen_user_agent")]
+    unstable_last_seen_user_agent: Option<String>,
+}
+
+/// `GET /devices` as ruma sends it, answered with `ServerDevice`s so the
+/// user agent survives.
+#[derive(Clone, Debug)]
+struct GetDevicesRequest;
+
+struct GetDevicesResponse {
+    devices: Vec<ServerDevice>,
+}
+
+impl ruma::api::OutgoingRequest for GetDevicesRequest {
+    type EndpointError = ruma::api::client::Error;
+    type IncomingResponse = GetDevicesResponse;
+
+    const METADATA: ruma::api::Metadata =
+        <ruma::api::client::device::get_devices::v3::Request as ruma::api::OutgoingRequest>::METADATA;
+
+    fn try_into_http_request<T: Default + ruma::exports::bytes::BufMut>(
+        self,
+        base_url: &str,
+        access_token: ruma::api::SendAccessToken<'_>,
+        considering: &'_ ruma::api::SupportedVersions,
+    ) -> Result<ruma::exports::http::Request<T>, ruma::api::error::IntoHttpError> {
+        ruma::api::client::device::get_devices::v3::Request::new().try_into_http_request(
+            base_url,
+            access_token,
+            considering,
+        )
+    }
+}
+
+impl ruma::api::IncomingResponse for GetDevicesResponse {
+    type EndpointError = ruma::api::client::Error;
+
+    fn try_from_http_response<T: AsRef<[u8]>>(
+        response: ruma::exports::http::Response<T>,
+    ) -> Result<Self, ruma::api::error::FromHttpResponseError<Self::EndpointError>> {
+        use ruma::api::{EndpointError, error::FromHttpResponseError};
+
+        #[derive(Deserialize)]
+        struct Body {
+            devices: Vec<ServerDevice>,
+        }
+
+        if response.status().as_u16() >= 400 {
+            return Err(FromHttpResponseError::Server(
+                ruma::api::client::Error::from_http_response(response),
+            ));
         }
+        let body: Body = serde_json::from_slice(response.body().as_ref())?;
+        Ok(Self {
+            devices: body.devices,
+        })
     }
+}
 
-    // Helper: same, but for Option<&MediaSource> (used for thumbnails)
-    fn split_opt_source(source: Option<&MediaSource>) -> (Option<String>, Option<EncFile>) {
-        match source {
-            Some(MediaSource::Plain(url)) => (Some(url.to_string()), None),
-            Some(MediaSource::Encrypted(file)) => {
-                let url = file.url.to_string();
-                let enc = enc_to_record(file.as_ref());
-                (Some(url), Some(enc))
-            }
-            None => (None, None),
+async fn fetch_server_devices(client: &SdkClient) -> Result<Vec<ServerDevice>, FfiError> {
+    let resp = client.send(GetDevicesRequest).await?;
+    Ok(resp
+        .devices
+        .into_iter()
+        .map(|mut d| {
+            d.last_seen_user_agent = d
+                .last_seen_user_agent
+                .or(d.unstable_last_seen_user_agent.take());
+            d
+        })
+        .collect())
+}
+
+/// `identity_changed`: the identity no longer matches the pinned one, see
+/// `pin_violation_in_rooms`.
+fn map_user_identity(
+    identity: &matrix_sdk::encryption::identities::UserIdentity,
+    identity_changed: bool,
+) -> UserIdentityInfo {
+    let trust = if identity.is_verified() {
+        IdentityTrust::Verified
+    } else if identity.has_verification_violation() {
+        IdentityTrust::VerificationViolation
+    } else if identity_changed {
+        IdentityTrust::IdentityChanged
+    } else {
+        IdentityTrust::Pinned
+    };
+    UserIdentityInfo {
+        user_id: identity.user_id().to_string(),
+        master_key: identity.master_key().get_first_key().map(|k| k.to_base64()),
+        trust,
+        was_previously_verified: identity.was_previously_verified(),
+    }
+}
+
+/// The SDK only exposes pin violations per room: the first item of a room's
+/// identity status stream lists its members in a non-pinned state. Any
+/// encrypted room shared with `user_id` will do.
+async fn pin_violation_in_rooms(client: &SdkClient, user_id: &ruma::UserId) -> bool {
+    use futures_util::{FutureExt, pin_mut};
+    use matrix_sdk_crypto::IdentityState;
+
+    for room in client.joined_rooms() {
+        if !room
+            .latest_encryption_state()
+            .await
+            .map(|s| s.is_encrypted())
+            .unwrap_or(false)
+        {
+            continue;
         }
+        if !matches!(room.get_member_no_sync(user_id).await, Ok(Some(_))) {
+            continue;
+        }
+        let Ok(stream) = room.subscribe_to_identity_status_changes().await else {
+            continue;
+        };
+        pin_mut!(stream);
+        // An empty initial state is not yielded, so don't wait for one.
+        let initial = stream.next().now_or_never().flatten().unwrap_or_default();
+        return initial
+            .iter()
+            .any(|c| c.user_id == user_id && c.changed_to == IdentityState::PinViolation);
+    }
+    false
+}
+
+fn map_identity_state(state: matrix_sdk_crypto::IdentityState) -> IdentityTrust {
+    use matrix_sdk_crypto::IdentityState;
+
+    match state {
+        IdentityState::Verified => IdentityTrust::Verified,
+        IdentityState::Pinned => IdentityTrust::Pinned,
+        IdentityState::PinViolation => IdentityTrust::IdentityChanged,
+        IdentityState::VerificationViolation => IdentityTrust::VerificationViolation,
+    }
+}
+
+fn map_backup_state(state: matrix_sdk::encryption::backups::BackupState) -> BackupState {
+    use matrix_sdk::encryption::backups::BackupState as Sdk;
+
+    match state {
+        Sdk::Creating => BackupState::Creating,
+        Sdk::Enabling => BackupState::Enabling,
+        Sdk::Resuming => BackupState::Resuming,
+        Sdk::Enabled => BackupState::Enabled,
+        Sdk::Downloading => BackupState::Downloading,
+        Sdk::Disabling => BackupState::Disabling,
+        _ => BackupState::Unknown,
     }
+}
+
+fn map_location(msgtype: &MessageType) -> Option<LocationInfo> {
+    use ruma::events::location::AssetType;
+
+    let MessageType::Location(c) = msgtype else {
+        return None;
+    };
+    // Prefer the MSC3488 block, which carries description and zoom.
+    let geo_uri = c
+        .location
+        .as_ref()
+        .map(|l| l.uri.clone())
+        .unwrap_or_else(|| c.geo_uri.clone());
+    let (latitude, longitude) = parse_geo_uri(&geo_uri).unzip();
+    Some(LocationInfo {
+        latitude,
+        longitude,
+        description: c.location.as_ref().and_then(|l| l.description.clone()),
+        zoom_level: c
+            .location
+            .as_ref()
+            .and_then(|l| l.zoom_level.as_ref())
+            .map(|z| u64::from(z.get()) as u32),
+        is_pin: c.asset.as_ref().is_some_and(|a| a.type_ == AssetType::Pin),
+        geo_uri,
+    })
+}
 
-    match msg.msgtype() {
-        MT::Image(c) => {
-            // main image source
-            let (mxc_uri, encrypted) = split_source(&c.source);
+/// Latitude and longitude of a `geo:lat,lon[,alt][;params]` URI (RFC 5870).
+fn parse_geo_uri(uri: &str) -> Option<(f64, f64)> {
+    let coords = uri.strip_prefix("geo:")?.split(';').next()?;
+    let mut parts = coords.split(',');
+    let lat: f64 = parts.next()?.trim().parse().ok()?;
+    let lon: f64 = parts.next()?.trim().parse().ok()?;
+    ((-90.0..=90.0).contains(&lat) && (-180.0..=180.0).contains(&lon)).then_some((lat, lon))
+}
 
-            // metadata + thumbnail
-            let (w, h, size, mime, thumb_mxc, thumb_enc) = c
-                .info
-                .as_ref()
-                .map(|info| {
-                    let (thumb_mxc, thumb_enc) = split_opt_source(info.thumbnail_source.as_ref());
-                    (
-                        info.width.map(|v| u32::try_from(v).unwrap_or(0)),
-                        info.height.map(|v| u32::try_from(v).unwrap_or(0)),
-                        info.size.map(u64::from),
-                        info.mimetype.clone(),
-                        thumb_mxc,
-                        thumb_enc,
-                    )
-                })
-                .unwrap_or((None, None, None, None, None, None));
+fn sticker_attachment(c: &ruma::events::sticker::StickerEventContent) -> AttachmentInfo {
+    let (mxc_uri, encrypted) = match MediaSource::from(c.source.clone()) {
+        MediaSource::Plain(url) => (url.to_string(), None),
+        MediaSource::Encrypted(file) => (file.url.to_string(), Some(enc_to_record(&file))),
+    };
+    AttachmentInfo {
+        kind: AttachmentKind::Sticker,
+        mxc_uri,
+        mime: c.info.mimetype.clone(),
+        size_bytes: c.info.size.map(u64::from),
+        width: c.info.width.map(|v| u32::try_from(v).unwrap_or(0)),
+        height: c.info.height.map(|v| u32::try_from(v).unwrap_or(0)),
+        duration_ms: None,
+        thumbnail_mxc_uri: None,
+        encrypted,
+        thumbnail_encrypted: None,
+        filename: None,
+        caption: Some(c.body.clone()).filter(|b| !b.is_empty()),
+        is_voice: false,
+        waveform: vec![],
+    }
+}
 
-            Some(AttachmentInfo {
-                kind: AttachmentKind::Image,
-                mxc_uri,
-                mime,
-                size_bytes: size,
-                width: w,
-                height: h,
-                duration_ms: None,
-                thumbnail_mxc_uri: thumb_mxc,
-                encrypted,
-                thumbnail_encrypted: thumb_enc,
-            })
-        }
+fn sticker_content(
+    body: String,
+    mxc_uri: String,
+    info: ImageInfo,
+) -> ruma::events::sticker::StickerEventContent {
+    ruma::events::sticker::StickerEventContent::new(body, info, mxc_uri.into())
+}
 
-        MT::Video(c) => {
-            let (mxc_uri, encrypted) = split_source(&c.source);
+fn image_info(att: &AttachmentInfo) -> ImageInfo {
+    let mut info = ImageInfo::new();
+    info.mimetype = att.mime.clone();
+    info.size = att.size_bytes.and_then(UInt::new);
+    info.width = att.width.map(UInt::from);
+    info.height = att.height.map(UInt::from);
+    info
+}
 
-            let (w, h, size, mime, dur, thumb_mxc, thumb_enc) = c
-                .info
-                .as_ref()
-                .map(|info| {
-                    let (thumb_mxc, thumb_enc) = split_opt_source(info.thumbnail_source.as_ref());
-                    (
-                        info.width.map(|v| u32::try_from(v).unwrap_or(0)),
-                        info.height.map(|v| u32::try_from(v).unwrap_or(0)),
-                        info.size.map(u64::from),
-                        info.mimetype.clone(),
-                        info.duration.map(|d| d.as_millis() as u64),
-                        thumb_mxc,
-                        thumb_enc,
-                    )
-                })
-                .unwrap_or((None, None, None, None, None, None, None));
+/// `im.ponies.user_emotes` / `im.ponies.room_emotes` content (MSC2545).
+#[derive(Default, Deserialize)]
+struct RawImagePack {
+    #[serde(default)]
+    images: BTreeMap<String, RawPackImage>,
+    #[serde(default)]
+    pack: RawPackInfo,
+}
 
-            Some(AttachmentInfo {
-                kind: AttachmentKind::Video,
-                mxc_uri: mxc_uri.clone(),
-                mime,
-                size_bytes: size,
-                width: w,
-                height: h,
-                duration_ms: dur,
-                // Fallback to full video if no explicit thumbnail
-                thumbnail_mxc_uri: thumb_mxc.or_else(|| Some(mxc_uri.clone())),
-                encrypted,
-                thumbnail_encrypted: thumb_enc,
-            })
-        }
+#[derive(Deserialize)]
+struct RawPackImage {
+    url: String,
+    body: Option<String>,
+    info: Option<RawPackImageInfo>,
+    usage: Option<Vec<String>>,
+}
 
-        MT::File(c) => {
-            let (mxc_uri, encrypted) = split_source(&c.source);
+#[derive(Deserialize)]
+struct RawPackImageInfo {
+    mimetype: Option<String>,
+    size: Option<u64>,
+    w: Option<u32>,
+    h: Option<u32>,
+}
 
-            let (size, mime, thumb_mxc, thumb_enc) = c
-                .info
-                .as_ref()
-                .map(|info| {
-                    let (thumb_mxc, thumb_enc) = split_opt_source(info.thumbnail_source.as_ref());
-                    (
-                        info.size.map(u64::from),
-                        info.mimetype.clone(),
-                        thumb_mxc,
-                        thumb_enc,
-                    )
-                })
-                .unwrap_or((None, None, None, None));
+#[derive(Default, Deserialize)]
+struct RawPackInfo {
+    display_name: Option<String>,
+    avatar_url: Option<String>,
+    attribution: Option<String>,
+    usage: Option<Vec<String>>,
+}
 
-            Some(AttachmentInfo {
-                kind: AttachmentKind::File,
-                mxc_uri,
-                mime,
-                size_bytes: size,
-                width: None,
-                height: None,
-                duration_ms: None,
-                thumbnail_mxc_uri: thumb_mxc,
-                encrypted,
-                thumbnail_encrypted: thumb_enc,
-            })
-        }
+/// `im.ponies.emote_rooms`: room id → state keys of packs enabled everywhere.
+#[derive(Default, Deserialize)]
+struct RawEmoteRooms {
+    #[serde(default)]
+    rooms: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
 
-        _ => None,
+fn map_image_pack(
+    pack: RawImagePack,
+    id: String,
+    room_id: Option<String>,
+    state_key: Option<String>,
+) -> ImagePack {
+    // No usage on an image means the pack's; none on the pack means both.
+    let usage = |u: &Option<Vec<String>>| {
+        u.as_ref().map(|u| {
+            (
+                u.iter().any(|x| x == "emoticon"),
+                u.iter().any(|x| x == "sticker"),
+            )
+        })
+    };
+    let pack_usage = usage(&pack.pack.usage).unwrap_or((true, true));
+
+    let images = pack
+        .images
+        .into_iter()
+        .filter(|(_, img)| img.url.starts_with("mxc://"))
+        .map(|(shortcode, img)| {
+            let (is_emoticon, is_sticker) = usage(&img.usage).unwrap_or(pack_usage);
+            PackImage {
+                shortcode,
+                mxc_uri: img.url,
+                body: img.body,
+                mime: img.info.as_ref().and_then(|i| i.mimetype.clone()),
+                size_bytes: img.info.as_ref().and_then(|i| i.size),
+                width: img.info.as_ref().and_then(|i| i.w),
+                height: img.info.as_ref().and_then(|i| i.h),
+                is_emoticon,
+                is_sticker,
+            }
+        })
+        .collect();
+
+    ImagePack {
+        id,
+        room_id,
+        state_key,
+        display_name: pack.pack.display_name,
+        avatar_url: pack.pack.avatar_url,
+        attribution: pack.pack.attribution,
+        images,
     }
 }
 
+/// Packs defined in a room's `im.ponies.room_emotes` state, one per state key.
+async fn room_image_packs(room: &Room) -> Result<Vec<ImagePack>, FfiError> {
+    use matrix_sdk::deserialized_responses::RawAnySyncOrStrippedState;
+    use ruma::events::StateEventType;
+
+    #[derive(Deserialize)]
+    struct Envelope {
+        state_key: String,
+        #[serde(default)]
+        content: RawImagePack,
+    }
+
+    let events = room
+        .get_state_events(StateEventType::from("im.ponies.room_emotes"))
+        .await?;
+    let packs = events
+        .iter()
+        .filter_map(|raw| {
+            let json = match raw {
+                RawAnySyncOrStrippedState::Sync(ev) => ev.json().get(),
+                RawAnySyncOrStrippedState::Stripped(ev) => ev.json().get(),
+            };
+            serde_json::from_str::<Envelope>(json).ok()
+        })
+        .filter(|ev| !ev.content.images.is_empty())
+        .map(|ev| {
+            let id = format!("{}/{}", room.room_id(), ev.state_key);
+            map_image_pack(
+                ev.content,
+                id,
+                Some(room.room_id().to_string()),
+                Some(ev.state_key),
+            )
+        })
+        .collect();
+    Ok(packs)
+}
+
 fn enc_to_record(ef: &EncryptedFile) -> EncFile {
     EncFile {
         url: ef.url.to_string(),
@@ -5015,19 +9827,18 @@ fn enc_to_record(ef: &EncryptedFile) -> EncFile {
 
 async fn map_event_id_via_timeline(
     client: &SdkClient,
+    timelines: &TimelineCache,
     rid: &ruma::OwnedRoomId,
     eid: &ruma::OwnedEventId,
 ) -> Option<MessageEvent> {
-    let Some(tl) = get_timeline_for(client, rid).await else {
-        return None;
-    };
+    let tl = get_timeline_for(client, timelines, rid).await?;
     let _ = tl.fetch_details_for_event(eid.as_ref()).await;
     let item = tl.item_by_event_id(eid).await?;
     let item_id = match item.identifier() {
         TimelineEventItemId::EventId(id) => id.to_string(),
         TimelineEventItemId::TransactionId(id) => id.to_string(),
     };
-    map_timeline_event(&item, rid.as_str(), Some(&item_id))
+    map_timeline_event(&item, rid.as_str(), Some(&item_id), client.user_id())
 }
 
 fn render_timeline_text(ev: &EventTimelineItem) -> String {
@@ -5047,22 +9858,599 @@ fn render_timeline_text(ev: &EventTimelineItem) -> String {
     }
 }
 
-fn render_msg_like(_ev: &EventTimelineItem, ml: &MsgLikeContent) -> String {
-    use MsgLikeKind::*;
-    match &ml.kind {
-        Message(m) => render_message_text(m),
-        Sticker(_s) => "sent a sticker".to_string(),
-        Poll(_p) => "started a poll".to_string(),
-        Redacted => "Message deleted".to_string(),
-        UnableToDecrypt(_e) => "Unable to decrypt this message".to_string(),
+fn render_msg_like(_ev: &EventTimelineItem, ml: &MsgLikeContent) -> String {
+    use MsgLikeKind::*;
+    match &ml.kind {
+        Message(m) => render_message_text(m),
+        Sticker(s) if !s.content().body.trim().is_empty() => s.content().body.clone(),
+        Sticker(_) => "sent a sticker".to_string(),
+        Poll(_p) => "started a poll".to_string(),
+        Redacted => "Message deleted".to_string(),
+        UnableToDecrypt(_e) => "Unable to decrypt this message".to_string(),
+    }
+}
+
+const MAGES_CLIENT_URI: &str = "https://github.com/mlm-games/Mages";
+
+/// Wait for `fut`, giving up on `cancel_browser_auth` or after `BROWSER_AUTH_TIMEOUT`.
+async fn await_browser<F: std::future::IntoFuture>(
+    fut: F,
+    cancel: tokio::sync::oneshot::Receiver<()>,
+    what: &str,
+) -> Result<F::Output, FfiError> {
+    tokio::select! {
+        out = fut.into_future() => Ok(out),
+        Ok(()) = cancel => Err(FfiError::Msg(format!("{what} cancelled"))),
+        _ = tokio::time::sleep(BROWSER_AUTH_TIMEOUT) => Err(FfiError::Network {
+            message: format!("{what} timed out"),
+        }),
+    }
+}
+
+/// Without a `redirect_uri` the client registers for the device code grant
+/// only, as used by QR login.
+fn oauth_registration_data(
+    redirect_uri: Option<&matrix_sdk::reqwest::Url>,
+    client_name: Option<String>,
+) -> Result<matrix_sdk::authentication::oauth::ClientRegistrationData, FfiError> {
+    use matrix_sdk::authentication::oauth::registration::{
+        ApplicationType, ClientMetadata, Localized, OAuthGrantType,
+    };
+    use matrix_sdk::reqwest::Url;
+
+    let client_uri =
+        Url::parse(MAGES_CLIENT_URI).map_err(|e| FfiError::Msg(format!("client URI: {e}")))?;
+    let mut grant_types = vec![OAuthGrantType::DeviceCode];
+    if let Some(uri) = redirect_uri {
+        grant_types.push(OAuthGrantType::AuthorizationCode {
+            redirect_uris: vec![uri.clone()],
+        });
+    }
+    let mut metadata = ClientMetadata::new(
+        ApplicationType::Native,
+        grant_types,
+        Localized::new(client_uri, None),
+    );
+    metadata.client_name = Some(Localized::new(
+        client_name.unwrap_or_else(|| "Mages".to_owned()),
+        None,
+    ));
+
+    let raw = ruma::serde::Raw::new(&metadata)?;
+    Ok(raw.into())
+}
+
+/// Save the session so refreshed tokens survive restarts: into the state store
+/// when it is encrypted, else to `session.json`. Once the store holds it, the
+/// plaintext file is deleted.
+async fn persist_session(
+    client: &SdkClient,
+    store_dir: &Path,
+    store_encrypted: bool,
+) -> Result<(), FfiError> {
+    use matrix_sdk_base::StateStore;
+
+    let (Some(meta), Some(tokens)) = (client.session_meta(), client.session_tokens()) else {
+        return Ok(());
+    };
+    let info = SessionInfo {
+        user_id: meta.user_id.to_string(),
+        device_id: meta.device_id.to_string(),
+        access_token: tokens.access_token,
+        refresh_token: tokens.refresh_token,
+        homeserver: client.homeserver().to_string(),
+        oauth_client_id: client.oauth().client_id().map(|c| c.as_str().to_owned()),
+    };
+    let bytes = serde_json::to_vec(&info)?;
+    if !store_encrypted {
+        tokio::fs::write(session_file(store_dir), bytes).await?;
+        // Drop a plaintext copy an older version may have left in the store.
+        let _ = client
+            .state_store()
+            .remove_custom_value(SESSION_STORE_KEY)
+            .await;
+        return Ok(());
+    }
+    client
+        .state_store()
+        .set_custom_value(SESSION_STORE_KEY, bytes)
+        .await
+        .map_err(|e| FfiError::Store {
+            message: format!("store session: {e}"),
+        })?;
+    let _ = tokio::fs::remove_file(session_file(store_dir)).await;
+    Ok(())
+}
+
+/// Read the saved session, from the state store or `session.json`. A session
+/// in the wrong place for the store (`session.json` next to an encrypted store,
+/// or the store value of an unencrypted one) is moved, and the old copy only
+/// deleted once the new one is written.
+async fn load_session(
+    client: &SdkClient,
+    store_dir: &Path,
+    store_encrypted: bool,
+) -> Option<SessionInfo> {
+    use matrix_sdk_base::StateStore;
+
+    let path = session_file(store_dir);
+    if let Ok(Some(bytes)) = client
+        .state_store()
+        .get_custom_value(SESSION_STORE_KEY)
+        .await
+        && let Ok(info) = serde_json::from_slice::<SessionInfo>(&bytes)
+    {
+        if !store_encrypted && tokio::fs::write(&path, &bytes).await.is_ok() {
+            let _ = client
+                .state_store()
+                .remove_custom_value(SESSION_STORE_KEY)
+                .await;
+            info!("load_session: moved the session from the unencrypted store to session.json");
+        }
+        return Some(info);
+    }
+
+    let txt = tokio::fs::read_to_string(&path).await.ok()?;
+    let info = serde_json::from_str::<SessionInfo>(&txt).ok()?;
+    if store_encrypted
+        && client
+            .state_store()
+            .set_custom_value(SESSION_STORE_KEY, txt.into_bytes())
+            .await
+            .is_ok()
+    {
+        let _ = tokio::fs::remove_file(&path).await;
+        info!("load_session: migrated session.json into the store");
+    }
+    Some(info)
+}
+
+fn search_tokens(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_lowercase())
+        .collect()
+}
+
+/// Load the saved index. With an unencrypted store nothing is kept, and any
+/// index an older version saved there is deleted.
+async fn load_search_index(client: &SdkClient, store_encrypted: bool) -> SearchIndex {
+    use matrix_sdk_base::StateStore;
+
+    let store = client.state_store();
+    let rooms: Vec<String> = match store.get_custom_value(SEARCH_INDEX_ROOMS_KEY).await {
+        Ok(Some(bytes)) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        _ => Vec::new(),
+    };
+
+    if !store_encrypted {
+        for room in &rooms {
+            let _ = store
+                .remove_custom_value(&search_index_room_key(room))
+                .await;
+        }
+        let _ = store.remove_custom_value(SEARCH_INDEX_ROOMS_KEY).await;
+        let _ = store.remove_custom_value(LEGACY_SEARCH_INDEX_KEY).await;
+        return SearchIndex::default();
+    }
+
+    let mut idx = SearchIndex::default();
+    for room in &rooms {
+        let Ok(Some(bytes)) = store.get_custom_value(&search_index_room_key(room)).await else {
+            continue;
+        };
+        match serde_json::from_slice::<Vec<IndexedMessage>>(&bytes) {
+            Ok(docs) => docs.into_iter().for_each(|d| idx.insert(d)),
+            Err(e) => warn!("search index: dropping unreadable entry for {room}: {e}"),
+        }
+    }
+    idx.dirty.clear();
+
+    if let Ok(Some(bytes)) = store.get_custom_value(LEGACY_SEARCH_INDEX_KEY).await {
+        if let Ok(docs) = serde_json::from_slice::<Vec<IndexedMessage>>(&bytes) {
+            docs.into_iter().for_each(|d| idx.insert(d));
+        }
+        let (changed, all) = idx.take_dirty();
+        let failed = save_search_index_rooms(client, changed, all).await;
+        if failed.is_empty() {
+            let _ = store.remove_custom_value(LEGACY_SEARCH_INDEX_KEY).await;
+            info!("search index: migrated to per-room entries");
+        }
+        idx.dirty.extend(failed);
+    }
+    idx
+}
+
+async fn load_url_previews(client: &SdkClient, cache: &Mutex<UrlPreviewCache>) {
+    use matrix_sdk_base::StateStore;
+
+    if cache.lock().unwrap().loaded {
+        return;
+    }
+    let stored = match client
+        .state_store()
+        .get_custom_value(URL_PREVIEW_STORE_KEY)
+        .await
+    {
+        Ok(Some(bytes)) => serde_json::from_slice::<UrlPreviewCache>(&bytes).unwrap_or_else(|e| {
+            warn!("url previews: dropping unreadable cache: {e}");
+            UrlPreviewCache::default()
+        }),
+        _ => UrlPreviewCache::default(),
+    };
+    let mut guard = cache.lock().unwrap();
+    if !guard.loaded {
+        *guard = UrlPreviewCache {
+            loaded: true,
+            ..stored
+        };
+    }
+}
+
+async fn save_url_previews(client: &SdkClient, cache: &Mutex<UrlPreviewCache>) {
+    use matrix_sdk_base::StateStore;
+
+    let Ok(bytes) = serde_json::to_vec(&*cache.lock().unwrap()) else {
+        return;
+    };
+    if let Err(e) = client
+        .state_store()
+        .set_custom_value(URL_PREVIEW_STORE_KEY, bytes)
+        .await
+    {
+        warn!("url previews: save failed: {e}");
+    }
+}
+
+/// Ask the homeserver for a preview, on the authenticated media endpoint
+/// (MSC3916) or the legacy one.
+#[allow(deprecated)]
+async fn fetch_url_preview(
+    client: &SdkClient,
+    url: &str,
+    ts_ms: Option<u64>,
+) -> Result<Option<UrlPreview>, FfiError> {
+    use ruma::api::client::{
+        authenticated_media::get_media_preview::v1 as preview_v1, error::ErrorKind,
+        media::get_media_preview::v3 as preview_v3,
+    };
+
+    let ts = ts_ms
+        .and_then(UInt::new)
+        .map(ruma::MilliSecondsSinceUnixEpoch);
+
+    let mut req = preview_v1::Request::new(url.to_owned());
+    req.ts = ts;
+    let data = match client.send(req).await {
+        Ok(resp) => resp.data,
+        Err(e)
+            if matches!(
+                e.client_api_error_kind(),
+                Some(ErrorKind::Unrecognized | ErrorKind::NotFound)
+            ) =>
+        {
+            let mut req = preview_v3::Request::new(url.to_owned());
+            req.ts = ts;
+            client.send(req).await?.data
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let Some(data) = data else {
+        return Ok(None);
+    };
+
+    let og: serde_json::Map<String, serde_json::Value> = serde_json::from_str(data.get())?;
+    let text = |key: &str| {
+        og.get(key)
+            .and_then(|v| v.as_str())
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .map(str::to_owned)
+    };
+    // Servers send dimensions as numbers, some as strings.
+    let number = |key: &str| {
+        og.get(key).and_then(|v| match v {
+            serde_json::Value::Number(n) => n.as_u64().and_then(|n| u32::try_from(n).ok()),
+            serde_json::Value::String(s) => s.parse().ok(),
+            _ => None,
+        })
+    };
+
+    let preview = UrlPreview {
+        url: url.to_owned(),
+        title: text("og:title"),
+        description: text("og:description"),
+        site_name: text("og:site_name"),
+        image_mxc: text("og:image").filter(|u| u.starts_with("mxc://")),
+        image_width: number("og:image:width"),
+        image_height: number("og:image:height"),
+    };
+    if preview.title.is_none() && preview.description.is_none() && preview.image_mxc.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(preview))
+}
+
+/// http(s) links in plain text, without trailing punctuation, deduplicated.
+fn detect_links(body: &str) -> Vec<String> {
+    let mut out: Vec<String> = Vec::new();
+    for word in body.split_whitespace() {
+        let Some(start) = word.find("https://").or_else(|| word.find("http://")) else {
+            continue;
+        };
+        let link = word[start..].trim_end_matches(|c: char| ".,;:!?'\")]}>*_".contains(c));
+        let (_, host) = link.split_once("://").unwrap_or_default();
+        if host.is_empty() || out.iter().any(|l| l == link) {
+            continue;
+        }
+        out.push(link.to_owned());
+    }
+    out
+}
+
+/// Save the rooms whose index changed since the last flush. Nothing is saved
+/// into an unencrypted store.
+async fn flush_search_index(client: &SdkClient, index: &SearchIndexHandle, store_encrypted: bool) {
+    let (changed, all) = {
+        let mut guard = index.lock().unwrap();
+        if !store_encrypted {
+            guard.dirty.clear();
+        }
+        if guard.dirty.is_empty() {
+            return;
+        }
+        guard.take_dirty()
+    };
+    let failed = save_search_index_rooms(client, changed, all).await;
+    index.lock().unwrap().dirty.extend(failed);
+}
+
+/// Write (or, when empty, delete) the given rooms' entries and the room list.
+/// Returns the rooms that couldn't be saved.
+async fn save_search_index_rooms(
+    client: &SdkClient,
+    changed: Vec<(String, Vec<IndexedMessage>)>,
+    all: Vec<String>,
+) -> Vec<String> {
+    use matrix_sdk_base::StateStore;
+
+    let store = client.state_store();
+    let mut failed = Vec::new();
+    for (room, docs) in changed {
+        let key = search_index_room_key(&room);
+        let res = if docs.is_empty() {
+            store.remove_custom_value(&key).await.map(|_| ())
+        } else {
+            let Ok(bytes) = serde_json::to_vec(&docs) else {
+                continue;
+            };
+            store.set_custom_value(&key, bytes).await.map(|_| ())
+        };
+        if let Err(e) = res {
+            warn!("search index: saving {room} failed: {e}");
+            failed.push(room);
+        }
+    }
+    if let Ok(bytes) = serde_json::to_vec(&all)
+        && let Err(e) = store.set_custom_value(SEARCH_INDEX_ROOMS_KEY, bytes).await
+    {
+        warn!("search index: saving the room list failed: {e}");
+    }
+    failed
+}
+
+/// Index a decrypted message from sync; edits update the original's entry.
+fn index_sync_message(
+    index: &SearchIndexHandle,
+    room_id: &ruma::RoomId,
+    ev: &ruma::events::room::message::OriginalSyncRoomMessageEvent,
+) {
+    let mut guard = index.lock().unwrap();
+    if let Some(MsgRelation::Replacement(edit)) = &ev.content.relates_to {
+        if let Some(orig) = guard.docs.get(edit.event_id.as_str()).cloned() {
+            guard.insert(IndexedMessage {
+                body: edit.new_content.msgtype.body().to_owned(),
+                ..orig
+            });
+        }
+        return;
+    }
+    guard.insert(IndexedMessage {
+        event_id: ev.event_id.to_string(),
+        room_id: room_id.to_string(),
+        sender: ev.sender.to_string(),
+        body: strip_reply_fallback(ev.content.body()),
+        timestamp_ms: ev.origin_server_ts.0.into(),
+    });
+}
+
+/// Index (or, if redacted, drop) a timeline message. Returns true if it is indexed.
+fn index_timeline_item(
+    index: &SearchIndexHandle,
+    room_id: &OwnedRoomId,
+    ev: &EventTimelineItem,
+) -> bool {
+    let Some(event_id) = ev.event_id() else {
+        return false;
+    };
+    let TimelineItemContent::MsgLike(ml) = ev.content() else {
+        return false;
+    };
+    if matches!(ml.kind, MsgLikeKind::Redacted) {
+        index.lock().unwrap().remove(event_id.as_str());
+        return false;
+    }
+    let Some(msg) = ml.as_message() else {
+        return false;
+    };
+    index.lock().unwrap().insert(IndexedMessage {
+        event_id: event_id.to_string(),
+        room_id: room_id.to_string(),
+        sender: ev.sender().to_string(),
+        body: strip_reply_fallback(msg.body()),
+        timestamp_ms: ev.timestamp().0.into(),
+    });
+    true
+}
+
+fn index_vector_diff(
+    index: &SearchIndexHandle,
+    room_id: &OwnedRoomId,
+    diff: &VectorDiff<Arc<TimelineItem>>,
+) {
+    let items: Vec<&Arc<TimelineItem>> = match diff {
+        VectorDiff::Append { values } | VectorDiff::Reset { values } => values.iter().collect(),
+        VectorDiff::PushBack { value }
+        | VectorDiff::PushFront { value }
+        | VectorDiff::Insert { value, .. }
+        | VectorDiff::Set { value, .. } => vec![value],
+        _ => vec![],
+    };
+    for it in items {
+        if let Some(ev) = it.as_event() {
+            index_timeline_item(index, room_id, ev);
+        }
+    }
+}
+
+fn indexed_message_event(doc: &IndexedMessage) -> MessageEvent {
+    MessageEvent {
+        item_id: doc.event_id.clone(),
+        event_id: doc.event_id.clone(),
+        room_id: doc.room_id.clone(),
+        sender: doc.sender.clone(),
+        body: doc.body.clone(),
+        timestamp_ms: doc.timestamp_ms,
+        send_state: None,
+        txn_id: None,
+        reply_to_event_id: None,
+        reply_to_sender: None,
+        reply_to_body: None,
+        attachment: None,
+        thread_root_event_id: None,
+        formatted_body: None,
+        mentioned_user_ids: vec![],
+        mentions_room: false,
+        kind: MessageKind::Text,
+        reactions: vec![],
+        edited: false,
+        edit_history: vec![],
+        read_by: vec![],
+        shield: None,
+        send_error: None,
+        profile_change: None,
+        links: detect_links(&doc.body),
+        location: None,
+    }
+}
+
+/// Map a raw `m.room.message` (e.g. a `/search` result) without going through a timeline.
+fn map_raw_message(raw: &ruma::serde::Raw<ruma::events::AnyTimelineEvent>) -> Option<MessageEvent> {
+    use ruma::events::{AnyMessageLikeEvent, AnyTimelineEvent, MessageLikeEvent};
+
+    let AnyTimelineEvent::MessageLike(AnyMessageLikeEvent::RoomMessage(
+        MessageLikeEvent::Original(ev),
+    )) = raw.deserialize().ok()?
+    else {
+        return None;
+    };
+
+    let (reply_to_event_id, thread_root_event_id) = match &ev.content.relates_to {
+        Some(MsgRelation::Reply { in_reply_to }) => (Some(in_reply_to.event_id.to_string()), None),
+        Some(MsgRelation::Thread(t)) => (
+            t.in_reply_to.as_ref().map(|r| r.event_id.to_string()),
+            Some(t.event_id.to_string()),
+        ),
+        _ => (None, None),
+    };
+    let body = if reply_to_event_id.is_some() {
+        strip_reply_fallback(ev.content.body())
+    } else {
+        ev.content.body().to_owned()
+    };
+    let (mentioned_user_ids, mentions_room) = ev
+        .content
+        .mentions
+        .as_ref()
+        .map(|m| (m.user_ids.iter().map(|u| u.to_string()).collect(), m.room))
+        .unwrap_or_default();
+    let kind = msgtype_kind(&ev.content.msgtype);
+
+    let links = detect_links(&body);
+
+    Some(MessageEvent {
+        item_id: ev.event_id.to_string(),
+        event_id: ev.event_id.to_string(),
+        room_id: ev.room_id.to_string(),
+        sender: ev.sender.to_string(),
+        body,
+        timestamp_ms: ev.origin_server_ts.0.into(),
+        send_state: None,
+        txn_id: None,
+        reply_to_event_id,
+        reply_to_sender: None,
+        reply_to_body: None,
+        attachment: None,
+        thread_root_event_id,
+        formatted_body: formatted_html(&ev.content.msgtype),
+        mentioned_user_ids,
+        mentions_room,
+        kind,
+        reactions: vec![],
+        edited: false,
+        edit_history: vec![],
+        read_by: vec![],
+        shield: None,
+        send_error: None,
+        profile_change: None,
+        links,
+        location: map_location(&ev.content.msgtype),
+    })
+}
+
+/// A store can't switch between encrypted and unencrypted in place; refuse to
+/// open it with the wrong setting rather than losing its data. `reset_store`
+/// starts over explicitly.
+fn prepare_store_dir(dir: &Path, encrypted: bool) -> Result<(), FfiError> {
+    let marker_exists = store_encrypted_marker(dir).exists();
+    let has_store = std::fs::read_dir(dir)
+        .map(|it| {
+            it.flatten()
+                .any(|e| e.file_name().to_string_lossy().ends_with(".sqlite3"))
+        })
+        .unwrap_or(false);
+
+    if encrypted && has_store && !marker_exists {
+        return Err(FfiError::Store {
+            message: "the store is not encrypted; reset it to add a passphrase".into(),
+        });
+    }
+    if !encrypted && marker_exists {
+        return Err(FfiError::Store {
+            message: "the store is encrypted; a passphrase is required".into(),
+        });
     }
+    Ok(())
 }
 
-fn reset_store_dir(dir: &PathBuf) {
+fn reset_store_dir(dir: &Path) {
     let _ = std::fs::remove_dir_all(dir);
     let _ = std::fs::create_dir_all(dir);
 }
 
+/// Delete every store in `store_dir`, e.g. to switch it to (or from) a
+/// passphrase. The account on it has to log in again. Don't call it while a
+/// `Client` on this dir is open.
+#[export]
+pub fn reset_store(store_dir: String) -> Result<(), FfiError> {
+    let dir = PathBuf::from(store_dir);
+    if dir.exists() {
+        std::fs::remove_dir_all(&dir)?;
+    }
+    std::fs::create_dir_all(&dir)?;
+    Ok(())
+}
+
 async fn attach_sas_stream(
     verifs: VerifMap,
     flow_id: String,
@@ -5077,7 +10465,7 @@ async fn attach_sas_stream(
     verifs.lock().unwrap().insert(
         flow_id.clone(),
         VerifFlow {
-            sas: sas.clone(),
+            kind: VerifKind::Sas(sas.clone()),
             _other_user: other_user.clone(),
             _other_device: other_device.clone(),
         },
@@ -5134,23 +10522,290 @@ async fn attach_sas_stream(
     }
 }
 
+async fn attach_qr_stream(
+    verifs: VerifMap,
+    flow_id: String,
+    qr: QrVerification,
+    obs: Arc<dyn VerificationObserver>,
+) {
+    info!("attach_qr_stream: flow_id={}", flow_id);
+
+    verifs.lock().unwrap().insert(
+        flow_id.clone(),
+        VerifFlow {
+            kind: VerifKind::Qr(qr.clone()),
+            _other_user: qr.other_user_id().to_owned(),
+            _other_device: qr.other_device().device_id().to_owned(),
+        },
+    );
+
+    let mut stream = qr.changes();
+    while let Some(state) = stream.next().await {
+        info!("attach_qr_stream: flow_id={} state={:?}", flow_id, state);
+
+        match state {
+            QrVerificationState::Scanned => obs.on_phase(flow_id.clone(), SasPhase::QrScanned),
+            QrVerificationState::Reciprocated => {
+                obs.on_phase(flow_id.clone(), SasPhase::QrReciprocated)
+            }
+            QrVerificationState::Confirmed => obs.on_phase(flow_id.clone(), SasPhase::Confirmed),
+            QrVerificationState::Done { .. } => {
+                obs.on_phase(flow_id.clone(), SasPhase::Done);
+                verifs.lock().unwrap().remove(&flow_id);
+                break;
+            }
+            QrVerificationState::Cancelled(info_c) => {
+                obs.on_phase(flow_id.clone(), SasPhase::Cancelled);
+                obs.on_error(flow_id.clone(), info_c.reason().to_owned());
+                verifs.lock().unwrap().remove(&flow_id);
+                break;
+            }
+            QrVerificationState::Started => {}
+        }
+    }
+}
+
 fn render_message_text(msg: &matrix_sdk_ui::timeline::Message) -> String {
     let mut s = msg.body().to_owned();
     if s.trim().is_empty() {
         s = "Encrypted or unsupported message. Verify this session or restore keys to view."
             .to_owned();
     }
-    if msg.is_edited() {
-        s.push_str(" \n(edited)");
-    }
     s
 }
 
-async fn latest_room_event_for(client: &SdkClient, room: &Room) -> Option<LatestRoomEvent> {
+/// Build outgoing content for a composer body. `/me ` turns a text message into
+/// an emote; Markdown only produces a `formatted_body` when it actually has markup.
+/// Mentioned MXIDs found in the body become matrix.to pills, and are listed in
+/// `m.mentions` together with `@room`.
+fn build_text_content(
+    body: String,
+    options: Option<TextOptions>,
+) -> ruma::events::room::message::RoomMessageEventContentWithoutRelation {
+    use matrix_sdk::ruma::events::{
+        Mentions,
+        room::message::{FormattedBody, RoomMessageEventContentWithoutRelation as MsgNoRel},
+    };
+
+    let TextOptions {
+        format,
+        mut kind,
+        mentioned_user_ids,
+        mention_room,
+        custom_emojis,
+    } = options.unwrap_or(TextOptions {
+        format: TextFormat::Plain,
+        kind: TextMsgKind::Text,
+        mentioned_user_ids: Vec::new(),
+        mention_room: false,
+        custom_emojis: Vec::new(),
+    });
+
+    let mut body = body;
+    if matches!(kind, TextMsgKind::Text)
+        && let Some(rest) = body.strip_prefix("/me ")
+    {
+        body = rest.to_owned();
+        kind = TextMsgKind::Emote;
+    }
+
+    let mentioned: Vec<OwnedUserId> = mentioned_user_ids
+        .iter()
+        .filter_map(|u| u.parse::<OwnedUserId>().ok())
+        .collect();
+
+    let mut html = match format {
+        TextFormat::Plain => None,
+        TextFormat::Markdown => FormattedBody::markdown(&body).map(|f| f.body),
+        TextFormat::Html { html } => Some(html),
+    };
+    let mut pilled: Vec<&OwnedUserId> = mentioned
+        .iter()
+        .filter(|u| body.contains(u.as_str()))
+        .collect();
+    if !pilled.is_empty() {
+        // Longest first, so a pill is in place (and skipped as a link) before
+        // a shorter MXID that is its prefix is looked for.
+        pilled.sort_by_key(|u| std::cmp::Reverse(u.as_str().len()));
+        let mut h = html.unwrap_or_else(|| html_escape(&body).replace('\n', "<br>"));
+        for uid in pilled {
+            let pill = format!(
+                "<a href=\"https://matrix.to/#/{uid}\">{}</a>",
+                html_escape(uid.as_str())
+            );
+            h = map_html_text(&h, |text| replace_mxid(text, uid.as_str(), &pill));
+        }
+        html = Some(h);
+    }
+    let emojis: Vec<(String, &PackImage)> = custom_emojis
+        .iter()
+        .map(|e| (format!(":{}:", e.shortcode), e))
+        .filter(|(code, _)| body.contains(code.as_str()))
+        .collect();
+    if !emojis.is_empty() {
+        let mut h = html.unwrap_or_else(|| html_escape(&body).replace('\n', "<br>"));
+        for (code, emoji) in emojis {
+            // Text nodes hold the code escaped; tags (incl. earlier emoji) are skipped.
+            let code = html_escape(&code);
+            let img = format!(
+                "<img data-mx-emoticon src=\"{}\" alt=\"{code}\" title=\"{code}\" height=\"32\">",
+                html_escape(&emoji.mxc_uri)
+            );
+            h = map_html_text(&h, |text| text.replace(&code, &img));
+        }
+        html = Some(h);
+    }
+
+    let msgtype = match (kind, html) {
+        (TextMsgKind::Text, None) => MessageType::text_plain(body),
+        (TextMsgKind::Text, Some(h)) => MessageType::text_html(body, h),
+        (TextMsgKind::Emote, None) => MessageType::emote_plain(body),
+        (TextMsgKind::Emote, Some(h)) => MessageType::emote_html(body, h),
+        (TextMsgKind::Notice, None) => MessageType::notice_plain(body),
+        (TextMsgKind::Notice, Some(h)) => MessageType::notice_html(body, h),
+    };
+
+    let mut mentions = Mentions::with_user_ids(mentioned);
+    mentions.room = mention_room;
+
+    let mut content = MsgNoRel::new(msgtype);
+    content.mentions = Some(mentions);
+    content
+}
+
+/// Apply `f` to the text between the tags of `html`. Tags, attribute values
+/// and the contents of links and code are left alone.
+fn map_html_text(html: &str, mut f: impl FnMut(&str) -> String) -> String {
+    let mut out = String::with_capacity(html.len());
+    let mut opaque = 0usize;
+    let mut rest = html;
+    loop {
+        let (text, tail) = rest.split_at(rest.find('<').unwrap_or(rest.len()));
+        if opaque == 0 {
+            out.push_str(&f(text));
+        } else {
+            out.push_str(text);
+        }
+        if tail.is_empty() {
+            return out;
+        }
+        let (tag, tail) = tail.split_at(html_tag_len(tail));
+        let name = tag
+            .trim_start_matches(['<', '/'])
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        if matches!(name.as_str(), "a" | "code" | "pre") && !tag.ends_with("/>") {
+            if tag.starts_with("</") {
+                opaque = opaque.saturating_sub(1);
+            } else {
+                opaque += 1;
+            }
+        }
+        out.push_str(tag);
+        rest = tail;
+    }
+}
+
+/// Length of the tag `s` starts with, through the first `>` outside a quoted
+/// attribute value.
+fn html_tag_len(s: &str) -> usize {
+    let mut quote = None;
+    for (i, c) in s.char_indices().skip(1) {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), c) if c == q => quote = None,
+            (None, '>') => return i + 1,
+            _ => {}
+        }
+    }
+    s.len()
+}
+
+/// Replace whole occurrences of the MXID `uid` in `text`: `@bob:x` matches
+/// neither inside `@bob:x.org` nor `@bob:xy`, but does before a full stop.
+fn replace_mxid(text: &str, uid: &str, with: &str) -> String {
+    let id_char = |c: char| c.is_ascii_alphanumeric() || "._=-/+:@".contains(c);
+
+    let mut out = String::with_capacity(text.len());
+    let mut last = 0;
+    for (i, _) in text.match_indices(uid) {
+        let before = text[..i].chars().next_back();
+        let mut after = text[i + uid.len()..].chars();
+        let starts = before.is_none_or(|c| !id_char(c));
+        let ends = match after.next() {
+            None => true,
+            Some('.' | ':') => after.next().is_none_or(|c| !c.is_ascii_alphanumeric()),
+            Some(c) => !id_char(c),
+        };
+        if starts && ends {
+            out.push_str(&text[last..i]);
+            out.push_str(with);
+            last = i + uid.len();
+        }
+    }
+    out.push_str(&text[last..]);
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for ch in s.chars() {
+        match ch {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+/// Extract the HTML `formatted_body` of a text-like message, without the reply fallback.
+fn formatted_html(mt: &MessageType) -> Option<String> {
+    use matrix_sdk::ruma::events::room::message::MessageFormat;
+
+    let formatted = match mt {
+        MessageType::Text(c) => c.formatted.as_ref(),
+        MessageType::Emote(c) => c.formatted.as_ref(),
+        MessageType::Notice(c) => c.formatted.as_ref(),
+        // Formatted media captions (MSC2530)
+        MessageType::Image(c) => c.formatted.as_ref(),
+        MessageType::Video(c) => c.formatted.as_ref(),
+        MessageType::Audio(c) => c.formatted.as_ref(),
+        MessageType::File(c) => c.formatted.as_ref(),
+        _ => None,
+    }?;
+    if formatted.format != MessageFormat::Html {
+        return None;
+    }
+    Some(strip_html_reply_fallback(&formatted.body))
+}
+
+fn strip_html_reply_fallback(html: &str) -> String {
+    match (html.find("<mx-reply>"), html.find("</mx-reply>")) {
+        (Some(start), Some(end)) if end > start => {
+            let mut out = String::with_capacity(html.len());
+            out.push_str(&html[..start]);
+            out.push_str(&html[end + "</mx-reply>".len()..]);
+            out
+        }
+        _ => html.to_owned(),
+    }
+}
+
+async fn latest_room_event_for(
+    client: &SdkClient,
+    timelines: &TimelineCache,
+    room: &Room,
+) -> Option<LatestRoomEvent> {
     use matrix_sdk::ruma::events::room::message::MessageType;
 
     let rid = room.room_id().to_owned();
-    let tl = get_timeline_for(client, &rid).await?;
+    let tl = get_timeline_for(client, timelines, &rid).await?;
     let ev = tl.latest_event().await?;
 
     let ts: u64 = ev.timestamp().0.into();
@@ -5283,6 +10938,20 @@ fn render_membership_change(
     }
 }
 
+fn map_profile_change(pc: &matrix_sdk_ui::timeline::MemberProfileChange) -> ProfileChange {
+    let name = pc.displayname_change();
+    let avatar = pc.avatar_url_change();
+    ProfileChange {
+        user_id: pc.user_id().to_string(),
+        display_name_changed: name.is_some_and(|c| c.old != c.new),
+        old_display_name: name.and_then(|c| c.old.clone()),
+        new_display_name: name.and_then(|c| c.new.clone()),
+        avatar_changed: avatar.is_some_and(|c| c.old != c.new),
+        old_avatar_url: avatar.and_then(|c| c.old.as_ref()).map(|u| u.to_string()),
+        new_avatar_url: avatar.and_then(|c| c.new.as_ref()).map(|u| u.to_string()),
+    }
+}
+
 fn render_profile_change(
     _ev: &EventTimelineItem,
     pc: &matrix_sdk_ui::timeline::MemberProfileChange,
@@ -5348,90 +11017,79 @@ fn _mxc_from_media_source(src: &matrix_sdk::ruma::events::room::MediaSource) ->
 }
 
 fn missing_reply_event_id(ev: &EventTimelineItem) -> Option<matrix_sdk::ruma::OwnedEventId> {
-    if let TimelineItemContent::MsgLike(ml) = ev.content() {
-        if let Some(details) = &ml.in_reply_to {
-            use matrix_sdk_ui::timeline::TimelineDetails::*;
-            if !matches!(details.event, Ready(_)) {
-                return Some(details.event_id.clone());
-            }
+    if let TimelineItemContent::MsgLike(ml) = ev.content()
+        && let Some(details) = &ml.in_reply_to
+    {
+        use matrix_sdk_ui::timeline::TimelineDetails::*;
+        if !matches!(details.event, Ready(_)) {
+            return Some(details.event_id.clone());
         }
     }
     None
 }
 
+fn map_timeline_item(
+    it: &TimelineItem,
+    room_id: &str,
+    me: Option<&ruma::UserId>,
+) -> TimelineItemKind {
+    let item_id = it.unique_id().0.to_string();
+    if let Some(ei) = it.as_event() {
+        return match map_timeline_event(ei, room_id, Some(&item_id), me) {
+            Some(event) => TimelineItemKind::Event { event },
+            None => TimelineItemKind::Other { item_id },
+        };
+    }
+    match it.as_virtual() {
+        Some(VirtualTimelineItem::DateDivider(ts)) => TimelineItemKind::DateDivider {
+            item_id,
+            timestamp_ms: ts.0.into(),
+        },
+        Some(VirtualTimelineItem::ReadMarker) => TimelineItemKind::ReadMarker { item_id },
+        Some(VirtualTimelineItem::TimelineStart) => TimelineItemKind::TimelineStart { item_id },
+        None => TimelineItemKind::Other { item_id },
+    }
+}
+
 fn map_vec_diff(
     diff: VectorDiff<Arc<TimelineItem>>,
     room_id: &OwnedRoomId,
     tl: &Arc<Timeline>,
-) -> Option<TimelineDiffKind> {
-    match diff {
-        VectorDiff::Append { values } => {
-            let vals: Vec<_> = values
-                .iter()
-                .filter_map(|v| {
-                    v.as_event().and_then(|ei| {
-                        fetch_reply_if_needed(ei, tl);
-                        map_timeline_event(ei, room_id.as_str(), Some(&v.unique_id().0.to_string()))
-                    })
-                })
-                .collect();
-            Some(TimelineDiffKind::Append { values: vals })
+    me: Option<&ruma::UserId>,
+) -> TimelineDiffKind {
+    let map = |v: &Arc<TimelineItem>| {
+        if let Some(ei) = v.as_event() {
+            fetch_reply_if_needed(ei, tl);
         }
-        VectorDiff::PushBack { value } => value
-            .as_event()
-            .and_then(|ei| {
-                fetch_reply_if_needed(ei, tl);
-                map_timeline_event(ei, room_id.as_str(), Some(&value.unique_id().0.to_string()))
-            })
-            .map(|v| TimelineDiffKind::PushBack { value: v }),
-        VectorDiff::PushFront { value } => value
-            .as_event()
-            .and_then(|ei| {
-                fetch_reply_if_needed(ei, tl);
-                map_timeline_event(ei, room_id.as_str(), Some(&value.unique_id().0.to_string()))
-            })
-            .map(|v| TimelineDiffKind::PushFront { value: v }),
-        VectorDiff::Insert { index, value } => value
-            .as_event()
-            .and_then(|ei| {
-                fetch_reply_if_needed(ei, tl);
-                map_timeline_event(ei, room_id.as_str(), Some(&value.unique_id().0.to_string()))
-            })
-            .map(|v| TimelineDiffKind::Insert {
-                index: index as u32,
-                value: v,
-            }),
-        VectorDiff::Set { index, value } => value
-            .as_event()
-            .and_then(|ei| {
-                fetch_reply_if_needed(ei, tl);
-                map_timeline_event(ei, room_id.as_str(), Some(&value.unique_id().0.to_string()))
-            })
-            .map(|v| TimelineDiffKind::Set {
-                index: index as u32,
-                value: v,
-            }),
-        VectorDiff::Remove { index } => Some(TimelineDiffKind::Remove {
+        map_timeline_item(v, room_id.as_str(), me)
+    };
+
+    match diff {
+        VectorDiff::Append { values } => TimelineDiffKind::Append {
+            values: values.iter().map(map).collect(),
+        },
+        VectorDiff::PushBack { value } => TimelineDiffKind::PushBack { value: map(&value) },
+        VectorDiff::PushFront { value } => TimelineDiffKind::PushFront { value: map(&value) },
+        VectorDiff::Insert { index, value } => TimelineDiffKind::Insert {
             index: index as u32,
-        }),
-        VectorDiff::PopBack => Some(TimelineDiffKind::PopBack),
-        VectorDiff::PopFront => Some(TimelineDiffKind::PopFront),
-        VectorDiff::Truncate { length } => Some(TimelineDiffKind::Truncate {
+            value: map(&value),
+        },
+        VectorDiff::Set { index, value } => TimelineDiffKind::Set {
+            index: index as u32,
+            value: map(&value),
+        },
+        VectorDiff::Remove { index } => TimelineDiffKind::Remove {
+            index: index as u32,
+        },
+        VectorDiff::PopBack => TimelineDiffKind::PopBack,
+        VectorDiff::PopFront => TimelineDiffKind::PopFront,
+        VectorDiff::Truncate { length } => TimelineDiffKind::Truncate {
             length: length as u32,
-        }),
-        VectorDiff::Clear => Some(TimelineDiffKind::Clear),
-        VectorDiff::Reset { values } => {
-            let vals: Vec<_> = values
-                .iter()
-                .filter_map(|v| {
-                    v.as_event().and_then(|ei| {
-                        fetch_reply_if_needed(ei, tl);
-                        map_timeline_event(ei, room_id.as_str(), Some(&v.unique_id().0.to_string()))
-                    })
-                })
-                .collect();
-            Some(TimelineDiffKind::Reset { values: vals })
-        }
+        },
+        VectorDiff::Clear => TimelineDiffKind::Clear,
+        VectorDiff::Reset { values } => TimelineDiffKind::Reset {
+            values: values.iter().map(map).collect(),
+        },
     }
 }
 
@@ -5448,3 +11106,758 @@ fn fetch_reply_if_needed(ei: &EventTimelineItem, tl: &Arc<Timeline>) {
 pub trait UrlOpener: Send + Sync {
     fn open(&self, url: String) -> bool;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text_options(mentioned: &[&str]) -> TextOptions {
+        TextOptions {
+            format: TextFormat::Plain,
+            kind: TextMsgKind::Text,
+            mentioned_user_ids: mentioned.iter().map(|u| u.to_string()).collect(),
+            mention_room: false,
+            custom_emojis: Vec::new(),
+        }
+    }
+
+    fn formatted(
+        content: &ruma::events::room::message::RoomMessageEventContentWithoutRelation,
+    ) -> String {
+        match &content.msgtype {
+            MessageType::Text(c) => c.formatted.as_ref().map(|f| f.body.clone()),
+            MessageType::Emote(c) => c.formatted.as_ref().map(|f| f.body.clone()),
+            _ => None,
+        }
+        .unwrap_or_default()
+    }
+
+    #[test]
+    fn pills_do_not_match_mxid_prefixes() {
+        let content = build_text_content(
+            "hi @bob:x and @bob:x.org.".to_owned(),
+            Some(text_options(&["@bob:x", "@bob:x.org"])),
+        );
+        assert_eq!(
+            formatted(&content),
+            "hi <a href=\"https://matrix.to/#/@bob:x\">@bob:x</a> and \
+             <a href=\"https://matrix.to/#/@bob:x.org\">@bob:x.org</a>."
+        );
+        let mentions = content.mentions.unwrap();
+        assert_eq!(mentions.user_ids.len(), 2);
+        assert!(!mentions.room);
+    }
+
+    #[test]
+    fn pills_skip_existing_links_and_attributes() {
+        let mut options = text_options(&["@bob:x"]);
+        options.format = TextFormat::Html {
+            html: "<a href=\"https://matrix.to/#/@bob:x\">Bob</a> <span title=\"@bob:x\">@bob:x</span>"
+                .to_owned(),
+        };
+        let content = build_text_content("Bob @bob:x".to_owned(), Some(options));
+        assert_eq!(
+            formatted(&content),
+            "<a href=\"https://matrix.to/#/@bob:x\">Bob</a> <span title=\"@bob:x\">\
+             <a href=\"https://matrix.to/#/@bob:x\">@bob:x</a></span>"
+        );
+    }
+
+    #[test]
+    fn pill_not_applied_mid_word() {
+        assert_eq!(
+            replace_mxid("a@bob:x @bob:xy", "@bob:x", "P"),
+            "a@bob:x @bob:xy"
+        );
+        assert_eq!(replace_mxid("(@bob:x)", "@bob:x", "P"), "(P)");
+        assert_eq!(replace_mxid("@bob:x: hi", "@bob:x", "P"), "P: hi");
+    }
+
+    #[test]
+    fn html_text_mapping_leaves_tags_and_code_alone() {
+        let out = map_html_text("a<b title='x>y'>a</b><code>a</code>a", |t| {
+            t.replace('a', "A")
+        });
+        assert_eq!(out, "A<b title='x>y'>A</b><code>a</code>A");
+    }
+
+    /// Fresh store directory under the system temp dir.
+    fn temp_store_dir(name: &str) -> String {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        std::env::temp_dir()
+            .join(format!("mages-{name}-{}-{nanos}", std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Mock homeserver that delegates auth to an OAuth 2.0 server.
+    fn oauth_mock_server() -> matrix_sdk::test_utils::mocks::MatrixMockServer {
+        RT.block_on(async {
+            let server = matrix_sdk::test_utils::mocks::MatrixMockServer::new().await;
+            let oauth = server.oauth();
+            server.mock_versions().ok().mount().await;
+            oauth.mock_server_metadata().ok().mount().await;
+            oauth.mock_registration().ok().mount().await;
+            oauth.mock_token().ok().mount().await;
+            server.mock_who_am_i().ok().mount().await;
+            server
+        })
+    }
+
+    /// Plays the user's browser: approves at once by sending the redirect to
+    /// the loopback server.
+    struct ApprovingBrowser;
+
+    impl UrlOpener for ApprovingBrowser {
+        fn open(&self, url: String) -> bool {
+            use std::io::{Read, Write};
+
+            let url = matrix_sdk::reqwest::Url::parse(&url).unwrap();
+            let param = |name: &str| {
+                url.query_pairs()
+                    .find(|(k, _)| k == name)
+                    .map(|(_, v)| v.into_owned())
+                    .unwrap()
+            };
+            let mut redirect = matrix_sdk::reqwest::Url::parse(&param("redirect_uri")).unwrap();
+            redirect
+                .query_pairs_mut()
+                .append_pair("code", "42")
+                .append_pair("state", &param("state"));
+
+            std::thread::spawn(move || {
+                let host = redirect.host_str().unwrap().to_owned();
+                let mut stream =
+                    std::net::TcpStream::connect((host, redirect.port().unwrap())).unwrap();
+                write!(
+                    stream,
+                    "GET {}?{} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    redirect.path(),
+                    redirect.query().unwrap()
+                )
+                .unwrap();
+                let _ = stream.read_to_end(&mut Vec::new());
+            });
+            true
+        }
+    }
+
+    /// A browser that opens but the user never finishes.
+    struct IdleBrowser;
+
+    impl UrlOpener for IdleBrowser {
+        fn open(&self, _url: String) -> bool {
+            true
+        }
+    }
+
+    /// No browser available.
+    struct NoBrowser;
+
+    impl UrlOpener for NoBrowser {
+        fn open(&self, _url: String) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn oauth_loopback_login_completes() {
+        let server = oauth_mock_server();
+        let client = Client::new(server.uri(), temp_store_dir("oauth-ok")).unwrap();
+
+        client
+            .login_oauth_loopback(Box::new(ApprovingBrowser), None)
+            .unwrap();
+        assert!(client.inner.session_meta().is_some());
+    }
+
+    #[test]
+    fn oauth_loopback_login_fails_when_url_cannot_open() {
+        let server = oauth_mock_server();
+        let client = Client::new(server.uri(), temp_store_dir("oauth-no-browser")).unwrap();
+
+        let err = client
+            .login_oauth_loopback(Box::new(NoBrowser), None)
+            .unwrap_err();
+        assert!(err.to_string().contains("could not open"), "{err}");
+        assert!(client.inner.session_meta().is_none());
+    }
+
+    #[test]
+    fn oauth_loopback_login_can_be_cancelled() {
+        let server = oauth_mock_server();
+        let client = Client::new(server.uri(), temp_store_dir("oauth-cancel")).unwrap();
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        let res = std::thread::scope(|s| {
+            s.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    client.cancel_browser_auth();
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+            });
+            let res = client.login_oauth_loopback(Box::new(IdleBrowser), None);
+            done.store(true, Ordering::SeqCst);
+            res
+        });
+        let err = res.unwrap_err();
+        assert!(err.to_string().contains("cancelled"), "{err}");
+    }
+
+    #[test]
+    fn store_dir_is_not_wiped_when_adding_a_passphrase() {
+        let dir = temp_store_dir("store-upgrade");
+        drop(Client::new("http://localhost:1".into(), dir.clone()).unwrap());
+
+        let err =
+            Client::new_with_passphrase("http://localhost:1".into(), dir.clone(), "pw".into())
+                .err()
+                .unwrap();
+        assert!(matches!(err, FfiError::Store { .. }), "{err}");
+        assert!(std::fs::read_dir(&dir).unwrap().count() > 0);
+        assert!(!store_encrypted_marker(&PathBuf::from(&dir)).exists());
+
+        reset_store(dir.clone()).unwrap();
+        let client =
+            Client::new_with_passphrase("http://localhost:1".into(), dir.clone(), "pw".into())
+                .unwrap();
+        assert!(client.store_encrypted());
+        drop(client);
+
+        let err = Client::new("http://localhost:1".into(), dir).err().unwrap();
+        assert!(matches!(err, FfiError::Store { .. }), "{err}");
+    }
+
+    #[test]
+    fn client_build_errors_are_classified() {
+        let dir = temp_store_dir("bad-homeserver");
+        let err = Client::new("not a url".into(), dir).err().unwrap();
+        assert!(matches!(err, FfiError::InvalidInput { .. }), "{err}");
+
+        let err = FfiError::from(matrix_sdk::ClientBuildError::InvalidServerName);
+        assert!(matches!(err, FfiError::InvalidInput { .. }), "{err}");
+    }
+
+    fn indexed(room: &str, id: &str, ts: u64, body: &str) -> IndexedMessage {
+        IndexedMessage {
+            event_id: id.to_owned(),
+            room_id: room.to_owned(),
+            sender: "@a:x".to_owned(),
+            body: body.to_owned(),
+            timestamp_ms: ts,
+        }
+    }
+
+    #[test]
+    fn search_index_matches_all_terms_and_last_as_prefix() {
+        let mut idx = SearchIndex::default();
+        idx.insert(indexed("!r:x", "$1", 1, "Lunch at noon?"));
+        idx.insert(indexed("!r:x", "$2", 2, "lunchtime is over"));
+        idx.insert(indexed("!s:x", "$3", 3, "noon lunch"));
+
+        let ids = |hits: Vec<IndexedMessage>| -> Vec<String> {
+            hits.into_iter().map(|d| d.event_id).collect()
+        };
+        assert_eq!(ids(idx.query("lunch", None)), ["$3", "$2", "$1"]);
+        assert_eq!(ids(idx.query("noon lun", None)), ["$3", "$1"]);
+        assert_eq!(ids(idx.query("lunch no", None)), ["$3", "$1"]);
+        let only_r: HashSet<String> = ["!r:x".to_owned()].into();
+        assert_eq!(ids(idx.query("noon", Some(&only_r))), ["$1"]);
+        assert!(idx.query("dinner", None).is_empty());
+        assert!(idx.query("  ", None).is_empty());
+    }
+
+    #[test]
+    fn search_index_updates_and_removes_docs() {
+        let mut idx = SearchIndex::default();
+        idx.insert(indexed("!r:x", "$1", 1, "old words"));
+        idx.insert(indexed("!r:x", "$1", 1, "new words"));
+        assert!(idx.query("old", None).is_empty());
+        assert_eq!(idx.query("new", None).len(), 1);
+
+        idx.remove("$1");
+        assert!(idx.query("words", None).is_empty());
+        assert!(idx.postings.is_empty());
+        assert!(idx.by_room.is_empty());
+    }
+
+    #[test]
+    fn search_index_context_is_ordered_within_the_room() {
+        let mut idx = SearchIndex::default();
+        for (id, ts) in [("$3", 30), ("$1", 10), ("$5", 50), ("$2", 20), ("$4", 40)] {
+            idx.insert(indexed("!r:x", id, ts, "hello"));
+        }
+        idx.insert(indexed("!other:x", "$9", 35, "hello"));
+
+        let doc = idx.docs["$3"].clone();
+        let (before, after) = idx.context(&doc, 2);
+        let ids =
+            |v: Vec<IndexedMessage>| -> Vec<String> { v.into_iter().map(|d| d.event_id).collect() };
+        assert_eq!(ids(before), ["$1", "$2"]);
+        assert_eq!(ids(after), ["$4", "$5"]);
+
+        let (before, after) = idx.context(&idx.docs["$1"].clone(), 1);
+        assert!(before.is_empty());
+        assert_eq!(ids(after), ["$2"]);
+        assert_eq!(idx.context(&doc, 0), (vec![], vec![]));
+    }
+
+    #[test]
+    fn search_index_tracks_changed_rooms() {
+        let mut idx = SearchIndex::default();
+        idx.insert(indexed("!r:x", "$1", 1, "a"));
+        idx.insert(indexed("!s:x", "$2", 2, "b"));
+        let (changed, mut all) = idx.take_dirty();
+        assert_eq!(changed.len(), 2);
+        all.sort();
+        assert_eq!(all, ["!r:x", "!s:x"]);
+
+        idx.remove("$2");
+        let (changed, all) = idx.take_dirty();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].0, "!s:x");
+        assert!(changed[0].1.is_empty());
+        assert_eq!(all, ["!r:x"]);
+
+        idx.clear(None);
+        let (changed, all) = idx.take_dirty();
+        assert_eq!(changed, [("!r:x".to_owned(), vec![])]);
+        assert!(all.is_empty());
+    }
+
+    fn jpeg_segment(marker: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = vec![0xFF, marker];
+        out.extend_from_slice(&((body.len() + 2) as u16).to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn jpeg_exif_is_stripped() {
+        let app0 = jpeg_segment(0xE0, b"JFIF\0");
+        let mut data = vec![0xFF, 0xD8];
+        data.extend(&app0);
+        data.extend(jpeg_segment(0xE1, b"Exif\0\0GPS"));
+        data.extend([0xFF, 0xDA, 0x00, 0x02, 1, 2, 3]);
+
+        let mut expected = vec![0xFF, 0xD8];
+        expected.extend(&app0);
+        expected.extend([0xFF, 0xDA, 0x00, 0x02, 1, 2, 3]);
+        assert_eq!(strip_jpeg_exif(&data), expected);
+    }
+
+    #[test]
+    fn malformed_jpeg_is_passed_through() {
+        assert_eq!(strip_jpeg_exif(b""), b"");
+        assert_eq!(strip_jpeg_exif(&[0xFF]), [0xFF]);
+        assert_eq!(strip_jpeg_exif(&[0xFF, 0xD8, 0xFF]), [0xFF, 0xD8, 0xFF]);
+        // Segment lengths that are too small or run past the end.
+        let short = [0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x01, b'E'];
+        assert_eq!(strip_jpeg_exif(&short), short);
+        let truncated = [0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x40, b'E', b'x'];
+        assert_eq!(strip_jpeg_exif(&truncated), truncated);
+    }
+
+    fn png_chunk(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = (body.len() as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out.extend_from_slice(&[0; 4]);
+        out
+    }
+
+    #[test]
+    fn png_exif_is_stripped() {
+        let ihdr = png_chunk(b"IHDR", &[0; 13]);
+        let iend = png_chunk(b"IEND", b"");
+        let mut data = PNG_SIGNATURE.to_vec();
+        data.extend(&ihdr);
+        data.extend(png_chunk(b"eXIf", b"MM\0*GPS"));
+        data.extend(&iend);
+
+        let mut expected = PNG_SIGNATURE.to_vec();
+        expected.extend(&ihdr);
+        expected.extend(&iend);
+        assert_eq!(strip_png_exif(&data), expected);
+
+        let mut truncated = PNG_SIGNATURE.to_vec();
+        truncated.extend(&png_chunk(b"IHDR", &[0; 13])[..10]);
+        assert_eq!(strip_png_exif(&truncated), truncated);
+        assert_eq!(strip_png_exif(b"\x89PN"), b"\x89PN");
+    }
+
+    fn webp_chunk(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn webp(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&((body.len() + 4) as u32).to_le_bytes());
+        out.extend_from_slice(b"WEBP");
+        out.extend(body);
+        out
+    }
+
+    #[test]
+    fn webp_exif_is_stripped() {
+        let mut flags = [0u8; 10];
+        flags[0] = 0x08 | 0x10;
+        let data = webp(&[
+            webp_chunk(b"VP8X", &flags),
+            webp_chunk(b"VP8L", &[1, 2, 3]),
+            webp_chunk(b"EXIF", b"MM\0*GPS"),
+        ]);
+
+        flags[0] = 0x10;
+        let expected = webp(&[webp_chunk(b"VP8X", &flags), webp_chunk(b"VP8L", &[1, 2, 3])]);
+        assert_eq!(strip_webp_exif(&data), expected);
+
+        let truncated = &data[..data.len() - 3];
+        assert!(strip_webp_exif(truncated).windows(4).any(|w| w == b"VP8L"));
+        assert_eq!(strip_webp_exif(b"RIFF\0\0"), b"RIFF\0\0");
+    }
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn mp4_boxes_stop_at_malformed_sizes() {
+        let mut data = mp4_box(b"ftyp", b"isom");
+        data.extend(mp4_box(b"free", b""));
+        let boxes = mp4_boxes(&data);
+        assert_eq!(boxes.len(), 2);
+        assert_eq!(boxes[0], (*b"ftyp", &b"isom"[..]));
+
+        // Larger than the data, smaller than its header, truncated 64-bit size.
+        let mut data = mp4_box(b"ftyp", b"isom");
+        data.extend([0, 0, 0, 0x40]);
+        data.extend(b"moov");
+        assert_eq!(mp4_boxes(&data).len(), 1);
+        assert!(mp4_boxes(&[0, 0, 0, 4, b'f', b'r', b'e', b'e']).is_empty());
+        assert!(mp4_boxes(&[0, 0, 0, 1, b'm', b'd', b'a', b't', 0, 0]).is_empty());
+        assert!(mp4_boxes(&[0, 0, 0]).is_empty());
+
+        // Size 0 runs to the end of the data.
+        let to_end = [0, 0, 0, 0, b'm', b'd', b'a', b't', 9];
+        assert_eq!(mp4_boxes(&to_end), [(*b"mdat", &[9u8][..])]);
+    }
+
+    #[test]
+    fn mvhd_duration_handles_both_versions_and_garbage() {
+        let mut v0 = vec![0u8; 20];
+        v0[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        v0[16..20].copy_from_slice(&2500u32.to_be_bytes());
+        assert_eq!(mvhd_duration(&v0), Some(Duration::from_millis(2500)));
+
+        let mut v1 = vec![0u8; 32];
+        v1[0] = 1;
+        v1[20..24].copy_from_slice(&10u32.to_be_bytes());
+        v1[24..32].copy_from_slice(&25u64.to_be_bytes());
+        assert_eq!(mvhd_duration(&v1), Some(Duration::from_millis(2500)));
+
+        assert_eq!(mvhd_duration(&[]), None);
+        assert_eq!(mvhd_duration(&v0[..18]), None);
+        assert_eq!(mvhd_duration(&v1[..30]), None);
+        v0[12..16].copy_from_slice(&0u32.to_be_bytes());
+        assert_eq!(mvhd_duration(&v0), None);
+    }
+
+    fn tkhd(matrix_ab: (u32, u32), width: u32, height: u32) -> Vec<u8> {
+        let mut b = vec![0u8; 84];
+        b[40..44].copy_from_slice(&matrix_ab.0.to_be_bytes());
+        b[44..48].copy_from_slice(&matrix_ab.1.to_be_bytes());
+        b[76..80].copy_from_slice(&(width << 16).to_be_bytes());
+        b[80..84].copy_from_slice(&(height << 16).to_be_bytes());
+        b
+    }
+
+    #[test]
+    fn tkhd_dimensions_apply_rotation() {
+        assert_eq!(
+            tkhd_dimensions(&tkhd((0x1_0000, 0), 1920, 1080)),
+            Some((1920, 1080))
+        );
+        // 90 degrees: a = 0, b = 1.0
+        assert_eq!(
+            tkhd_dimensions(&tkhd((0, 0x1_0000), 1920, 1080)),
+            Some((1080, 1920))
+        );
+        // 270 degrees: a = 0, b = -1.0
+        assert_eq!(
+            tkhd_dimensions(&tkhd((0, 0xFFFF_0000), 1920, 1080)),
+            Some((1080, 1920))
+        );
+    }
+
+    #[test]
+    fn tkhd_dimensions_reject_garbage() {
+        assert_eq!(tkhd_dimensions(&[]), None);
+        assert_eq!(
+            tkhd_dimensions(&tkhd((0x1_0000, 0), 1920, 1080)[..80]),
+            None
+        );
+        assert_eq!(tkhd_dimensions(&tkhd((0x1_0000, 0), 0, 0)), None);
+        let mut v1 = tkhd((0x1_0000, 0), 1920, 1080);
+        v1[0] = 1;
+        assert_eq!(tkhd_dimensions(&v1), None);
+    }
+
+    #[test]
+    fn emote_captions_are_refused() {
+        let options = |caption: &str| AttachmentOptions {
+            caption: Some(caption.to_owned()),
+            caption_options: None,
+            reply_to_event_id: None,
+            thread_root_event_id: None,
+        };
+        assert!(matches!(
+            caption_parts(Some(&options("/me waves"))),
+            Err(FfiError::InvalidInput { .. })
+        ));
+        let (body, _, _) = caption_parts(Some(&options("a /me b"))).unwrap();
+        assert_eq!(body.as_deref(), Some("a /me b"));
+    }
+
+    fn encrypted_file(url: &str) -> EncryptedFile {
+        serde_json::from_value(serde_json::json!({
+            "url": url,
+            "key": {
+                "kty": "oct",
+                "key_ops": ["encrypt", "decrypt"],
+                "alg": "A256CTR",
+                "k": "qcHVMSgYg-71CauWBezXI5qkaRb0LuIy-Wx5kIaHMIA",
+                "ext": true
+            },
+            "iv": "X85+XgHN+HEAAAAAAAAAAA",
+            "hashes": { "sha256": "5qG4fFnbbVdlAB1Q72JDKwCagV6Dbkx9uds4rSak37c" },
+            "v": "v2"
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn media_keys_evict_least_recently_used() {
+        let mut keys = MediaKeys::default();
+        for i in 0..MEDIA_PROXY_KEYS_MAX {
+            let url = format!("mxc://x/{i}");
+            keys.insert(url.clone(), encrypted_file(&url));
+        }
+        // Touch the oldest so it survives the eviction.
+        assert!(keys.get("mxc://x/0").is_some());
+        keys.insert("mxc://x/new".into(), encrypted_file("mxc://x/new"));
+
+        assert!(keys.entries.len() <= MEDIA_PROXY_KEYS_MAX);
+        assert!(keys.get("mxc://x/0").is_some());
+        assert!(keys.get("mxc://x/new").is_some());
+        assert!(keys.get("mxc://x/1").is_none());
+    }
+
+    fn decrypt_chunks(
+        ciphertext: &[u8],
+        info: &serde_json::Value,
+        out: &Path,
+    ) -> Result<(), FfiError> {
+        let info = serde_json::from_value(info.clone()).unwrap();
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        for chunk in ciphertext.chunks(7) {
+            tx.try_send(chunk.to_vec()).unwrap();
+        }
+        drop(tx);
+        decrypt_to_file(rx, info, out)
+    }
+
+    #[test]
+    fn downloads_are_decrypted_in_chunks_and_hash_checked() {
+        use std::io::Read;
+
+        let plain = b"a few chunks worth of attachment plaintext".to_vec();
+        let mut cursor = std::io::Cursor::new(plain.clone());
+        let mut encryptor = matrix_sdk_crypto::AttachmentEncryptor::new(&mut cursor);
+        let mut ciphertext = Vec::new();
+        encryptor.read_to_end(&mut ciphertext).unwrap();
+        // `MediaEncryptionInfo` isn't `Clone`; keep it as JSON.
+        let info = serde_json::to_value(encryptor.finish()).unwrap();
+
+        let dir = PathBuf::from(temp_store_dir("decrypt"));
+        std::fs::create_dir_all(&dir).unwrap();
+        let out = dir.join("file");
+        decrypt_chunks(&ciphertext, &info, &out).unwrap();
+        assert_eq!(std::fs::read(&out).unwrap(), plain);
+
+        ciphertext[3] ^= 1;
+        let err = decrypt_chunks(&ciphertext, &info, &out).unwrap_err();
+        assert!(matches!(err, FfiError::Crypto { .. }), "{err}");
+
+        // A download that ends early fails the hash check as well.
+        let err = decrypt_chunks(&ciphertext[..10], &info, &out).unwrap_err();
+        assert!(matches!(err, FfiError::Crypto { .. }), "{err}");
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn custom_emoji_only_replaced_in_text() {
+        let emoji = |shortcode: &str, mxc: &str| PackImage {
+            shortcode: shortcode.to_owned(),
+            mxc_uri: mxc.to_owned(),
+            body: None,
+            mime: None,
+            size_bytes: None,
+            width: None,
+            height: None,
+            is_emoticon: true,
+            is_sticker: false,
+        };
+        let mut options = text_options(&[]);
+        options.format = TextFormat::Html {
+            html: "<a href=\"https://x/:cat:\">:cat:</a> :cat: &lt;:a&amp;b:".to_owned(),
+        };
+        options.custom_emojis = vec![emoji("cat", "mxc://x/cat"), emoji("a&b", "mxc://x/ab")];
+        let content = build_text_content(":cat: :cat: <:a&b:".to_owned(), Some(options));
+        assert_eq!(
+            formatted(&content),
+            "<a href=\"https://x/:cat:\">:cat:</a> \
+             <img data-mx-emoticon src=\"mxc://x/cat\" alt=\":cat:\" title=\":cat:\" height=\"32\"> \
+             &lt;<img data-mx-emoticon src=\"mxc://x/ab\" alt=\":a&amp;b:\" title=\":a&amp;b:\" height=\"32\">"
+        );
+    }
+
+    #[test]
+    fn text_content_turns_me_into_an_emote_and_lists_mentions() {
+        let mut options = text_options(&["@alice:example.org", "not an id"]);
+        options.mention_room = true;
+        let content = build_text_content("/me waves".to_owned(), Some(options));
+        let MessageType::Emote(emote) = &content.msgtype else {
+            panic!("expected an emote");
+        };
+        assert_eq!(emote.body, "waves");
+        let mentions = content.mentions.unwrap();
+        assert!(mentions.room);
+        assert_eq!(
+            mentions.user_ids.into_iter().collect::<Vec<_>>(),
+            ["@alice:example.org"]
+        );
+
+        let mut options = text_options(&[]);
+        options.kind = TextMsgKind::Notice;
+        let content = build_text_content("/me waves".to_owned(), Some(options));
+        assert!(matches!(&content.msgtype, MessageType::Notice(n) if n.body == "/me waves"));
+    }
+
+    #[test]
+    fn markdown_is_only_formatted_when_it_has_markup() {
+        let markdown = || TextOptions {
+            format: TextFormat::Markdown,
+            ..text_options(&[])
+        };
+        let content = build_text_content("just text".to_owned(), Some(markdown()));
+        assert_eq!(formatted(&content), "");
+        let content = build_text_content("**bold**".to_owned(), Some(markdown()));
+        assert_eq!(formatted(&content), "<strong>bold</strong>");
+    }
+
+    #[test]
+    fn links_are_detected_without_trailing_punctuation() {
+        assert_eq!(
+            detect_links(
+                "see (https://example.org/a), https://example.org/a. \
+                 and <http://x.y/b?c=d>! not ftp://z or https:// or example.com"
+            ),
+            ["https://example.org/a", "http://x.y/b?c=d"]
+        );
+        assert!(detect_links("").is_empty());
+    }
+
+    #[test]
+    fn url_preview_cache_expires_and_drops_oldest() {
+        let entry = |fetched_ms: u64, hit: bool| CachedUrlPreview {
+            fetched_ms,
+            preview: hit.then(|| UrlPreview {
+                url: String::new(),
+                title: Some("t".into()),
+                description: None,
+                site_name: None,
+                image_mxc: None,
+                image_width: None,
+                image_height: None,
+            }),
+        };
+        let mut cache = UrlPreviewCache::default();
+        cache.insert("hit".into(), entry(0, true));
+        cache.insert("miss".into(), entry(0, false));
+        let now = URL_PREVIEW_MISS_TTL_MS;
+        assert!(cache.get("hit", now).is_some());
+        assert!(cache.get("miss", now).is_none());
+        assert!(cache.get("hit", URL_PREVIEW_TTL_MS).is_none());
+
+        for i in 0..URL_PREVIEW_CACHE_MAX as u64 {
+            cache.insert(format!("u{i}"), entry(i + 1, true));
+        }
+        assert_eq!(cache.entries.len(), URL_PREVIEW_CACHE_MAX);
+        assert!(!cache.entries.contains_key("hit"));
+        assert!(!cache.entries.contains_key("miss"));
+        assert!(cache.entries.contains_key("u0"));
+    }
+
+    #[test]
+    fn image_pack_usage_falls_back_to_the_pack() {
+        let pack: RawImagePack = serde_json::from_value(serde_json::json!({
+            "pack": { "display_name": "Cats", "usage": ["sticker"] },
+            "images": {
+                "plain": { "url": "mxc://x/plain" },
+                "emote": {
+                    "url": "mxc://x/emote",
+                    "usage": ["emoticon"],
+                    "info": { "mimetype": "image/png", "size": 10, "w": 32, "h": 16 }
+                },
+                "remote": { "url": "https://x/remote.png" }
+            }
+        }))
+        .unwrap();
+        let pack = map_image_pack(pack, "!r:x/".into(), Some("!r:x".into()), Some("".into()));
+        assert_eq!(pack.display_name.as_deref(), Some("Cats"));
+        let [emote, plain] = &pack.images[..] else {
+            panic!("expected two images, got {}", pack.images.len());
+        };
+        assert_eq!(emote.shortcode, "emote");
+        assert!(emote.is_emoticon && !emote.is_sticker);
+        assert_eq!((emote.width, emote.height), (Some(32), Some(16)));
+        assert_eq!(emote.mime.as_deref(), Some("image/png"));
+        assert!(!plain.is_emoticon && plain.is_sticker);
+
+        let bare: RawImagePack = serde_json::from_value(
+            serde_json::json!({ "images": { "a": { "url": "mxc://x/a" } } }),
+        )
+        .unwrap();
+        let pack = map_image_pack(bare, "user".into(), None, None);
+        assert!(pack.images[0].is_emoticon && pack.images[0].is_sticker);
+    }
+
+    #[test]
+    fn geo_uris_are_parsed_and_range_checked() {
+        assert_eq!(parse_geo_uri("geo:51.5,-0.12"), Some((51.5, -0.12)));
+        assert_eq!(
+            parse_geo_uri("geo:-33.9,151.2,12;u=35"),
+            Some((-33.9, 151.2))
+        );
+        assert_eq!(parse_geo_uri("geo:1,2;crs=wgs84"), Some((1.0, 2.0)));
+        assert_eq!(parse_geo_uri("geo:91,0"), None);
+        assert_eq!(parse_geo_uri("geo:0,181"), None);
+        assert_eq!(parse_geo_uri("geo:1"), None);
+        assert_eq!(parse_geo_uri("geo:a,b"), None);
+        assert_eq!(parse_geo_uri("51.5,-0.12"), None);
+    }
+}